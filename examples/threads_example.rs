@@ -6,8 +6,9 @@ use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
 fn init_subscriber() {
     let trace_path = std::env::temp_dir().join("test.pftrace");
     let trace_file = std::fs::File::create(&trace_path).unwrap();
-    let perfetto_layer =
-        PerfettoLayer::new(std::sync::Mutex::new(trace_file)).with_debug_annotations(true);
+    let perfetto_layer = PerfettoLayer::new(std::sync::Mutex::new(trace_file))
+        .with_debug_annotations(true)
+        .with_span_ids(true);
 
     let fmt_layer = fmt::layer()
         .with_writer(std::io::stdout)
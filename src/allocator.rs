@@ -0,0 +1,135 @@
+//! Allocation counting, see [`PerfettoAllocator`].
+
+use crate::idl;
+use crate::idl_helpers::uint_annotation;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+thread_local! {
+    static THREAD_COUNTERS: Cell<AllocationCounters> = const {
+        Cell::new(AllocationCounters {
+            allocated_bytes: 0,
+            allocations: 0,
+            freed_bytes: 0,
+            frees: 0,
+        })
+    };
+}
+
+/// Bytes currently allocated by the process.
+static HEAP_SIZE: AtomicI64 = AtomicI64::new(0);
+
+/// A global allocator wrapper counting the allocations of each thread, so that
+/// [`PerfettoLayer::with_allocations`](crate::PerfettoLayer::with_allocations) can attach them
+/// to spans.
+///
+/// ```rust
+/// use tracing_perfetto::PerfettoAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: PerfettoAllocator = PerfettoAllocator::new(std::alloc::System);
+/// ```
+pub struct PerfettoAllocator<A: GlobalAlloc = System> {
+    inner: A,
+}
+
+impl<A: GlobalAlloc> PerfettoAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+fn record(allocated: usize, freed: usize) {
+    HEAP_SIZE.fetch_add(allocated as i64 - freed as i64, Ordering::Relaxed);
+    // The thread local is gone once the thread is being torn down, its frees aren't counted.
+    _ = THREAD_COUNTERS.try_with(|counters| {
+        let mut current = counters.get();
+        if allocated > 0 {
+            current.allocated_bytes += allocated as u64;
+            current.allocations += 1;
+        }
+        if freed > 0 {
+            current.freed_bytes += freed as u64;
+            current.frees += 1;
+        }
+        counters.set(current);
+    });
+}
+
+// SAFETY: every call is forwarded to the inner allocator, counting doesn't allocate.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for PerfettoAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record(layout.size(), 0);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record(layout.size(), 0);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        record(0, layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record(new_size, layout.size());
+        }
+        new_ptr
+    }
+}
+
+/// Allocations of one thread since it started.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct AllocationCounters {
+    pub allocated_bytes: u64,
+    pub allocations: u64,
+    pub freed_bytes: u64,
+    pub frees: u64,
+}
+
+impl AllocationCounters {
+    pub fn current_thread() -> Self {
+        THREAD_COUNTERS.with(Cell::get)
+    }
+
+    pub fn debug_annotations(&self) -> impl Iterator<Item = idl::DebugAnnotation> {
+        [
+            ("allocated_bytes", self.allocated_bytes),
+            ("allocations", self.allocations),
+            ("freed_bytes", self.freed_bytes),
+            ("frees", self.frees),
+        ]
+        .into_iter()
+        .map(|(name, value)| uint_annotation(name, value))
+    }
+
+    pub fn add_since(&mut self, start: Self, end: Self) {
+        self.allocated_bytes += end.allocated_bytes - start.allocated_bytes;
+        self.allocations += end.allocations - start.allocations;
+        self.freed_bytes += end.freed_bytes - start.freed_bytes;
+        self.frees += end.frees - start.frees;
+    }
+}
+
+pub(crate) fn heap_size() -> i64 {
+    HEAP_SIZE.load(Ordering::Relaxed)
+}
+
+/// Allocations made while a span was entered.
+#[derive(Default)]
+pub(crate) struct SpanAllocations {
+    /// Counters of the thread at the last enter, if the span is entered.
+    pub entered: Option<AllocationCounters>,
+    pub total: AllocationCounters,
+}
@@ -0,0 +1,84 @@
+//! Background threads of the layer, e.g. the samplers of counters.
+
+use crate::idl;
+use crate::idl_helpers::create_counter_event;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// A thread running a task periodically, stopped when dropped.
+pub(crate) struct Periodic {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    /// Spawns a thread named `name` running `tick` right away, then every `interval`, and a
+    /// last time when stopped.
+    pub fn start(
+        name: &str,
+        interval: Duration,
+        mut tick: impl FnMut() + Send + 'static,
+    ) -> std::io::Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                tick();
+                loop {
+                    let done = !matches!(
+                        stopped.recv_timeout(interval),
+                        Err(mpsc::RecvTimeoutError::Timeout)
+                    );
+                    tick();
+                    if done {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// Builds the packets of one sample of counters, `values` being those of `tracks`, in order.
+pub(crate) fn counter_trace<V: Into<idl::track_event::CounterValueField>>(
+    tracks: &[idl::TrackDescriptor],
+    values: impl IntoIterator<Item = Option<V>>,
+    sequence_id: u32,
+) -> idl::Trace {
+    let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
+    // counter tracks are described with every sample, like the named tracks of the spans
+    let descriptors = tracks.iter().map(|track| idl::TracePacket {
+        data: Some(idl::trace_packet::Data::TrackDescriptor(track.clone())),
+        ..Default::default()
+    });
+    let counters = tracks.iter().zip(values).filter_map(|(track, value)| {
+        let event = create_counter_event(track.uuid(), value?);
+        Some(idl::TracePacket {
+            timestamp,
+            trusted_pid: Some(std::process::id() as _),
+            optional_trusted_packet_sequence_id: Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    sequence_id,
+                ),
+            ),
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            ..Default::default()
+        })
+    });
+    idl::Trace {
+        packet: descriptors.chain(counters).collect(),
+    }
+}
@@ -0,0 +1,69 @@
+//! Converts traces written by `tracing-perfetto` into plain-text formats.
+//!
+//! ```text
+//! tracing-perfetto-cli <json|folded|csv> <input.pftrace> [output]
+//! tracing-perfetto-cli merge <output.pftrace> <input.pftrace>...
+//! ```
+//!
+//! The output defaults to stdout.
+
+use anyhow::{bail, Context};
+use std::io::Write;
+
+const USAGE: &str = "usage: tracing-perfetto-cli <json|folded|csv> <input.pftrace> [output]
+       tracing-perfetto-cli merge <output.pftrace> <input.pftrace>...
+
+  json    Chrome JSON trace event format (about:tracing, speedscope)
+  folded  folded stacks for flamegraph tools, weighted by self time in ns
+  csv     per slice name: count, total, self and max duration in ns
+  merge   the traces of processes sharing a trace context, into one trace";
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    if args.first().is_some_and(|command| command == "merge") {
+        return match args.as_slice() {
+            [_, output, inputs @ ..] if !inputs.is_empty() => merge(output, inputs),
+            _ => bail!("{USAGE}"),
+        };
+    }
+    let (format, input, output) = match args.as_slice() {
+        [format, input] => (format, input, None),
+        [format, input, output] => (format, input, Some(output)),
+        _ => bail!("{USAGE}"),
+    };
+
+    let trace = std::fs::read(input).with_context(|| format!("failed to read {input}"))?;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path).with_context(|| format!("failed to create {path}"))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    match format.as_str() {
+        "json" => tracing_perfetto::convert::to_chrome_json(&trace, &mut out),
+        "folded" => tracing_perfetto::convert::to_folded_stacks(&trace, &mut out),
+        "csv" => tracing_perfetto::convert::to_csv_summary(&trace, &mut out),
+        other => bail!("unknown format `{other}`\n\n{USAGE}"),
+    }
+    .with_context(|| format!("failed to convert {input}"))?;
+    out.flush()?;
+    Ok(())
+}
+
+fn merge(output: &str, inputs: &[String]) -> anyhow::Result<()> {
+    let traces = inputs
+        .iter()
+        .map(|input| std::fs::read(input).with_context(|| format!("failed to read {input}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut out = std::io::BufWriter::new(
+        std::fs::File::create(output).with_context(|| format!("failed to create {output}"))?,
+    );
+    tracing_perfetto::convert::merge(&traces, &mut out).context("failed to merge the traces")?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! Call stack capture, see [`PerfettoLayer::with_callstacks`].
+//!
+//! Stacks are written as `PerfSample` packets referencing interned `Callstack`/`Frame`/`Mapping`
+//! data. Since the layer writes packets out of order (spans are written when they close), every
+//! sample packet carries the interned data it refers to and starts a fresh interning state on
+//! its own sequence. Symbolization, the expensive part, is still done once per unique frame.
+//!
+//! [`PerfettoLayer::with_callstacks`]: crate::PerfettoLayer::with_callstacks
+
+use crate::idl;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+
+/// Deepest stack captured, in frames.
+const MAX_DEPTH: usize = 128;
+
+/// Frames of the tracing machinery leading to the capture, stripped from the top of the stacks.
+const TRACING_FRAMES: &[&str] = &[
+    "backtrace::",
+    "tracing::",
+    "tracing_core::",
+    "tracing_subscriber::",
+    "tracing_perfetto::PerfettoLayer",
+    "tracing_perfetto::callstack::",
+];
+
+/// Looks for the `perfetto.callstack = true` marker.
+#[derive(Default)]
+pub(crate) struct CallstackVisitor {
+    pub marked: bool,
+}
+
+impl Visit for CallstackVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "perfetto.callstack" {
+            self.marked = value;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[derive(Clone)]
+struct Frame {
+    iid: u64,
+    ip: u64,
+    function_name: Arc<str>,
+}
+
+impl Frame {
+    fn is_tracing(&self) -> bool {
+        let name = self.function_name.trim_start_matches('<');
+        TRACING_FRAMES.iter().any(|prefix| name.starts_with(prefix))
+    }
+
+    fn is_std(&self) -> bool {
+        let name = self.function_name.trim_start_matches('<');
+        ["std::", "core::", "alloc::"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    }
+}
+
+/// A symbolized stack, innermost frame first.
+pub(crate) struct Callstack {
+    frames: Vec<Frame>,
+}
+
+impl Callstack {
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Builds a self-contained `PerfSample` packet for this stack, sampled on thread `tid`.
+    pub fn sample_packet(
+        &self,
+        tid: u32,
+        timestamp: Option<u64>,
+        sequence_id: u32,
+    ) -> idl::TracePacket {
+        let mut interned = idl::InternedData {
+            mapping_paths: vec![idl::InternedString {
+                iid: Some(1),
+                str: Some(main_binary_path().into_bytes()),
+            }],
+            // Frames are symbolized in-process, the mapping is only there for completeness.
+            mappings: vec![idl::Mapping {
+                iid: Some(1),
+                start: Some(0),
+                end: Some(u64::MAX),
+                path_string_ids: vec![1],
+                ..Default::default()
+            }],
+            callstacks: vec![idl::Callstack {
+                iid: Some(1),
+                // outermost frame first
+                frame_ids: self.frames.iter().rev().map(|frame| frame.iid).collect(),
+            }],
+            ..Default::default()
+        };
+        let mut seen = std::collections::HashSet::new();
+        for frame in self.frames.iter().filter(|frame| seen.insert(frame.iid)) {
+            interned.function_names.push(idl::InternedString {
+                iid: Some(frame.iid),
+                str: Some(frame.function_name.as_bytes().to_vec()),
+            });
+            interned.frames.push(idl::Frame {
+                iid: Some(frame.iid),
+                function_name_id: Some(frame.iid),
+                mapping_id: Some(1),
+                rel_pc: Some(frame.ip),
+            });
+        }
+
+        let sample = idl::PerfSample {
+            pid: Some(std::process::id()),
+            tid: Some(tid),
+            callstack_iid: Some(1),
+            ..Default::default()
+        };
+        idl::TracePacket {
+            timestamp,
+            trusted_pid: Some(std::process::id() as _),
+            optional_trusted_packet_sequence_id: Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    sequence_id,
+                ),
+            ),
+            interned_data: Some(interned),
+            sequence_flags: Some(
+                idl::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32
+                    | idl::trace_packet::SequenceFlags::SeqNeedsIncrementalState as u32,
+            ),
+            data: Some(idl::trace_packet::Data::PerfSample(sample)),
+            ..Default::default()
+        }
+    }
+}
+
+fn main_binary_path() -> String {
+    std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Cache {
+    /// instruction pointer -> its frames, more than one when functions were inlined
+    by_ip: HashMap<u64, Vec<Frame>>,
+    next_iid: u64,
+}
+
+/// Symbolizes instruction pointers, once per unique frame.
+#[derive(Default)]
+pub(crate) struct Symbolizer {
+    cache: Mutex<Cache>,
+}
+
+impl Symbolizer {
+    /// Captures the stack of the current thread, without the frames of tracing itself.
+    pub fn capture(&self) -> Callstack {
+        let mut ips = Vec::with_capacity(MAX_DEPTH);
+        backtrace::trace(|frame| {
+            ips.push(frame.ip() as u64);
+            ips.len() < MAX_DEPTH
+        });
+
+        let mut callstack = self.symbolize(&ips);
+        // the standard library shows up in between, e.g. to reach the thread local dispatcher
+        let top = callstack
+            .frames
+            .iter()
+            .take_while(|frame| frame.is_tracing() || frame.is_std())
+            .count();
+        let tracing_frames = callstack.frames[..top]
+            .iter()
+            .rposition(Frame::is_tracing)
+            .map_or(0, |last| last + 1);
+        callstack.frames.drain(..tracing_frames);
+        callstack
+    }
+
+    /// Symbolizes a stack of instruction pointers, innermost first.
+    pub fn symbolize(&self, ips: &[u64]) -> Callstack {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let mut frames = Vec::with_capacity(ips.len());
+        for &ip in ips {
+            if !cache.by_ip.contains_key(&ip) {
+                let resolved = cache.resolve(ip);
+                cache.by_ip.insert(ip, resolved);
+            }
+            frames.extend(cache.by_ip[&ip].iter().cloned());
+        }
+        Callstack { frames }
+    }
+}
+
+impl Cache {
+    fn resolve(&mut self, ip: u64) -> Vec<Frame> {
+        let mut names = vec![];
+        backtrace::resolve(ip as usize as *mut std::ffi::c_void, |symbol| {
+            // `{:#}` leaves out the trailing hash of mangled names
+            names.push(symbol.name().map(|name| format!("{name:#}")));
+        });
+        if names.is_empty() {
+            names.push(None);
+        }
+        names
+            .into_iter()
+            .map(|name| {
+                self.next_iid += 1;
+                Frame {
+                    iid: self.next_iid,
+                    ip,
+                    function_name: name.unwrap_or_else(|| format!("{ip:#x}")).into(),
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,393 @@
+//! Encoder for Chrome's JSON trace event format.
+//!
+//! Perfetto tracks are mapped onto `pid`/`tid` pairs: thread and process tracks keep their own
+//! ids, every other track (named tracks, counter tracks) gets a virtual `tid` inside its process,
+//! named through a `thread_name` metadata event.
+
+use crate::encoder::Encoder;
+use crate::idl;
+use bytes::BytesMut;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Virtual thread ids start above any `pid_max` a kernel hands out, so they never collide with
+/// real threads.
+const VIRTUAL_TID_BASE: i64 = 1 << 32;
+
+#[derive(Default)]
+pub(crate) struct ChromeJsonEncoder {
+    state: Mutex<State>,
+}
+
+impl Encoder for ChromeJsonEncoder {
+    fn encode(&self, trace: idl::Trace, buf: &mut BytesMut) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut events = Vec::new();
+        for packet in &trace.packet {
+            state.convert_packet(packet, &mut events);
+        }
+
+        if !state.started {
+            state.started = true;
+            buf.extend_from_slice(b"[\n");
+        }
+        for event in events {
+            buf.extend_from_slice(&serde_json::to_vec(&event)?);
+            buf.extend_from_slice(b",\n");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct JsonTrack {
+    pid: i64,
+    tid: i64,
+    name: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Whether the opening `[` has been written.
+    started: bool,
+    tracks: HashMap<u64, JsonTrack>,
+    /// `(pid, tid)` pairs that already got a `thread_name` metadata event.
+    named_threads: HashSet<(i64, i64)>,
+    named_processes: HashSet<i64>,
+    next_virtual_tid: i64,
+    /// Flows that were started by an `s` event and not yet finished.
+    open_flows: HashSet<u64>,
+}
+
+impl State {
+    fn convert_packet(&mut self, packet: &idl::TracePacket, out: &mut Vec<Value>) {
+        match &packet.data {
+            Some(idl::trace_packet::Data::TrackDescriptor(desc)) => self.register_track(desc, out),
+            Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                let ts = packet.timestamp.unwrap_or_default();
+                let pid = packet
+                    .trusted_pid
+                    .map(i64::from)
+                    .unwrap_or_else(|| std::process::id().into());
+                self.convert_event(event, ts, pid, out);
+            }
+            Some(idl::trace_packet::Data::PerfSample(sample)) => {
+                let Some(frames) = packet
+                    .interned_data
+                    .as_ref()
+                    .and_then(|interned| resolve_callstack(interned, sample.callstack_iid()))
+                else {
+                    return;
+                };
+                // `tid` is the same truncated thread id as the one of the thread descriptors
+                out.push(json!({
+                    "ph": "i", "s": "t", "name": "callstack",
+                    "ts": packet.timestamp.unwrap_or_default() as f64 / 1000.0,
+                    "pid": sample.pid(), "tid": sample.tid() as i32,
+                    "args": { "frames": frames },
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    fn register_track(&mut self, desc: &idl::TrackDescriptor, out: &mut Vec<Value>) {
+        let name = desc.display_name().map(str::to_string);
+
+        let track = if let Some(thread) = &desc.thread {
+            JsonTrack {
+                pid: thread.pid().into(),
+                tid: thread.tid().into(),
+                name: thread.thread_name.clone().or(name),
+            }
+        } else if let Some(process) = &desc.process {
+            let pid = process.pid().into();
+            if let Some(process_name) = process.process_name.as_ref().or(name.as_ref()) {
+                if self.named_processes.insert(pid) {
+                    out.push(json!({
+                        "ph": "M", "name": "process_name", "pid": pid,
+                        "args": { "name": process_name },
+                    }));
+                }
+            }
+            JsonTrack { pid, tid: 0, name }
+        } else if let Some(existing) = self.tracks.get(&desc.uuid()) {
+            // Child tracks are described again with every record; keep their virtual tid.
+            existing.clone()
+        } else {
+            let pid = desc
+                .parent_uuid
+                .and_then(|parent| self.tracks.get(&parent))
+                .map(|parent| parent.pid)
+                .unwrap_or_else(|| std::process::id().into());
+            JsonTrack {
+                pid,
+                tid: self.virtual_tid(),
+                name,
+            }
+        };
+
+        if let Some(name) = &track.name {
+            if desc.process.is_none() && self.named_threads.insert((track.pid, track.tid)) {
+                out.push(json!({
+                    "ph": "M", "name": "thread_name", "pid": track.pid, "tid": track.tid,
+                    "args": { "name": name },
+                }));
+            }
+        }
+        self.tracks.insert(desc.uuid(), track);
+    }
+
+    fn virtual_tid(&mut self) -> i64 {
+        self.next_virtual_tid += 1;
+        VIRTUAL_TID_BASE + self.next_virtual_tid
+    }
+
+    fn track(&mut self, uuid: u64, pid: i64) -> JsonTrack {
+        if let Some(track) = self.tracks.get(&uuid) {
+            return track.clone();
+        }
+        // Not described (yet), still keep all its events on one row.
+        let track = JsonTrack {
+            pid,
+            tid: self.virtual_tid(),
+            name: None,
+        };
+        self.tracks.insert(uuid, track.clone());
+        track
+    }
+
+    fn convert_event(&mut self, event: &idl::TrackEvent, ts: u64, pid: i64, out: &mut Vec<Value>) {
+        let track = self.track(event.track_uuid(), pid);
+        let ts = ts as f64 / 1000.0;
+        let name = match &event.name_field {
+            Some(idl::track_event::NameField::Name(name)) => name.as_str(),
+            _ => "",
+        };
+        let cat = event.categories.join(",");
+
+        let mut base = json!({
+            "name": name, "cat": cat, "ts": ts, "pid": track.pid, "tid": track.tid,
+        });
+        let fields = base.as_object_mut().expect("base event is an object");
+
+        match event.r#type() {
+            idl::track_event::Type::SliceBegin => {
+                fields.insert("ph".into(), "B".into());
+            }
+            idl::track_event::Type::SliceEnd => {
+                fields.insert("ph".into(), "E".into());
+            }
+            idl::track_event::Type::Instant => {
+                fields.insert("ph".into(), "i".into());
+                fields.insert("s".into(), "t".into());
+            }
+            idl::track_event::Type::Counter => {
+                let counter_name = track.name.clone().unwrap_or_else(|| name.to_string());
+                let value = match event.counter_value_field {
+                    Some(idl::track_event::CounterValueField::CounterValue(v)) => json!(v),
+                    Some(idl::track_event::CounterValueField::DoubleCounterValue(v)) => json!(v),
+                    None => Value::Null,
+                };
+                fields.insert("ph".into(), "C".into());
+                fields.insert("name".into(), counter_name.clone().into());
+                fields.insert("args".into(), json!({ counter_name: value }));
+            }
+            idl::track_event::Type::Unspecified => return,
+        }
+
+        if !event.debug_annotations.is_empty() {
+            let args = annotations_to_json(&event.debug_annotations);
+            fields.insert("args".into(), Value::Object(args));
+        }
+        out.push(base);
+
+        self.convert_extra_counters(event, ts, pid, out);
+        self.convert_flows(event, name, &cat, ts, &track, out);
+    }
+
+    fn convert_extra_counters(
+        &mut self,
+        event: &idl::TrackEvent,
+        ts: f64,
+        pid: i64,
+        out: &mut Vec<Value>,
+    ) {
+        let int_values = event
+            .extra_counter_track_uuids
+            .iter()
+            .zip(event.extra_counter_values.iter().map(|v| json!(v)));
+        let double_values = event
+            .extra_double_counter_track_uuids
+            .iter()
+            .zip(event.extra_double_counter_values.iter().map(|v| json!(v)));
+        for (uuid, value) in int_values.chain(double_values) {
+            let track = self.track(*uuid, pid);
+            let name = track.name.unwrap_or_else(|| uuid.to_string());
+            out.push(json!({
+                "ph": "C", "name": name, "ts": ts, "pid": track.pid, "tid": track.tid,
+                "args": { name: value },
+            }));
+        }
+    }
+
+    fn convert_flows(
+        &mut self,
+        event: &idl::TrackEvent,
+        name: &str,
+        cat: &str,
+        ts: f64,
+        track: &JsonTrack,
+        out: &mut Vec<Value>,
+    ) {
+        let flow = |ph: &str, id: u64| {
+            json!({
+                "ph": ph, "id": id, "name": name, "cat": cat, "ts": ts,
+                "pid": track.pid, "tid": track.tid, "bp": "e",
+            })
+        };
+        for id in &event.flow_ids {
+            // A flow passing through several events is a chain of `s` -> `f` arrows.
+            if self.open_flows.contains(id) {
+                out.push(flow("f", *id));
+            }
+            out.push(flow("s", *id));
+            self.open_flows.insert(*id);
+        }
+        for id in &event.terminating_flow_ids {
+            out.push(flow("f", *id));
+            self.open_flows.remove(id);
+        }
+    }
+}
+
+/// Resolves the function names of an interned callstack, innermost frame first.
+fn resolve_callstack(interned: &idl::InternedData, iid: u64) -> Option<Vec<String>> {
+    let callstack = interned.callstacks.iter().find(|c| c.iid() == iid)?;
+    let function_name = |frame_iid: u64| {
+        let frame = interned.frames.iter().find(|f| f.iid() == frame_iid)?;
+        let name = interned
+            .function_names
+            .iter()
+            .find(|name| name.iid() == frame.function_name_id())?;
+        Some(String::from_utf8_lossy(name.str()).into_owned())
+    };
+    Some(
+        callstack
+            .frame_ids
+            .iter()
+            .rev()
+            .map(|&frame_iid| function_name(frame_iid).unwrap_or_else(|| "?".to_string()))
+            .collect(),
+    )
+}
+
+fn annotations_to_json(annotations: &[idl::DebugAnnotation]) -> Map<String, Value> {
+    annotations
+        .iter()
+        .map(|annotation| {
+            let name = match &annotation.name_field {
+                Some(idl::debug_annotation::NameField::Name(name)) => name.clone(),
+                Some(idl::debug_annotation::NameField::NameIid(iid)) => iid.to_string(),
+                None => String::new(),
+            };
+            (name, annotation_value(annotation))
+        })
+        .collect()
+}
+
+fn annotation_value(annotation: &idl::DebugAnnotation) -> Value {
+    use idl::debug_annotation::Value as V;
+
+    if !annotation.dict_entries.is_empty() {
+        return Value::Object(annotations_to_json(&annotation.dict_entries));
+    }
+    if !annotation.array_values.is_empty() {
+        return Value::Array(
+            annotation
+                .array_values
+                .iter()
+                .map(annotation_value)
+                .collect(),
+        );
+    }
+    match &annotation.value {
+        Some(V::BoolValue(v)) => json!(v),
+        Some(V::UintValue(v)) => json!(v),
+        Some(V::IntValue(v)) => json!(v),
+        Some(V::DoubleValue(v)) => json!(v),
+        Some(V::PointerValue(v)) => json!(format!("0x{v:x}")),
+        Some(V::StringValue(v)) => json!(v),
+        Some(V::LegacyJsonValue(v)) => serde_json::from_str(v).unwrap_or_else(|_| json!(v)),
+        Some(V::StringValueIid(iid)) => json!(iid),
+        Some(V::NestedValue(_)) | None => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice_event(track_uuid: u64, kind: idl::track_event::Type) -> idl::TrackEvent {
+        let mut event = idl::TrackEvent {
+            track_uuid: Some(track_uuid),
+            name_field: Some(idl::track_event::NameField::Name("work".to_string())),
+            ..Default::default()
+        };
+        event.set_type(kind);
+        event
+    }
+
+    fn packet(ts: u64, event: idl::TrackEvent) -> idl::TracePacket {
+        idl::TracePacket {
+            timestamp: Some(ts),
+            trusted_pid: Some(42),
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            ..Default::default()
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Vec<Value> {
+        let text = std::str::from_utf8(buf)
+            .unwrap()
+            .trim_end()
+            .trim_end_matches(',');
+        serde_json::from_str(&format!("{text}]")).unwrap()
+    }
+
+    #[test]
+    fn test_flows_become_start_and_finish_events() {
+        let named = idl::TrackDescriptor::named_child_for("worker", 1);
+        let uuid = named.uuid();
+        let mut begin = slice_event(uuid, idl::track_event::Type::SliceBegin);
+        begin.flow_ids = vec![7];
+        let mut end = slice_event(uuid, idl::track_event::Type::Instant);
+        end.terminating_flow_ids = vec![7];
+
+        let trace = idl::Trace {
+            packet: vec![
+                idl::TracePacket {
+                    data: Some(idl::trace_packet::Data::TrackDescriptor(named)),
+                    ..Default::default()
+                },
+                packet(1_000, begin),
+                packet(3_000, end),
+            ],
+        };
+        let mut buf = BytesMut::new();
+        ChromeJsonEncoder::default()
+            .encode(trace, &mut buf)
+            .unwrap();
+        let events = decode(&buf);
+
+        let phases: Vec<&str> = events.iter().map(|e| e["ph"].as_str().unwrap()).collect();
+        assert_eq!(phases, ["M", "B", "s", "i", "f"]);
+        assert_eq!(events[0]["args"]["name"], "worker");
+        assert_eq!(events[2]["id"], 7);
+        assert_eq!(events[4]["id"], 7);
+        assert_eq!(events[4]["ts"], 3.0);
+        // Every event of the named track lands on the same virtual thread.
+        assert!(events.iter().all(|e| e["tid"] == events[0]["tid"]));
+    }
+}
@@ -0,0 +1,152 @@
+//! Trace context propagation to child processes, whose traces can then be merged into one.
+//!
+//! A process spawning workers which write their own trace hands each of them a
+//! [`TraceContext`], through the [`ENV_VAR`] environment variable:
+//!
+//! ```rust,no_run
+//! use tracing_perfetto::{PerfettoLayer, TraceContext};
+//!
+//! // the context of this process, received from its parent or a new one
+//! let context = TraceContext::from_env().unwrap_or_default();
+//! let file = std::fs::File::create(std::env::temp_dir().join("worker.pftrace")).unwrap();
+//! let layer = PerfettoLayer::new(std::sync::Mutex::new(file)).with_trace_context(context.clone());
+//!
+//! let mut worker = std::process::Command::new("worker");
+//! context.inject(&mut worker);
+//! ```
+//!
+//! The traces of the processes share the trace UUID of the context, and a flow links the
+//! `spawn child` instant of the parent to the `trace context` instant the layer of the child
+//! starts its trace with. [`convert::merge`](crate::convert::merge) combines them into one trace.
+
+use crate::idl;
+use tracing::field::{Field, Visit};
+
+/// The environment variable [`TraceContext::inject`] passes the context in.
+pub const ENV_VAR: &str = "TRACING_PERFETTO_CONTEXT";
+
+/// Name of the instant a child's trace starts with.
+pub(crate) const START_EVENT: &str = "trace context";
+
+/// Annotation of the [`START_EVENT`] holding the parent's clock at spawn time.
+pub(crate) const PARENT_TIMESTAMP: &str = "parent_timestamp";
+
+/// The trace a process belongs to, and how it was spawned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_uuid: u128,
+    /// The flow from the parent's `spawn child` instant, for a child.
+    flow_id: Option<u64>,
+    /// The parent's clock when spawning the child, in nanoseconds since the epoch.
+    parent_timestamp: Option<u64>,
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceContext {
+    /// A context for a new trace, with a random UUID.
+    pub fn new() -> Self {
+        Self {
+            trace_uuid: rand::random(),
+            flow_id: None,
+            parent_timestamp: None,
+        }
+    }
+
+    /// The context the parent process passed in [`ENV_VAR`], if any.
+    pub fn from_env() -> Option<Self> {
+        Self::parse(&std::env::var(ENV_VAR).ok()?)
+    }
+
+    /// Parses a context formatted by [`TraceContext::to_env_value`].
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split(':');
+        let trace_uuid = u128::from_str_radix(parts.next()?, 16).ok()?;
+        let flow_id = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let parent_timestamp = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            trace_uuid,
+            flow_id: (flow_id != 0).then_some(flow_id),
+            parent_timestamp: (parent_timestamp != 0).then_some(parent_timestamp),
+        })
+    }
+
+    /// Formats the context as `<trace uuid>:<flow id>:<parent timestamp>`, the ids in hex.
+    pub fn to_env_value(&self) -> String {
+        format!(
+            "{:032x}:{:016x}:{}",
+            self.trace_uuid,
+            self.flow_id.unwrap_or_default(),
+            self.parent_timestamp.unwrap_or_default()
+        )
+    }
+
+    /// The UUID of the trace, shared by every process.
+    pub fn trace_uuid(&self) -> u128 {
+        self.trace_uuid
+    }
+
+    /// The id of the flow from the parent to this process, for a child.
+    pub fn flow_id(&self) -> Option<u64> {
+        self.flow_id
+    }
+
+    /// The context of a child about to be spawned, with a new flow starting at a `spawn child`
+    /// instant recorded in the current span, and the current clock.
+    pub fn for_child(&self) -> Self {
+        let flow_id = rand::random::<u64>().max(1);
+        tracing::info!(name: "spawn child", { perfetto.flow_id = flow_id }, "spawn child");
+        Self {
+            trace_uuid: self.trace_uuid,
+            flow_id: Some(flow_id),
+            parent_timestamp: chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64),
+        }
+    }
+
+    /// Passes the context of a child, see [`TraceContext::for_child`], to `command`.
+    pub fn inject(&self, command: &mut std::process::Command) {
+        command.env(ENV_VAR, self.for_child().to_env_value());
+    }
+
+    /// The clock of the parent when spawning this process, in nanoseconds since the epoch.
+    pub fn parent_timestamp(&self) -> Option<u64> {
+        self.parent_timestamp
+    }
+
+    pub(crate) fn trace_uuid_packet(&self) -> idl::TracePacket {
+        idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TraceUuid(idl::TraceUuid {
+                msb: Some((self.trace_uuid >> 64) as i64),
+                lsb: Some(self.trace_uuid as i64),
+            })),
+            ..Default::default()
+        }
+    }
+}
+
+/// Finds the flows an event starts or ends, in its `perfetto.flow_id` and
+/// `perfetto.terminating_flow_id` fields.
+#[derive(Default)]
+pub(crate) struct FlowVisitor {
+    pub flow_ids: Vec<u64>,
+    pub terminating_flow_ids: Vec<u64>,
+}
+
+impl Visit for FlowVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "perfetto.flow_id" => self.flow_ids.push(value),
+            "perfetto.terminating_flow_id" => self.terminating_flow_ids.push(value),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
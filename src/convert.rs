@@ -0,0 +1,413 @@
+//! Offline conversions of the traces written by [`PerfettoLayer`](crate::PerfettoLayer).
+//!
+//! Each function takes the raw bytes of a protobuf trace (e.g. the content of a `.pftrace` file)
+//! and writes the converted output. These power the `tracing-perfetto-cli` binary (`cli`
+//! feature), but can be used directly as well:
+//!
+//! ```rust,no_run
+//! let trace = std::fs::read("/tmp/test.pftrace").unwrap();
+//! tracing_perfetto::convert::to_csv_summary(&trace, std::io::stdout()).unwrap();
+//! ```
+
+#[cfg(feature = "chrome-json")]
+use crate::chrome_json::ChromeJsonEncoder;
+use crate::context;
+#[cfg(feature = "chrome-json")]
+use crate::encoder::Encoder;
+use crate::idl;
+#[cfg(feature = "chrome-json")]
+use bytes::BytesMut;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+/// Converts a protobuf trace into a complete Chrome JSON trace event array.
+///
+/// Requires the `chrome-json` feature.
+#[cfg(feature = "chrome-json")]
+pub fn to_chrome_json(trace: &[u8], mut out: impl Write) -> std::io::Result<()> {
+    let trace = decode(trace)?;
+    let mut buf = BytesMut::new();
+    ChromeJsonEncoder::default().encode(trace, &mut buf)?;
+
+    // Unlike the streamed output of the layer, a converted file is closed properly.
+    if buf.is_empty() {
+        return out.write_all(b"[]\n");
+    }
+    let events = buf.strip_suffix(b",\n").unwrap_or(&buf);
+    out.write_all(events)?;
+    out.write_all(b"\n]\n")
+}
+
+/// Converts a protobuf trace into the folded stack format read by flamegraph tools (`inferno`,
+/// `flamegraph.pl`, speedscope).
+///
+/// Every line is a `;` separated stack of nested slice names, rooted at the track (thread) name,
+/// followed by the self time of that stack in nanoseconds.
+pub fn to_folded_stacks(trace: &[u8], mut out: impl Write) -> std::io::Result<()> {
+    let slices = Slices::from_trace(&decode(trace)?);
+
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for (index, slice) in slices.slices.iter().enumerate() {
+        let mut frames = vec![];
+        let mut current = Some(index);
+        while let Some(i) = current {
+            frames.push(folded_frame(&slices.slices[i].name));
+            current = slices.slices[i].parent;
+        }
+        frames.push(folded_frame(slices.track_name(slice.track_uuid)));
+        frames.reverse();
+        *stacks.entry(frames.join(";")).or_default() += slice.self_time();
+    }
+
+    for (stack, weight) in stacks {
+        writeln!(out, "{stack} {weight}")?;
+    }
+    Ok(())
+}
+
+/// Converts a protobuf trace into a CSV summary with one row per slice name, sorted by total
+/// duration. Durations are in nanoseconds.
+pub fn to_csv_summary(trace: &[u8], mut out: impl Write) -> std::io::Result<()> {
+    #[derive(Default)]
+    struct Row {
+        count: u64,
+        total: u64,
+        self_time: u64,
+        max: u64,
+    }
+
+    let slices = Slices::from_trace(&decode(trace)?);
+    let mut rows: HashMap<&str, Row> = HashMap::new();
+    for slice in &slices.slices {
+        let row = rows.entry(slice.name.as_str()).or_default();
+        row.count += 1;
+        row.total += slice.duration();
+        row.self_time += slice.self_time();
+        row.max = row.max.max(slice.duration());
+    }
+    let mut rows: Vec<_> = rows.into_iter().collect();
+    rows.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+
+    writeln!(out, "name,count,total_ns,self_ns,max_ns")?;
+    for (name, row) in rows {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            csv_field(name),
+            row.count,
+            row.total,
+            row.self_time,
+            row.max
+        )?;
+    }
+    Ok(())
+}
+
+/// Merges the protobuf traces of the processes sharing a
+/// [`TraceContext`](crate::TraceContext) into one protobuf trace.
+///
+/// The flows between processes match as they are. The packet sequences of the traces are kept
+/// apart, and the trace keeps the UUID of the first trace having one. The clock of a child,
+/// e.g. on another host or in a VM, is aligned on its parent's: its trace is shifted so that it
+/// starts when its parent spawned it, whether its clock was behind or ahead.
+pub fn merge<T: AsRef<[u8]>>(traces: &[T], mut out: impl Write) -> std::io::Result<()> {
+    let mut merged = idl::Trace::default();
+    let mut trace_uuid = None;
+    let mut sequence_ids = HashSet::new();
+    for trace in traces {
+        let mut trace = decode(trace.as_ref())?;
+        let offset = clock_offset(&trace);
+
+        // the ids are random, but remapped on the off chance that two traces share one
+        let mut remapped = HashMap::new();
+        for id in trace
+            .packet
+            .iter()
+            .filter_map(sequence_id)
+            .collect::<HashSet<_>>()
+        {
+            let mut new_id = id;
+            while !sequence_ids.insert(new_id) {
+                new_id = rand::random();
+            }
+            remapped.insert(id, new_id);
+        }
+
+        for mut packet in trace.packet.drain(..) {
+            if let Some(idl::trace_packet::Data::TraceUuid(uuid)) = packet.data {
+                trace_uuid.get_or_insert(uuid);
+                continue;
+            }
+            if let Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(id),
+            ) = &mut packet.optional_trusted_packet_sequence_id
+            {
+                *id = remapped[id];
+            }
+            if let Some(timestamp) = &mut packet.timestamp {
+                *timestamp = timestamp.saturating_add_signed(offset);
+            }
+            merged.packet.push(packet);
+        }
+    }
+
+    if let Some(uuid) = trace_uuid {
+        let packet = idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TraceUuid(uuid)),
+            ..Default::default()
+        };
+        merged.packet.insert(0, packet);
+    }
+    out.write_all(&merged.encode_to_vec())
+}
+
+/// How much the clock of a child is behind (positive) or ahead (negative), from the parent's clock
+/// in the `trace context` instant it starts with.
+fn clock_offset(trace: &idl::Trace) -> i64 {
+    trace
+        .packet
+        .iter()
+        .find_map(|packet| {
+            let Some(idl::trace_packet::Data::TrackEvent(event)) = &packet.data else {
+                return None;
+            };
+            if event.terminating_flow_ids.is_empty()
+                || !matches!(
+                    &event.name_field,
+                    Some(idl::track_event::NameField::Name(name)) if name == context::START_EVENT
+                )
+            {
+                return None;
+            }
+            let parent_timestamp =
+                event.debug_annotations.iter().find_map(|annotation| {
+                    match (&annotation.name_field, &annotation.value) {
+                        (
+                            Some(idl::debug_annotation::NameField::Name(name)),
+                            Some(idl::debug_annotation::Value::UintValue(value)),
+                        ) if name == context::PARENT_TIMESTAMP => Some(*value),
+                        _ => None,
+                    }
+                })?;
+            Some(parent_timestamp as i64 - packet.timestamp? as i64)
+        })
+        .unwrap_or_default()
+}
+
+fn sequence_id(packet: &idl::TracePacket) -> Option<u32> {
+    match packet.optional_trusted_packet_sequence_id {
+        Some(idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(id)) => {
+            Some(id)
+        }
+        _ => None,
+    }
+}
+
+fn decode(trace: &[u8]) -> std::io::Result<idl::Trace> {
+    idl::Trace::decode(trace).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn folded_frame(name: &str) -> String {
+    name.replace([';', '\n'], "_")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// A slice rebuilt from a matching `TYPE_SLICE_BEGIN`/`TYPE_SLICE_END` pair.
+pub(crate) struct Slice {
+    pub track_uuid: u64,
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    /// Index of the enclosing slice on the same track.
+    pub parent: Option<usize>,
+    children_time: u64,
+}
+
+impl Slice {
+    pub fn duration(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn self_time(&self) -> u64 {
+        self.duration().saturating_sub(self.children_time)
+    }
+}
+
+pub(crate) struct Slices {
+    pub slices: Vec<Slice>,
+    track_names: HashMap<u64, String>,
+}
+
+impl Slices {
+    pub fn from_trace(trace: &idl::Trace) -> Self {
+        let mut track_names = HashMap::new();
+        let mut open: HashMap<u64, Vec<(String, u64)>> = HashMap::new();
+        let mut slices = vec![];
+
+        for packet in &trace.packet {
+            match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(desc)) => {
+                    let name = match &desc.thread {
+                        Some(thread) => thread
+                            .thread_name
+                            .clone()
+                            .unwrap_or_else(|| format!("thread {}", thread.tid())),
+                        None => match desc.display_name() {
+                            Some(name) => name.to_string(),
+                            None => continue,
+                        },
+                    };
+                    track_names.insert(desc.uuid(), name);
+                }
+                Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                    let ts = packet.timestamp.unwrap_or_default();
+                    let stack = open.entry(event.track_uuid()).or_default();
+                    match event.r#type() {
+                        idl::track_event::Type::SliceBegin => {
+                            let name = match &event.name_field {
+                                Some(idl::track_event::NameField::Name(name)) => name.clone(),
+                                _ => String::new(),
+                            };
+                            stack.push((name, ts));
+                        }
+                        idl::track_event::Type::SliceEnd => {
+                            let Some((name, start)) = stack.pop() else {
+                                continue;
+                            };
+                            slices.push(Slice {
+                                track_uuid: event.track_uuid(),
+                                name,
+                                start,
+                                end: ts,
+                                parent: None,
+                                children_time: 0,
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Spans are written when they close, so file order isn't time order: rebuild the nesting
+        // per track from the time ranges, outer slices first.
+        slices.sort_by(|a, b| {
+            (a.track_uuid, a.start)
+                .cmp(&(b.track_uuid, b.start))
+                .then(b.end.cmp(&a.end))
+        });
+        let mut stack: Vec<usize> = vec![];
+        for i in 0..slices.len() {
+            while let Some(&top) = stack.last() {
+                let enclosing = slices[top].track_uuid == slices[i].track_uuid
+                    && slices[top].end > slices[i].start;
+                if enclosing {
+                    break;
+                }
+                stack.pop();
+            }
+            if let Some(&parent) = stack.last() {
+                slices[i].parent = Some(parent);
+                slices[parent].children_time += slices[i].duration();
+            }
+            stack.push(i);
+        }
+
+        Self {
+            slices,
+            track_names,
+        }
+    }
+
+    pub fn track_name(&self, uuid: u64) -> &str {
+        self.track_names
+            .get(&uuid)
+            .map(String::as_str)
+            .unwrap_or("unknown")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice_packet(ts: u64, name: &str, kind: idl::track_event::Type) -> idl::TracePacket {
+        let mut event = idl::TrackEvent {
+            track_uuid: Some(1),
+            name_field: Some(idl::track_event::NameField::Name(name.to_string())),
+            ..Default::default()
+        };
+        event.set_type(kind);
+        idl::TracePacket {
+            timestamp: Some(ts),
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            ..Default::default()
+        }
+    }
+
+    /// `outer` [0, 100] containing `inner` [10, 30] and `inner` [50, 60], written in the order
+    /// the layer closes them.
+    fn nested_trace() -> Vec<u8> {
+        use idl::track_event::Type::{SliceBegin, SliceEnd};
+        let track = idl::TrackDescriptor {
+            uuid: Some(1),
+            static_or_dynamic_name: Some(idl::track_descriptor::StaticOrDynamicName::Name(
+                "main".to_string(),
+            )),
+            ..Default::default()
+        };
+        let trace = idl::Trace {
+            packet: vec![
+                idl::TracePacket {
+                    data: Some(idl::trace_packet::Data::TrackDescriptor(track)),
+                    ..Default::default()
+                },
+                slice_packet(10, "inner", SliceBegin),
+                slice_packet(30, "inner", SliceEnd),
+                slice_packet(50, "inner", SliceBegin),
+                slice_packet(60, "inner", SliceEnd),
+                slice_packet(0, "outer", SliceBegin),
+                slice_packet(100, "outer", SliceEnd),
+            ],
+        };
+        trace.encode_to_vec()
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let mut out = vec![];
+        to_folded_stacks(&nested_trace(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main;outer 70\nmain;outer;inner 30\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_summary() {
+        let mut out = vec![];
+        to_csv_summary(&nested_trace(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "name,count,total_ns,self_ns,max_ns\nouter,1,100,70,100\ninner,2,30,30,20\n"
+        );
+    }
+
+    #[cfg(feature = "chrome-json")]
+    #[test]
+    fn test_chrome_json_is_closed() {
+        let mut out = vec![];
+        to_chrome_json(&nested_trace(), &mut out).unwrap();
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(events.iter().filter(|e| e["ph"] == "B").count(), 3);
+    }
+}
@@ -0,0 +1,49 @@
+#[cfg(feature = "chrome-json")]
+use crate::chrome_json::ChromeJsonEncoder;
+use crate::idl;
+use bytes::BytesMut;
+use prost::Message;
+
+/// The format in which [`PerfettoLayer`](crate::PerfettoLayer) hands records to its
+/// [`PerfettoWriter`](crate::PerfettoWriter).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Perfetto's protobuf `Trace` packets, as understood by [ui.perfetto.dev](https://ui.perfetto.dev)
+    /// and `trace_processor`.
+    #[default]
+    Protobuf,
+    /// Chrome's [JSON trace event format](https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU),
+    /// as understood by `about:tracing`, speedscope and ui.perfetto.dev.
+    ///
+    /// Records are written in the JSON array form, which allows the closing `]` to be omitted,
+    /// so the output stays valid while it is being streamed and after the process exits.
+    /// Requires the `chrome-json` feature.
+    #[cfg(feature = "chrome-json")]
+    ChromeJson,
+}
+
+impl OutputFormat {
+    pub(crate) fn encoder(self) -> Box<dyn Encoder> {
+        match self {
+            OutputFormat::Protobuf => Box::new(ProtobufEncoder),
+            #[cfg(feature = "chrome-json")]
+            OutputFormat::ChromeJson => Box::new(ChromeJsonEncoder::default()),
+        }
+    }
+}
+
+/// Turns the packets accumulated by the layer into the bytes written by a `PerfettoWriter`.
+///
+/// Encoders are called once per `write_log`, with every packet of a closed span (or a single
+/// event) in `trace`, so an encoder may keep state across calls.
+pub(crate) trait Encoder: Send + Sync {
+    fn encode(&self, trace: idl::Trace, buf: &mut BytesMut) -> std::io::Result<()>;
+}
+
+struct ProtobufEncoder;
+
+impl Encoder for ProtobufEncoder {
+    fn encode(&self, trace: idl::Trace, buf: &mut BytesMut) -> std::io::Result<()> {
+        trace.encode(buf).map_err(std::io::Error::other)
+    }
+}
@@ -1,11 +1,73 @@
 use crate::idl;
+use std::cell::RefCell;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
 // This is thread safe, since duplicated descriptor will be combined into one by perfetto.
 static PROCESS_DESCRIPTOR_SENT: AtomicBool = AtomicBool::new(false);
 thread_local! {
     static THREAD_TRACK_UUID: AtomicU64 = AtomicU64::new(unique_uuid());
     static THREAD_DESCRIPTOR_SENT: AtomicBool = const { AtomicBool::new(false) };
+    static THREAD_CPU_TIME_TRACK_UUID: u64 = unique_uuid();
+    static THREAD_REGISTRATION: RefCell<Option<ThreadRegistration>> = const { RefCell::new(None) };
+}
+
+/// A parent track grouping the threads of a pool, ordering them by rank.
+#[derive(Clone, Debug)]
+pub struct ThreadGroup {
+    pub uuid: u64,
+    pub name: Arc<str>,
+}
+
+impl ThreadGroup {
+    pub fn new(name: &str) -> Self {
+        Self {
+            uuid: unique_uuid(),
+            name: name.into(),
+        }
+    }
+
+    pub fn track_descriptor(&self, process_track_uuid: u64) -> idl::TrackDescriptor {
+        let mut desc = idl::TrackDescriptor::named_child_for(&self.name, process_track_uuid);
+        desc.uuid = Some(self.uuid);
+        desc.set_child_ordering(idl::track_descriptor::ChildTracksOrdering::Explicit);
+        desc
+    }
+}
+
+/// Name and place of the current thread's track, overriding the `std` thread name.
+struct ThreadRegistration {
+    name: String,
+    group: Option<(ThreadGroup, i32)>,
+}
+
+/// Names the track of the current thread, optionally under `group` at `rank`.
+pub fn register_current_thread(name: String, group: Option<(ThreadGroup, i32)>) {
+    THREAD_REGISTRATION.with(|registration| {
+        *registration.borrow_mut() = Some(ThreadRegistration { name, group });
+    });
+}
+
+/// The group the current thread was registered in, if any.
+pub fn current_thread_group() -> Option<ThreadGroup> {
+    THREAD_REGISTRATION.with(|registration| {
+        registration
+            .borrow()
+            .as_ref()
+            .and_then(|registration| registration.group.as_ref())
+            .map(|(group, _)| group.clone())
+    })
+}
+
+fn current_thread_name() -> Option<String> {
+    THREAD_REGISTRATION
+        .with(|registration| {
+            registration
+                .borrow()
+                .as_ref()
+                .map(|registration| registration.name.clone())
+        })
+        .or_else(|| std::thread::current().name().map(|n| n.to_string()))
 }
 
 #[derive(Default)]
@@ -25,27 +87,51 @@ pub fn current_thread_uuid() -> u64 {
 }
 
 pub fn current_thread_descriptor() -> idl::ThreadDescriptor {
-    let mut thread = idl::ThreadDescriptor::default();
-    thread.pid = Some(std::process::id() as _);
-    thread.tid = Some(thread_id::get() as _);
-    thread.thread_name = std::thread::current().name().map(|n| n.to_string());
-    thread
+    idl::ThreadDescriptor {
+        pid: Some(std::process::id() as _),
+        tid: Some(thread_id::get() as _),
+        thread_name: current_thread_name(),
+        ..Default::default()
+    }
 }
 
 pub fn current_thread_track_descriptor() -> idl::TrackDescriptor {
     let thread_track_uuid = THREAD_TRACK_UUID.with(|id| id.load(Ordering::Relaxed));
-    let thread_desc = current_thread_descriptor().into();
-    let track_desc = create_track_descriptor(
+    let thread_desc = current_thread_descriptor();
+    let group = THREAD_REGISTRATION.with(|registration| {
+        registration
+            .borrow()
+            .as_ref()
+            .and_then(|registration| registration.group.clone())
+    });
+    let mut track_desc = create_track_descriptor(
         thread_track_uuid.into(),
+        group.as_ref().map(|(group, _)| group.uuid),
+        thread_desc.thread_name.clone(),
         None,
-        std::thread::current().name(),
-        None,
-        thread_desc,
+        thread_desc.into(),
         None,
     );
+    track_desc.sibling_order_rank = group.map(|(_, rank)| rank);
     track_desc
 }
 
+pub fn current_thread_cpu_time_track_uuid() -> u64 {
+    THREAD_CPU_TIME_TRACK_UUID.with(|id| *id)
+}
+
+/// The counter track of the CPU time of the current thread, under its thread track.
+pub fn current_thread_cpu_time_track_descriptor() -> idl::TrackDescriptor {
+    idl::TrackDescriptor {
+        uuid: Some(current_thread_cpu_time_track_uuid()),
+        ..idl::TrackDescriptor::counter_child_for(
+            "thread cpu time",
+            current_thread_uuid(),
+            idl::counter_descriptor::Unit::TimeNs,
+        )
+    }
+}
+
 impl idl::TrackDescriptor {
     pub fn named_child_for(name: &str, parent_uuid: u64) -> Self {
         idl::TrackDescriptor {
@@ -58,6 +144,29 @@ impl idl::TrackDescriptor {
         }
     }
 
+    pub fn counter_child_for(
+        name: &str,
+        parent_uuid: u64,
+        unit: idl::counter_descriptor::Unit,
+    ) -> Self {
+        let mut counter = idl::CounterDescriptor::default();
+        counter.set_unit(unit);
+        idl::TrackDescriptor {
+            counter: Some(counter),
+            ..Self::named_child_for(name, parent_uuid)
+        }
+    }
+
+    /// The name this track is displayed with, if any.
+    pub fn display_name(&self) -> Option<&str> {
+        match &self.static_or_dynamic_name {
+            Some(idl::track_descriptor::StaticOrDynamicName::Name(name))
+            | Some(idl::track_descriptor::StaticOrDynamicName::StaticName(name))
+            | Some(idl::track_descriptor::StaticOrDynamicName::AtraceName(name)) => Some(name),
+            None => None,
+        }
+    }
+
     pub fn for_process_descriptor(uuid: u64, process_descriptor: idl::ProcessDescriptor) -> Self {
         idl::TrackDescriptor {
             uuid: Some(uuid),
@@ -116,6 +225,47 @@ pub fn process_descriptor(process_track_uuid: u64) -> Option<idl::TracePacket> {
     Some(packet)
 }
 
+pub fn uint_annotation(name: &str, value: u64) -> idl::DebugAnnotation {
+    idl::DebugAnnotation {
+        name_field: Some(idl::debug_annotation::NameField::Name(name.to_string())),
+        value: Some(idl::debug_annotation::Value::UintValue(value)),
+        ..Default::default()
+    }
+}
+
+pub fn string_annotation(name: &str, value: String) -> idl::DebugAnnotation {
+    idl::DebugAnnotation {
+        name_field: Some(idl::debug_annotation::NameField::Name(name.to_string())),
+        value: Some(idl::debug_annotation::Value::StringValue(value)),
+        ..Default::default()
+    }
+}
+
+impl From<i64> for idl::track_event::CounterValueField {
+    fn from(value: i64) -> Self {
+        Self::CounterValue(value)
+    }
+}
+
+impl From<f64> for idl::track_event::CounterValueField {
+    fn from(value: f64) -> Self {
+        Self::DoubleCounterValue(value)
+    }
+}
+
+pub fn create_counter_event(
+    track_uuid: u64,
+    value: impl Into<idl::track_event::CounterValueField>,
+) -> idl::TrackEvent {
+    let mut event = idl::TrackEvent {
+        track_uuid: Some(track_uuid),
+        counter_value_field: Some(value.into()),
+        ..Default::default()
+    };
+    event.set_type(idl::track_event::Type::Counter);
+    event
+}
+
 pub fn create_event(
     track_uuid: u64,
     name: Option<&str>,
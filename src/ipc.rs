@@ -0,0 +1,367 @@
+//! Perfetto's IPC protocol, as spoken on the `traced` producer socket.
+//!
+//! Every message is an `IPCFrame` prefixed with its size as a little-endian `u32`. File
+//! descriptors, like the shared memory buffer sent by the service, travel as `SCM_RIGHTS`
+//! ancillary data of the frame they belong to.
+//!
+//! The messages are transcribed from `protos/perfetto/ipc/wire_protocol.proto` and
+//! `protos/perfetto/ipc/producer_port.proto`, keeping only the fields this crate uses.
+
+use bytes::{Buf, BytesMut};
+use prost::Message;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Largest frame accepted from the service, as `kIPCBufferSize` in Perfetto.
+const MAX_FRAME_SIZE: usize = 128 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct IpcFrame {
+    #[prost(uint64, optional, tag = "2")]
+    pub request_id: Option<u64>,
+    #[prost(oneof = "ipc_frame::Msg", tags = "3, 4, 5, 6, 7")]
+    pub msg: Option<ipc_frame::Msg>,
+}
+
+pub mod ipc_frame {
+    use prost::{Message, Oneof};
+
+    #[derive(Clone, PartialEq, Oneof)]
+    pub enum Msg {
+        #[prost(message, tag = "3")]
+        BindService(BindService),
+        #[prost(message, tag = "4")]
+        BindServiceReply(BindServiceReply),
+        #[prost(message, tag = "5")]
+        InvokeMethod(InvokeMethod),
+        #[prost(message, tag = "6")]
+        InvokeMethodReply(InvokeMethodReply),
+        #[prost(message, tag = "7")]
+        RequestError(RequestError),
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct BindService {
+        #[prost(string, optional, tag = "1")]
+        pub service_name: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct BindServiceReply {
+        #[prost(bool, optional, tag = "1")]
+        pub success: Option<bool>,
+        #[prost(uint32, optional, tag = "2")]
+        pub service_id: Option<u32>,
+        #[prost(message, repeated, tag = "3")]
+        pub methods: Vec<MethodInfo>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct MethodInfo {
+        #[prost(uint32, optional, tag = "1")]
+        pub id: Option<u32>,
+        #[prost(string, optional, tag = "2")]
+        pub name: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct InvokeMethod {
+        #[prost(uint32, optional, tag = "1")]
+        pub service_id: Option<u32>,
+        #[prost(uint32, optional, tag = "2")]
+        pub method_id: Option<u32>,
+        #[prost(bytes = "vec", optional, tag = "3")]
+        pub args_proto: Option<Vec<u8>>,
+        #[prost(bool, optional, tag = "4")]
+        pub drop_reply: Option<bool>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct InvokeMethodReply {
+        #[prost(bool, optional, tag = "1")]
+        pub success: Option<bool>,
+        #[prost(bool, optional, tag = "2")]
+        pub has_more: Option<bool>,
+        #[prost(bytes = "vec", optional, tag = "3")]
+        pub reply_proto: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct RequestError {
+        #[prost(string, optional, tag = "1")]
+        pub error: Option<String>,
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InitializeConnectionRequest {
+    #[prost(uint32, optional, tag = "1")]
+    pub shared_memory_page_size_hint_bytes: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub shared_memory_size_hint_bytes: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub producer_name: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub sdk_version: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InitializeConnectionResponse {
+    #[prost(bool, optional, tag = "1")]
+    pub using_shmem_provided_by_producer: Option<bool>,
+    #[prost(bool, optional, tag = "3")]
+    pub use_shmem_emulation: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DataSourceDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(bool, optional, tag = "2")]
+    pub will_notify_on_stop: Option<bool>,
+    #[prost(bool, optional, tag = "3")]
+    pub will_notify_on_start: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RegisterDataSourceRequest {
+    #[prost(message, optional, tag = "1")]
+    pub data_source_descriptor: Option<DataSourceDescriptor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RegisterDataSourceResponse {
+    #[prost(string, optional, tag = "1")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CommitDataRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub chunks_to_move: Vec<ChunksToMove>,
+    #[prost(uint64, optional, tag = "3")]
+    pub flush_request_id: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunksToMove {
+    #[prost(uint32, optional, tag = "1")]
+    pub page: Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub chunk: Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub target_buffer: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetAsyncCommandRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetAsyncCommandResponse {
+    #[prost(oneof = "get_async_command_response::Cmd", tags = "1, 2, 3, 5")]
+    pub cmd: Option<get_async_command_response::Cmd>,
+}
+
+pub mod get_async_command_response {
+    use crate::idl;
+    use prost::{Message, Oneof};
+
+    #[derive(Clone, PartialEq, Oneof)]
+    pub enum Cmd {
+        #[prost(message, tag = "1")]
+        StartDataSource(StartDataSource),
+        #[prost(message, tag = "2")]
+        StopDataSource(StopDataSource),
+        #[prost(message, tag = "3")]
+        SetupTracing(SetupTracing),
+        #[prost(message, tag = "5")]
+        Flush(Flush),
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct SetupTracing {
+        #[prost(uint32, optional, tag = "1")]
+        pub shared_buffer_page_size_kb: Option<u32>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct StartDataSource {
+        #[prost(uint64, optional, tag = "1")]
+        pub new_instance_id: Option<u64>,
+        #[prost(message, optional, boxed, tag = "2")]
+        pub config: Option<Box<idl::DataSourceConfig>>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct StopDataSource {
+        #[prost(uint64, optional, tag = "1")]
+        pub instance_id: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Flush {
+        #[prost(uint64, repeated, packed = "false", tag = "1")]
+        pub data_source_ids: Vec<u64>,
+        #[prost(uint64, optional, tag = "2")]
+        pub request_id: Option<u64>,
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NotifyDataSourceStartedRequest {
+    #[prost(uint64, optional, tag = "1")]
+    pub data_source_id: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NotifyDataSourceStoppedRequest {
+    #[prost(uint64, optional, tag = "1")]
+    pub data_source_id: Option<u64>,
+}
+
+/// Writes `frame` on `socket`, along with `fd` if any.
+pub fn send_frame(
+    socket: &UnixStream,
+    frame: &IpcFrame,
+    fd: Option<BorrowedFd<'_>>,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(4 + frame.encoded_len());
+    buf.extend_from_slice(&(frame.encoded_len() as u32).to_le_bytes());
+    frame.encode(&mut buf).map_err(io::Error::other)?;
+    let Some(fd) = fd else {
+        return (&*socket).write_all(&buf);
+    };
+
+    // the descriptor goes with the first byte of the frame, the rest is a plain write
+    let sent = send_with_fd(socket, &buf, fd)?;
+    (&*socket).write_all(&buf[sent..])
+}
+
+fn send_with_fd(socket: &UnixStream, buf: &[u8], fd: BorrowedFd<'_>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut _,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 8];
+    // SAFETY: `msghdr` is plain data, for which zeroes are valid.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    // SAFETY: `CMSG_SPACE` is a pure computation.
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as _) } as _;
+    // SAFETY: `msg_control` points to `control`, large enough for one header and descriptor.
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&msg);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as _) as _;
+        libc::CMSG_DATA(header)
+            .cast::<RawFd>()
+            .write_unaligned(fd.as_raw_fd());
+    }
+    loop {
+        // SAFETY: `msg` points to `iov` and `control`, both alive for the call.
+        let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+        if sent >= 0 {
+            return Ok(sent as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Reads the frames of a socket, keeping the file descriptors received along them.
+#[derive(Default)]
+pub struct FrameReader {
+    buf: BytesMut,
+    fds: VecDeque<OwnedFd>,
+}
+
+impl FrameReader {
+    /// Blocks until the next frame is read from `socket`.
+    pub fn read_frame(&mut self, socket: &UnixStream) -> io::Result<IpcFrame> {
+        loop {
+            if self.buf.len() >= 4 {
+                let size = u32::from_le_bytes(self.buf[..4].try_into().unwrap()) as usize;
+                if size > MAX_FRAME_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("IPC frame of {size} bytes"),
+                    ));
+                }
+                if self.buf.len() >= 4 + size {
+                    self.buf.advance(4);
+                    let frame = self.buf.split_to(size);
+                    return IpcFrame::decode(frame).map_err(io::Error::other);
+                }
+            }
+
+            let mut chunk = [0u8; 16 * 1024];
+            let read = recv_with_fds(socket, &mut chunk, &mut self.fds)?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Takes the oldest file descriptor received and not taken yet.
+    pub fn take_fd(&mut self) -> Option<OwnedFd> {
+        self.fds.pop_front()
+    }
+}
+
+fn recv_with_fds(
+    socket: &UnixStream,
+    buf: &mut [u8],
+    fds: &mut VecDeque<OwnedFd>,
+) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 32];
+    // SAFETY: `msghdr` is plain data, for which zeroes are valid.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&control) as _;
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+
+    let read = loop {
+        // SAFETY: `msg` points to `iov` and `control`, both alive for the call.
+        let read = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+        if read >= 0 {
+            break read as usize;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    // SAFETY: the control headers were filled by `recvmsg`, and are walked with the libc macros.
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&msg);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                let len = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / size_of::<RawFd>() {
+                    fds.push_back(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&msg, header);
+        }
+    }
+    Ok(read)
+}
@@ -1,11 +1,16 @@
 #![doc = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/README.md"))]
-#![forbid(unsafe_code)]
+#![deny(unsafe_code)]
 
 use bytes::BytesMut;
+use encoder::Encoder;
 use idl_helpers::process_descriptor;
 use idl_helpers::{create_event, current_thread_uuid, DebugAnnotations};
-use prost::Message;
+use sampling::{RateLimiter, SampledOut};
+use stats::StatsRegistry;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tracing::field::Field;
 use tracing::field::Visit;
 use tracing::span;
@@ -14,30 +19,120 @@ use tracing::Id;
 use tracing::Subscriber;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::Context;
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
 use tracing_subscriber::Layer;
 
 
 #[path = "perfetto.protos.rs"]
-#[allow(clippy::all)]
+#[allow(clippy::all, dead_code)]
 #[rustfmt::skip]
 mod idl;
 
+#[cfg(feature = "allocator")]
+#[allow(unsafe_code)]
+mod allocator;
+mod background;
+#[cfg(feature = "callstacks")]
+mod callstack;
+#[cfg(feature = "chrome-json")]
+mod chrome_json;
+pub mod context;
+pub mod convert;
+mod encoder;
 mod idl_helpers;
+#[cfg(all(feature = "producer", unix))]
+#[allow(unsafe_code)]
+mod ipc;
+#[cfg(feature = "log")]
+pub mod log;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod open_spans;
+#[cfg(feature = "opentelemetry")]
+pub mod opentelemetry;
+mod panic;
+#[cfg(target_os = "linux")]
+mod proc_stats;
+#[cfg(all(feature = "producer", unix))]
+pub mod producer;
+#[cfg(all(feature = "profiler", target_os = "linux"))]
+#[allow(unsafe_code)]
+mod profiler;
+mod sampling;
+#[cfg(all(feature = "signals", target_os = "linux"))]
+mod signals;
+#[cfg(all(feature = "producer", unix))]
+#[allow(unsafe_code)]
+mod smb;
+mod stats;
+pub mod stream;
+pub mod thread_pool;
+#[cfg(unix)]
+#[allow(unsafe_code)]
+mod thread_time;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+pub mod track;
+pub mod trigger;
+
+#[cfg(feature = "allocator")]
+pub use allocator::PerfettoAllocator;
+pub use context::TraceContext;
+pub use encoder::OutputFormat;
+pub use open_spans::{ArgValue, OpenSpan, OpenSpanStack};
+pub use panic::install_panic_hook;
+pub use stats::SpanStats;
+pub use track::Track;
+pub use trigger::{trigger, TriggerMode};
 
 struct PerfettoSpanState {
     track_descriptor: Option<idl::TrackDescriptor>, // optional track descriptor for this span, defaults to thread if not found
-    trace: idl::Trace, // The Protobuf trace messages that we accumulate for this span.
+    begin: Vec<idl::TracePacket>, // the `TYPE_SLICE_BEGIN` packet first, then its call stack if captured
+    trace: idl::Trace,            // The Protobuf trace messages that we accumulate for this span.
+    start: u64,                   // timestamp of the `TYPE_SLICE_BEGIN` packet, in nanoseconds
+    children: u64,                // accumulated durations of the closed child spans, in nanoseconds
+}
+
+impl PerfettoSpanState {
+    /// The packets of the span: its begin, the records accumulated while it was open, then `end`.
+    fn finish(mut self, end: idl::TracePacket) -> idl::Trace {
+        let mut packet = self.begin;
+        packet.append(&mut self.trace.packet);
+        packet.push(end);
+        idl::Trace { packet }
+    }
 }
 
 /// A `Layer` that records span as perfetto's
 /// `TYPE_SLICE_BEGIN`/`TYPE_SLICE_END`, and event as `TYPE_INSTANT`.
 ///
-/// `PerfettoLayer` will output the records as encoded [protobuf messages](https://github.com/google/perfetto).
-pub struct PerfettoLayer<W = fn() -> std::io::Stdout> {
+/// `PerfettoLayer` will output the records as encoded [protobuf messages](https://github.com/google/perfetto)
+/// by default, see [`PerfettoLayer::with_output_format`] for alternatives.
+pub struct PerfettoLayer<W: PerfettoWriter = fn() -> std::io::Stdout> {
     sequence_id: SequenceId,
     process_track_uuid: TrackUuid,
-    writer: W,
+    output: Arc<Output<W>>,
+    stats: StatsRegistry,
+    stats_summary_written: AtomicBool,
+    rate_limiter: RateLimiter,
+    #[cfg(feature = "callstacks")]
+    symbolizer: Arc<callstack::Symbolizer>,
+    #[cfg(feature = "callstacks")]
+    callstack_sequence_id: SequenceId,
+    #[cfg(all(feature = "profiler", target_os = "linux"))]
+    profiler: Option<profiler::Profiler>,
+    #[cfg(target_os = "linux")]
+    process_counters: Option<background::Periodic>,
+    #[cfg(feature = "allocator")]
+    heap_track: idl::TrackDescriptor,
+    #[cfg(feature = "tokio")]
+    tokio_metrics: Option<background::Periodic>,
+    panic: panic::PanicHandle,
+    next_slice_id: AtomicU64,
+    open_spans: Arc<open_spans::OpenSpans>,
+    lanes: track::Lanes,
+    #[cfg(all(feature = "signals", target_os = "linux"))]
+    signal_dump: Option<signals::SignalDump>,
     config: Config,
 }
 
@@ -46,36 +141,258 @@ pub struct PerfettoLayer<W = fn() -> std::io::Stdout> {
 /// This is implemented for types implements [`MakeWriter`].
 pub trait PerfettoWriter {
     fn write_log(&self, buf: BytesMut) -> std::io::Result<()>;
+
+    /// Flushes the records written so far, e.g. before the process goes down on a panic.
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 impl<W: for<'writer> MakeWriter<'writer> + 'static> PerfettoWriter for W {
     fn write_log(&self, buf: BytesMut) -> std::io::Result<()> {
         self.make_writer().write_all(&buf)
     }
+
+    fn flush(&self) -> std::io::Result<()> {
+        self.make_writer().flush()
+    }
+}
+
+/// The writer and its encoder, shared with the background threads of the layer.
+struct Output<W> {
+    writer: W,
+    encoder: Box<dyn Encoder>,
+    process_track_uuid: u64,
+    recorder: Option<Mutex<trigger::Recorder>>,
+    /// Whether records are dropped, toggled by `SIGUSR2`.
+    paused: AtomicBool,
+}
+
+impl<W: PerfettoWriter> Output<W> {
+    /// Encodes and writes `log`, prepended with the process descriptor if it wasn't written yet.
+    fn write(&self, mut log: idl::Trace) {
+        if self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(p) = process_descriptor(self.process_track_uuid) {
+            log.packet.insert(0, p);
+        }
+
+        let Some(recorder) = &self.recorder else {
+            return self.encode(log);
+        };
+        let now = chrono::Local::now()
+            .timestamp_nanos_opt()
+            .unwrap_or_default() as u64;
+        // the lock is held while writing, so that snapshots aren't interleaved with other records
+        let mut recorder = recorder.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(log) = recorder.record(log, now) {
+            self.encode(log);
+        }
+    }
+
+    /// Records a `Trigger` packet, after the records it flushes out of the recorder if any.
+    fn trigger(&self, name: &str) {
+        if self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
+        let mut log = idl::Trace {
+            packet: vec![trigger::trigger_packet(name, timestamp)],
+        };
+        if let Some(p) = process_descriptor(self.process_track_uuid) {
+            log.packet.insert(0, p);
+        }
+
+        let Some(recorder) = &self.recorder else {
+            return self.encode(log);
+        };
+        let now = timestamp.unwrap_or_default();
+        let mut recorder = recorder.lock().unwrap_or_else(|e| e.into_inner());
+        let flushed = recorder.fire(now);
+        for log in flushed.into_iter().chain(recorder.record(log, now)) {
+            self.encode(log);
+        }
+    }
+
+    fn encode(&self, log: idl::Trace) {
+        let mut buf = BytesMut::new();
+        let Ok(_) = self.encoder.encode(log, &mut buf) else {
+            return;
+        };
+        _ = self.writer.write_log(buf);
+    }
+
+    /// Writes `log`, prepended with the descriptor of the track its events are on, and the one
+    /// of the current thread's group if any.
+    fn write_on_track(&self, mut log: idl::Trace, track_descriptor: idl::TrackDescriptor) {
+        let packet = idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackDescriptor(track_descriptor)),
+            ..Default::default()
+        };
+        // descriptors go before the events referencing them, the JSON encoder relies on it
+        log.packet.insert(0, packet);
+        if let Some(group) = idl_helpers::current_thread_group() {
+            let group = group.track_descriptor(self.process_track_uuid);
+            let packet = idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackDescriptor(group)),
+                ..Default::default()
+            };
+            log.packet.insert(0, packet);
+        }
+        self.write(log);
+    }
 }
 
 #[derive(Default)]
 struct Config {
     debug_annotations: bool,
+    span_ids: bool,
+    trace_context: Option<TraceContext>,
     filter: Option<fn(&str) -> bool>,
+    span_stats: bool,
+    root_sample_rate: Option<f64>,
+    callsite_rate_limit: Option<u32>,
+    tail_sampling: Option<Duration>,
+    min_span_duration: Option<Duration>,
+    fold_dropped_spans: bool,
+    #[cfg(unix)]
+    thread_cpu_time: bool,
+    #[cfg(feature = "allocator")]
+    allocations: bool,
+    #[cfg(feature = "allocator")]
+    heap_counter: bool,
+    #[cfg(feature = "tokio")]
+    tokio_metrics: Option<(::tokio::runtime::Handle, Duration)>,
+    #[cfg(all(feature = "profiler", target_os = "linux"))]
+    cpu_profiler_frequency: Option<u32>,
+    #[cfg(target_os = "linux")]
+    process_counters_interval: Option<Duration>,
+    #[cfg(feature = "callstacks")]
+    callstacks: bool,
+    #[cfg(feature = "callstacks")]
+    callstack_level: Option<tracing::Level>,
+    #[cfg(all(feature = "signals", target_os = "linux"))]
+    signal_dump: bool,
 }
 
 impl<W: PerfettoWriter> PerfettoLayer<W> {
     pub fn new(writer: W) -> Self {
+        let process_track_uuid: u64 = rand::random();
         Self {
             sequence_id: SequenceId::new(rand::random()),
-            process_track_uuid: TrackUuid::new(rand::random()),
-            writer,
+            output: Arc::new(Output {
+                writer,
+                encoder: OutputFormat::default().encoder(),
+                process_track_uuid,
+                recorder: None,
+                paused: AtomicBool::new(false),
+            }),
+            process_track_uuid: TrackUuid::new(process_track_uuid),
+            stats: StatsRegistry::default(),
+            stats_summary_written: AtomicBool::new(false),
+            rate_limiter: RateLimiter::default(),
+            #[cfg(feature = "callstacks")]
+            symbolizer: Arc::default(),
+            #[cfg(feature = "callstacks")]
+            callstack_sequence_id: SequenceId::new(rand::random()),
+            #[cfg(all(feature = "profiler", target_os = "linux"))]
+            profiler: None,
+            #[cfg(target_os = "linux")]
+            process_counters: None,
+            #[cfg(feature = "allocator")]
+            heap_track: idl::TrackDescriptor::counter_child_for(
+                "heap size",
+                process_track_uuid,
+                idl::counter_descriptor::Unit::SizeBytes,
+            ),
+            #[cfg(feature = "tokio")]
+            tokio_metrics: None,
+            panic: panic::PanicHandle::default(),
+            next_slice_id: AtomicU64::new(1),
+            open_spans: Arc::default(),
+            lanes: track::Lanes::default(),
+            #[cfg(all(feature = "signals", target_os = "linux"))]
+            signal_dump: None,
             config: Config::default(),
         }
     }
 
+    /// Configures the format of the records handed to the writer, [`OutputFormat::Protobuf`] by
+    /// default.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "chrome-json")] {
+    /// use tracing_perfetto::{OutputFormat, PerfettoLayer};
+    ///
+    /// let file = std::fs::File::create(std::env::temp_dir().join("test.json")).unwrap();
+    /// let layer = PerfettoLayer::new(std::sync::Mutex::new(file))
+    ///                 .with_output_format(OutputFormat::ChromeJson);
+    /// # }
+    /// ```
+    pub fn with_output_format(mut self, format: OutputFormat) -> Self {
+        Arc::get_mut(&mut self.output)
+            .expect("the output is only shared once the layer is registered")
+            .encoder = format.encoder();
+        self
+    }
+
+    /// Configures what [triggers](trigger()) do to the trace: start it, stop it, or write
+    /// snapshots of a flight recorder, with the records of a window before and after each of
+    /// them. Triggers are only recorded as `Trigger` packets by default.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use tracing_perfetto::{PerfettoLayer, TriggerMode};
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// let file = std::fs::File::create(std::env::temp_dir().join("test.pftrace")).unwrap();
+    /// let layer = PerfettoLayer::new(std::sync::Mutex::new(file)).with_triggers(
+    ///     TriggerMode::CloneSnapshot {
+    ///         pre: Duration::from_secs(10),
+    ///         post: Duration::from_secs(1),
+    ///     },
+    /// );
+    /// let _guard = tracing_subscriber::registry().with(layer).set_default();
+    ///
+    /// // only the last 10s of spans before a slow request, and 1s after it, are written
+    /// tracing_perfetto::trigger("slow_request");
+    /// ```
+    pub fn with_triggers(mut self, mode: TriggerMode) -> Self {
+        Arc::get_mut(&mut self.output)
+            .expect("the output is only shared once the layer is registered")
+            .recorder = Some(Mutex::new(trigger::Recorder::new(mode)));
+        self
+    }
+
     /// Configures whether or not spans/events should be recorded with their metadata and fields.
     pub fn with_debug_annotations(mut self, value: bool) -> Self {
         self.config.debug_annotations = value;
         self
     }
 
+    /// Configures whether or not spans carry their `tracing` ids, to cross-reference slices with
+    /// other layers such as `fmt` logs.
+    ///
+    /// The `TYPE_SLICE_BEGIN` of a span gets `tracing_span_id` and `tracing_parent_id` arguments,
+    /// along with a `slice_id` which, unlike `tracing` ids, is never reused within the trace.
+    /// Events get the `tracing_span_id` and `slice_id` of the span they are in. The slice id is
+    /// also stored as a [`SliceId`] in the extensions of the span, for a formatter to print it.
+    pub fn with_span_ids(mut self, value: bool) -> Self {
+        self.config.span_ids = value;
+        self
+    }
+
+    /// Tags the trace with the [`TraceContext`] of the process, to merge it with the traces of
+    /// the other processes of the context with [`convert::merge`].
+    ///
+    /// The trace gets the UUID of the context. In a child process, it starts with a
+    /// `trace context` instant ending the flow from the parent's `spawn child` instant.
+    pub fn with_trace_context(mut self, context: TraceContext) -> Self {
+        self.config.trace_context = Some(context);
+        self
+    }
+
     /// Configures whether or not spans/events be recorded based on the occurrence of a field name.
     ///
     /// Sometimes, not all the events/spans should be treated as perfetto trace, you can append a
@@ -105,174 +422,915 @@ impl<W: PerfettoWriter> PerfettoLayer<W> {
         self
     }
 
-    fn write_log(&self, mut log: idl::Trace, track_descriptor: idl::TrackDescriptor) {
-        let mut buf = BytesMut::new();
+    /// Configures whether or not the layer aggregates the durations of closed spans per callsite.
+    ///
+    /// The aggregates (count, total and self time, min/max and percentiles) are available through
+    /// [`PerfettoLayer::stats_snapshot`], and are written as instants on a `span statistics`
+    /// track by [`PerfettoLayer::write_stats_summary`]. This gives a cheap profile even when the
+    /// trace itself is too big to keep.
+    pub fn with_span_stats(mut self, value: bool) -> Self {
+        self.config.span_stats = value;
+        self
+    }
 
-        if let Some(p) = process_descriptor(self.process_track_uuid.get()) {
-            log.packet.insert(0, p);
-        }
+    /// Returns the durations aggregated so far, sorted by decreasing total time.
+    ///
+    /// Once the layer is installed, it can be reached through the dispatcher:
+    ///
+    /// ```rust
+    /// use tracing_perfetto::PerfettoLayer;
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// type Writer = fn() -> std::io::Sink;
+    /// let layer = PerfettoLayer::new(std::io::sink as Writer).with_span_stats(true);
+    /// let _guard = tracing_subscriber::registry().with(layer).set_default();
+    ///
+    /// tracing::info_span!("work").in_scope(|| {});
+    ///
+    /// tracing::dispatcher::get_default(|dispatch| {
+    ///     let layer = dispatch.downcast_ref::<PerfettoLayer<Writer>>().unwrap();
+    ///     for stats in layer.stats_snapshot() {
+    ///         println!("{}: {} spans, p99 {:?}", stats.name(), stats.count(), stats.percentile(0.99));
+    ///     }
+    /// });
+    /// ```
+    pub fn stats_snapshot(&self) -> Vec<SpanStats> {
+        self.stats.snapshot()
+    }
 
-        let packet = idl::TracePacket {
-            data: Some(idl::trace_packet::Data::TrackDescriptor(track_descriptor)),
-            ..Default::default()
-        };
-        // let mut packet = idl::TracePacket::default();
-        // packet.data = Some(idl::trace_packet::Data::TrackDescriptor(track_descriptor));
-        log.packet.insert(1, packet);
+    /// Writes the durations aggregated so far as instants on a `span statistics` track, then
+    /// flushes the writer.
+    ///
+    /// A global subscriber, installed with `init` or `set_global_default`, is never dropped, so
+    /// call this before exiting. The layer otherwise writes the summary when dropped, unless it
+    /// was already written.
+    ///
+    /// ```rust
+    /// use tracing_perfetto::PerfettoLayer;
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// type Writer = fn() -> std::io::Sink;
+    /// let layer = PerfettoLayer::new(std::io::sink as Writer).with_span_stats(true);
+    /// tracing_subscriber::registry().with(layer).init();
+    ///
+    /// tracing::info_span!("work").in_scope(|| {});
+    ///
+    /// tracing::dispatcher::get_default(|dispatch| {
+    ///     let layer = dispatch.downcast_ref::<PerfettoLayer<Writer>>().unwrap();
+    ///     layer.write_stats_summary();
+    /// });
+    /// ```
+    pub fn write_stats_summary(&self) {
+        self.stats_summary_written.store(true, Ordering::Relaxed);
+        self.write_stats();
+        _ = self.output.writer.flush();
+    }
 
-        // if let Some(t) = track_descriptor {
-        //     let mut packet = idl::TracePacket::default();
-        //     packet.data = Some(idl::trace_packet::Data::TrackDescriptor(t));
-        //     log.packet.insert(1, packet);
-        // } else if let Some(t) = self.thread_descriptor() {
-        //     log.packet.insert(1, t);
-        // }
+    /// Returns the spans open right now, grouped by thread or by named track, outermost first,
+    /// e.g. to find where every thread is stuck from a health check or a deadlock watchdog.
+    ///
+    /// ```rust
+    /// use tracing_perfetto::PerfettoLayer;
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// type Writer = fn() -> std::io::Sink;
+    /// let layer = PerfettoLayer::new(std::io::sink as Writer);
+    /// let _guard = tracing_subscriber::registry().with(layer).set_default();
+    ///
+    /// let _span = tracing::info_span!("handle_request", id = 42).entered();
+    /// tracing::dispatcher::get_default(|dispatch| {
+    ///     let layer = dispatch.downcast_ref::<PerfettoLayer<Writer>>().unwrap();
+    ///     for stack in layer.open_spans_snapshot() {
+    ///         let spans: Vec<_> = stack.spans().iter().map(|span| span.name()).collect();
+    ///         println!("{:?}: {}", stack.track_name(), spans.join(" > "));
+    ///     }
+    /// });
+    /// ```
+    pub fn open_spans_snapshot(&self) -> Vec<OpenSpanStack> {
+        let now = chrono::Local::now()
+            .timestamp_nanos_opt()
+            .unwrap_or_default() as u64;
+        self.open_spans.snapshot(now)
+    }
 
-        let Ok(_) = log.encode(&mut buf) else {
-            return;
-        };
-        _ = self.writer.write_log(buf);
+    /// Writes a snapshot of the spans open right now into the trace, as an `open spans` instant
+    /// on each track with open spans, listing them with how long they have been open.
+    pub fn write_open_spans_snapshot(&self) {
+        let now = chrono::Local::now()
+            .timestamp_nanos_opt()
+            .unwrap_or_default() as u64;
+        let stacks = self.open_spans.snapshot(now);
+        self.output.write(open_spans::snapshot_trace(
+            stacks,
+            now,
+            self.sequence_id.get() as _,
+        ));
     }
-}
 
-struct SequenceId(u64);
+    /// Configures the probability for a root span to be recorded, along with its whole tree of
+    /// child spans and events.
+    ///
+    /// A root span is a span without any recorded ancestor. Spans and events of a dropped tree
+    /// are dropped as well, so sampled traces never contain partial trees.
+    pub fn with_root_span_sampling(mut self, probability: f64) -> Self {
+        self.config.root_sample_rate = Some(probability);
+        self
+    }
 
-impl SequenceId {
-    fn new(n: u64) -> Self {
-        Self(n)
+    /// Configures the maximum number of spans and events recorded per second for each callsite.
+    ///
+    /// When a span goes over the limit, its child spans and events are dropped with it.
+    pub fn with_callsite_rate_limit(mut self, max_per_second: u32) -> Self {
+        self.config.callsite_rate_limit = Some(max_per_second);
+        self
     }
 
-    fn get(&self) -> u64 {
-        self.0
+    /// Configures tail sampling: a span is only written if it lasted at least `threshold`,
+    /// which is checked as each span closes.
+    ///
+    /// The events of a faster span are handed over to its parent span, and written if the parent
+    /// is. Those of a fast root span are dropped with it.
+    pub fn with_tail_sampling(mut self, threshold: Duration) -> Self {
+        self.config.tail_sampling = Some(threshold);
+        self
     }
-}
 
-struct TrackUuid(u64);
+    /// Configures a minimum duration for spans: shorter spans are discarded when they close,
+    /// along with the events recorded inside them.
+    ///
+    /// Long outliers then stand out in long traces without blowing up their size. See
+    /// [`PerfettoLayer::with_fold_dropped_spans`] to keep the events of the discarded spans.
+    pub fn with_min_span_duration(mut self, threshold: Duration) -> Self {
+        self.config.min_span_duration = Some(threshold);
+        self
+    }
 
-impl TrackUuid {
-    fn new(n: u64) -> Self {
-        Self(n)
+    /// Configures whether or not the events of spans discarded by
+    /// [`PerfettoLayer::with_min_span_duration`] are folded into their parent span instead of
+    /// being discarded with them.
+    ///
+    /// Folded events keep their timestamp and track, so they show up nested in the parent span.
+    pub fn with_fold_dropped_spans(mut self, value: bool) -> Self {
+        self.config.fold_dropped_spans = value;
+        self
     }
 
-    fn get(&self) -> u64 {
-        self.0
+    /// Configures whether or not spans and events marked with a `perfetto.callstack = true` field
+    /// capture the call stack they were recorded from.
+    ///
+    /// Stacks are written as `PerfSample` packets, next to the `TYPE_SLICE_BEGIN` of spans and
+    /// the `TYPE_INSTANT` of events, and show up as callstack samples on the thread in the
+    /// Perfetto UI. Requires the `callstacks` feature.
+    ///
+    /// ```rust
+    /// use tracing_perfetto::PerfettoLayer;
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// let layer = PerfettoLayer::new(std::io::sink).with_callstacks(true);
+    /// let _guard = tracing_subscriber::registry().with(layer).set_default();
+    ///
+    /// tracing::warn!(perfetto.callstack = true, "unexpected retry");
+    /// ```
+    #[cfg(feature = "callstacks")]
+    pub fn with_callstacks(mut self, value: bool) -> Self {
+        self.config.callstacks = value;
+        self
     }
-}
 
-struct TrackNameVisitor<'a> {
-    user_track_name: &'a mut Option<String>,
-}
+    /// Configures a level at or above which every span and event captures its call stack, e.g.
+    /// `Level::WARN` for warnings and errors, marked or not. Requires the `callstacks` feature.
+    #[cfg(feature = "callstacks")]
+    pub fn with_callstack_level(mut self, level: tracing::Level) -> Self {
+        self.config.callstack_level = Some(level);
+        self
+    }
 
-impl Visit for TrackNameVisitor<'_> {
-    // fn record_u64(&mut self, field: &Field, value: u64) {
-    //     if field.name() == "perfetto_track_id" {
-    //         *self.user_track_id = Some(value);
-    //     }
-    // }
+    /// Captures the current call stack as a `PerfSample` packet, if the span or event with
+    /// `metadata` and `fields` asks for one.
+    #[cfg(feature = "callstacks")]
+    fn callstack_packet(
+        &self,
+        metadata: &tracing::Metadata<'_>,
+        fields: impl tracing_subscriber::field::RecordFields,
+        timestamp: Option<u64>,
+    ) -> Option<idl::TracePacket> {
+        let by_level = self
+            .config
+            .callstack_level
+            .is_some_and(|level| *metadata.level() <= level);
+        let marked = self.config.callstacks && {
+            let mut visitor = callstack::CallstackVisitor::default();
+            fields.record(&mut visitor);
+            visitor.marked
+        };
+        if !by_level && !marked {
+            return None;
+        }
 
-    fn record_str(&mut self, field: &Field, value: &str) {
-        if field.name() == "perfetto.track_name" {
-            *self.user_track_name = Some(value.to_string());
+        let callstack = self.symbolizer.capture();
+        if callstack.is_empty() {
+            return None;
         }
+        Some(callstack.sample_packet(
+            thread_id::get() as _,
+            timestamp,
+            self.callstack_sequence_id.get() as _,
+        ))
     }
-    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {
-        // If you want to parse `perfetto_track_id` from a non-u64 typed field,
-        // you could do that here, e.g. if user sets `perfetto_track_id = "0xABCD"`.
-        // For now, we'll ignore it.
+
+    /// Configures an in-process CPU profiler sampling the stacks of all threads `frequency` times
+    /// per second of CPU time. Requires the `profiler` feature, on Linux.
+    ///
+    /// Samples are written as `PerfSample` packets on the sampled threads, next to their spans.
+    /// The profiler uses `SIGPROF` and starts when the layer is registered; only one can run at
+    /// a time in a process, and the layers failing to start one write a `warning` instant and
+    /// a `tracing` warning instead. Stacks are walked through frame pointers, so code built
+    /// without them (`-C force-frame-pointers=yes`) shows up with truncated stacks.
+    #[cfg(all(feature = "profiler", target_os = "linux"))]
+    pub fn with_cpu_profiler(mut self, frequency: u32) -> Self {
+        self.config.cpu_profiler_frequency = Some(frequency);
+        self
     }
-    // Optionally implement record_* for other numeric types if needed
-}
-struct PerfettoVisitor {
-    perfetto: bool,
-    filter: fn(&str) -> bool,
-}
 
-impl PerfettoVisitor {
-    fn new(filter: fn(&str) -> bool) -> PerfettoVisitor {
-        Self {
-            filter,
-            perfetto: false,
-        }
+    /// Configures a thread sampling the counters of the process every `interval`: resident
+    /// memory and its peak, user/system CPU time, context switches, open file descriptors and
+    /// threads, along with the CPU usage of the whole system. Linux only.
+    ///
+    /// They are written as counter tracks under the process track, so memory spikes line up with
+    /// the spans causing them. The thread starts when the layer is registered.
+    #[cfg(target_os = "linux")]
+    pub fn with_process_counters(mut self, interval: Duration) -> Self {
+        self.config.process_counters_interval = Some(interval);
+        self
     }
-}
 
-impl Visit for PerfettoVisitor {
-    fn record_debug(&mut self, field: &Field, _value: &dyn std::fmt::Debug) {
-        if (self.filter)(field.name()) {
-            self.perfetto = true;
-        }
+    /// Configures whether or not the process dumps the trace on `SIGUSR1` and pauses or resumes
+    /// the recording on `SIGUSR2`, to grab a trace from a stuck process without restarting it.
+    /// Requires the `signals` feature, on Linux.
+    ///
+    /// A dump fires a `SIGUSR1` [trigger](trigger()), which writes the buffered records with
+    /// [`PerfettoLayer::with_triggers`], then writes an `open spans` instant on each track with
+    /// open spans, listing them with how long they have been open, and flushes the writer. The
+    /// signals are handled by a thread started when the layer is registered.
+    ///
+    /// ```text
+    /// kill -USR1 <pid>
+    /// ```
+    #[cfg(all(feature = "signals", target_os = "linux"))]
+    pub fn with_signal_dump(mut self, value: bool) -> Self {
+        self.config.signal_dump = value;
+        self
     }
-}
 
-impl<W, S: Subscriber> Layer<S> for PerfettoLayer<W>
-where
-    S: for<'a> LookupSpan<'a>,
-    W: for<'writer> MakeWriter<'writer> + 'static,
-{
-    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
-        let Some(span) = ctx.span(id) else {
-            return;
-        };
+    /// Configures whether or not spans record the CPU time of their thread, telling computing
+    /// spans from blocked ones. Unix only.
+    ///
+    /// The CPU time spent while the span was entered is attached to its `TYPE_SLICE_END` as a
+    /// `thread_cpu_time_ns` argument. Slice boundaries also sample a per-thread `thread cpu time`
+    /// counter through `extra_counter_track_uuids`.
+    #[cfg(unix)]
+    pub fn with_thread_cpu_time(mut self, value: bool) -> Self {
+        self.config.thread_cpu_time = value;
+        self
+    }
 
-        let enabled = self
-            .config
-            .filter
-            .map(|f| {
-                let mut visitor = PerfettoVisitor::new(f);
-                attrs.record(&mut visitor);
-                visitor.perfetto
-            })
-            .unwrap_or(true);
+    /// Configures whether or not spans record the allocations made while they were entered, as
+    /// `allocated_bytes`, `allocations`, `freed_bytes` and `frees` arguments of their
+    /// `TYPE_SLICE_END`. Requires the `allocator` feature, and [`PerfettoAllocator`] to be the
+    /// global allocator.
+    #[cfg(feature = "allocator")]
+    pub fn with_allocations(mut self, value: bool) -> Self {
+        self.config.allocations = value;
+        self
+    }
 
-        if !enabled {
-            return;
-        }
+    /// Configures whether or not slice boundaries sample the bytes allocated by the process on
+    /// a `heap size` counter track. Requires the `allocator` feature, and [`PerfettoAllocator`]
+    /// to be the global allocator.
+    #[cfg(feature = "allocator")]
+    pub fn with_heap_counter(mut self, value: bool) -> Self {
+        self.config.heap_counter = value;
+        self
+    }
 
-        let mut debug_annotations = DebugAnnotations::default();
-        if self.config.debug_annotations {
-            attrs.record(&mut debug_annotations);
-        }
+    /// Configures a thread sampling the metrics of the runtime of `handle` every `interval`:
+    /// alive tasks, depth of the global queue and busy percentage of each worker. Requires the
+    /// `tokio` feature.
+    ///
+    /// They are written as counter tracks under the process track. The thread starts when the
+    /// layer is registered.
+    #[cfg(feature = "tokio")]
+    pub fn with_tokio_metrics(
+        mut self,
+        handle: ::tokio::runtime::Handle,
+        interval: Duration,
+    ) -> Self {
+        self.config.tokio_metrics = Some((handle, interval));
+        self
+    }
 
-        let mut packet = idl::TracePacket::default();
+    /// Returns a `log::Log` implementation writing `log` records into this layer's trace, as
+    /// Perfetto log messages on the track of the logging thread. Requires the `log` feature.
+    ///
+    /// See the [`log`](crate::log) module.
+    #[cfg(feature = "log")]
+    pub fn log_bridge(&self) -> log::PerfettoLogger
+    where
+        W: Send + Sync + 'static,
+    {
+        let output = self.output.clone();
+        log::PerfettoLogger::new(move |trace| {
+            output.write_on_track(trace, idl_helpers::current_thread_track_descriptor())
+        })
+    }
 
-        // check if parent span has a non default track descriptor
-        let inherited_track_descriptor = span
-            .parent()
-            // If the span has a parent, try retrieving the track descriptor from the parent's state
-            .and_then(|parent_span| {
-                parent_span
-                    .extensions()
-                    .get::<PerfettoSpanState>()
-                    .map(|state| state.track_descriptor.clone())
-            })
-            .flatten();
+    /// Returns a `metrics::Recorder` writing the counters, gauges and histograms of the
+    /// `metrics` facade into this layer's trace, as counter tracks under the process track.
+    /// Requires the `metrics` feature.
+    ///
+    /// See the [`metrics`](crate::metrics) module.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_recorder(&self) -> metrics::PerfettoRecorder
+    where
+        W: Send + Sync + 'static,
+    {
+        let output = self.output.clone();
+        metrics::PerfettoRecorder::new(self.process_track_uuid.get(), move |trace| {
+            output.write(trace)
+        })
+    }
 
-        // retrieve the user set track name (via `perfetto.track_name` field)
-        let mut user_track_name = None;
-        let mut visitor = TrackNameVisitor {
-            user_track_name: &mut user_track_name,
-        };
-        attrs.record(&mut visitor);
+    /// Returns an OpenTelemetry span processor writing finished OpenTelemetry spans into this
+    /// layer's trace, as slices carrying their trace and span ids, with flows to their remote
+    /// children. Requires the `opentelemetry` feature.
+    ///
+    /// See the [`opentelemetry`](crate::opentelemetry) module.
+    #[cfg(feature = "opentelemetry")]
+    pub fn opentelemetry_processor(&self) -> opentelemetry::PerfettoSpanProcessor
+    where
+        W: Send + Sync + 'static,
+    {
+        let output = self.output.clone();
+        opentelemetry::PerfettoSpanProcessor::new(self.process_track_uuid.get(), move |trace| {
+            output.write(trace)
+        })
+    }
 
-        // resolve the optional track descriptor for this span (either inherited from parent or user set, or None)
-        let span_track_descriptor = user_track_name
-            .map(|name| idl::TrackDescriptor::named_child_for(&name, self.process_track_uuid.get()))
-            .or(inherited_track_descriptor);
+    /// Returns whether a new span, which passed the filter, is sampled in.
+    fn sample_span<S>(&self, span: &SpanRef<'_, S>) -> bool
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        if let Some(probability) = self.config.root_sample_rate {
+            let is_root = span
+                .scope()
+                .skip(1)
+                .all(|parent| parent.extensions().get::<PerfettoSpanState>().is_none());
+            if is_root && rand::random::<f64>() >= probability {
+                return false;
+            }
+        }
+        self.config
+            .callsite_rate_limit
+            .map(|limit| self.rate_limiter.allow(span.metadata().callsite(), limit))
+            .unwrap_or(true)
+    }
 
-        let final_uuid = span_track_descriptor
-            .as_ref()
-            .map(|desc| desc.uuid())
-            .unwrap_or_else(current_thread_uuid);
+    fn write_stats(&self) {
+        let stats = self.stats.snapshot();
+        if stats.is_empty() {
+            return;
+        }
 
-        let event = create_event(
-            final_uuid, // span track id if exists, otherwise thread track id
+        let track_descriptor =
+            idl::TrackDescriptor::named_child_for("span statistics", self.process_track_uuid.get());
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as _);
+        let packet = stats
+            .iter()
+            .map(|stats| {
+                let event = create_event(
+                    track_descriptor.uuid(),
+                    Some(stats.name()),
+                    stats.metadata().file().zip(stats.metadata().line()),
+                    stats.debug_annotations(),
+                    Some(idl::track_event::Type::Instant),
+                );
+                idl::TracePacket {
+                    data: Some(idl::trace_packet::Data::TrackEvent(event)),
+                    timestamp,
+                    trusted_pid: Some(std::process::id() as _),
+                    optional_trusted_packet_sequence_id: Some(
+                        idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                            self.sequence_id.get() as _,
+                        ),
+                    ),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        self.write_log(idl::Trace { packet }, track_descriptor);
+    }
+
+    /// Writes the trace UUID of `context`, and the end of the flow from the parent if any.
+    fn write_trace_context(&self, context: &TraceContext) {
+        let mut packet = vec![context.trace_uuid_packet()];
+        if let Some(flow_id) = context.flow_id() {
+            let annotations = context
+                .parent_timestamp()
+                .map(|t| idl_helpers::uint_annotation(context::PARENT_TIMESTAMP, t))
+                .into_iter()
+                .collect();
+            let mut event = create_event(
+                current_thread_uuid(),
+                Some(context::START_EVENT),
+                None,
+                DebugAnnotations { annotations },
+                Some(idl::track_event::Type::Instant),
+            );
+            event.terminating_flow_ids.push(flow_id);
+            packet.push(idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackEvent(event)),
+                timestamp: chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64),
+                trusted_pid: Some(std::process::id() as _),
+                optional_trusted_packet_sequence_id: Some(
+                    idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                        self.sequence_id.get() as _,
+                    ),
+                ),
+                ..Default::default()
+            });
+        }
+        self.write_log(
+            idl::Trace { packet },
+            idl_helpers::current_thread_track_descriptor(),
+        );
+    }
+
+    fn write_log(&self, log: idl::Trace, track_descriptor: idl::TrackDescriptor) {
+        self.output.write_on_track(log, track_descriptor);
+    }
+
+    /// Reports that the background part `what` of the layer failed to start, with a `tracing`
+    /// warning and a `warning` instant on the current thread's track.
+    #[cfg(any(target_os = "linux", feature = "tokio"))]
+    fn report_start_error(&self, what: &str, error: std::io::Error) {
+        let message = format!("failed to start the {what}: {error}");
+        tracing::warn!("{message}");
+
+        let debug_annotations = DebugAnnotations {
+            annotations: vec![idl_helpers::string_annotation("message", message)],
+        };
+        let event = create_event(
+            current_thread_uuid(),
+            Some("warning"),
+            None,
+            debug_annotations,
+            Some(idl::track_event::Type::Instant),
+        );
+        let packet = idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            timestamp: chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64),
+            trusted_pid: Some(std::process::id() as _),
+            optional_trusted_packet_sequence_id: Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    self.sequence_id.get() as _,
+                ),
+            ),
+            ..Default::default()
+        };
+        self.write_log(
+            idl::Trace {
+                packet: vec![packet],
+            },
+            idl_helpers::current_thread_track_descriptor(),
+        );
+    }
+
+    /// Records the panic described by `info` on the current thread's track, ends the open spans
+    /// of `subscriber` entered on the current thread on their track, and flushes the writer.
+    fn finalize_on_panic<S>(&self, subscriber: &S, info: &std::panic::PanicHookInfo<'_>)
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
+        let sequence_id = Some(
+            idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                self.sequence_id.get() as _,
+            ),
+        );
+
+        let debug_annotations = DebugAnnotations {
+            annotations: vec![
+                idl_helpers::string_annotation("message", panic::panic_message(info)),
+                idl_helpers::string_annotation(
+                    "backtrace",
+                    std::backtrace::Backtrace::force_capture().to_string(),
+                ),
+            ],
+        };
+        let event = create_event(
+            current_thread_uuid(),
+            Some("panic"),
+            info.location()
+                .map(|location| (location.file(), location.line())),
+            debug_annotations,
+            Some(idl::track_event::Type::Instant),
+        );
+        let packet = idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            timestamp,
+            trusted_pid: Some(std::process::id() as _),
+            optional_trusted_packet_sequence_id: sequence_id,
+            ..Default::default()
+        };
+        self.write_log(
+            idl::Trace {
+                packet: vec![packet],
+            },
+            idl_helpers::current_thread_track_descriptor(),
+        );
+
+        // innermost spans first, so that each end closes its own slice on shared tracks
+        let open_spans = self
+            .open_spans
+            .take_entered()
+            .into_iter()
+            .filter_map(|(id, track)| subscriber.span(&id).map(|span| (span, track)));
+        for (span, track_descriptor) in open_spans {
+            let Some(span_state) = span.extensions_mut().remove::<PerfettoSpanState>() else {
+                continue;
+            };
+            let meta = span.metadata();
+            let mut event = create_event(
+                track_descriptor.uuid(),
+                Some(meta.name()),
+                meta.file().zip(meta.line()),
+                DebugAnnotations::default(),
+                Some(idl::track_event::Type::SliceEnd),
+            );
+            event.categories = vec![meta.target().to_string()];
+            let end = idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackEvent(event)),
+                timestamp,
+                trusted_pid: Some(std::process::id() as _),
+                optional_trusted_packet_sequence_id: sequence_id,
+                ..Default::default()
+            };
+            self.write_log(span_state.finish(end), track_descriptor);
+        }
+
+        _ = self.output.writer.flush();
+    }
+}
+
+/// Finalizes the trace of the `PerfettoLayer<W>` registered in `dispatch` over `S`, see
+/// [`install_panic_hook`].
+fn finalize_on_panic<S, W>(dispatch: &tracing::Dispatch, info: &std::panic::PanicHookInfo<'_>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: PerfettoWriter + 'static,
+{
+    let layer = dispatch.downcast_ref::<PerfettoLayer<W>>();
+    let subscriber = dispatch.downcast_ref::<S>();
+    if let Some((layer, subscriber)) = layer.zip(subscriber) {
+        layer.finalize_on_panic(subscriber, info);
+    }
+}
+
+impl<W: PerfettoWriter> Drop for PerfettoLayer<W> {
+    fn drop(&mut self) {
+        // best effort, for the layers of scoped subscribers
+        if self.config.span_stats && !self.stats_summary_written.load(Ordering::Relaxed) {
+            self.write_stats();
+        }
+    }
+}
+
+/// The id of the slice of a span, unique within the trace unlike `tracing` ids which get reused
+/// once their span closes. Stored in the extensions of recorded spans by
+/// [`PerfettoLayer::with_span_ids`]:
+///
+/// ```rust
+/// use tracing_perfetto::{PerfettoLayer, SliceId};
+/// use tracing_subscriber::prelude::*;
+/// use tracing_subscriber::registry::LookupSpan;
+///
+/// let layer = PerfettoLayer::new(std::io::sink).with_span_ids(true);
+/// let _guard = tracing_subscriber::registry().with(layer).set_default();
+///
+/// let span = tracing::info_span!("request").entered();
+/// tracing::dispatcher::get_default(|dispatch| {
+///     let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>().unwrap();
+///     let span = registry.span(&span.id().unwrap()).unwrap();
+///     println!("slice {}", span.extensions().get::<SliceId>().unwrap().get());
+/// });
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SliceId(u64);
+
+impl SliceId {
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+struct SequenceId(u64);
+
+impl SequenceId {
+    fn new(n: u64) -> Self {
+        Self(n)
+    }
+
+    fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+struct TrackUuid(u64);
+
+impl TrackUuid {
+    fn new(n: u64) -> Self {
+        Self(n)
+    }
+
+    fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+struct TrackNameVisitor<'a> {
+    user_track_name: &'a mut Option<String>,
+    user_track: &'a mut Option<u64>,
+}
+
+impl Visit for TrackNameVisitor<'_> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "perfetto.track" {
+            *self.user_track = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "perfetto.track_name" {
+            *self.user_track_name = Some(value.to_string());
+        }
+    }
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {
+        // If you want to parse `perfetto_track_id` from a non-u64 typed field,
+        // you could do that here, e.g. if user sets `perfetto_track_id = "0xABCD"`.
+        // For now, we'll ignore it.
+    }
+    // Optionally implement record_* for other numeric types if needed
+}
+struct PerfettoVisitor {
+    perfetto: bool,
+    filter: fn(&str) -> bool,
+}
+
+impl PerfettoVisitor {
+    fn new(filter: fn(&str) -> bool) -> PerfettoVisitor {
+        Self {
+            filter,
+            perfetto: false,
+        }
+    }
+}
+
+impl Visit for PerfettoVisitor {
+    fn record_debug(&mut self, field: &Field, _value: &dyn std::fmt::Debug) {
+        if (self.filter)(field.name()) {
+            self.perfetto = true;
+        }
+    }
+}
+
+impl<W, S: Subscriber> Layer<S> for PerfettoLayer<W>
+where
+    S: for<'a> LookupSpan<'a>,
+    W: PerfettoWriter + Send + Sync + 'static,
+{
+    fn on_register_dispatch(&self, subscriber: &tracing::Dispatch) {
+        self.open_spans.register::<S>(subscriber);
+    }
+
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        self.panic.finalize = Some(finalize_on_panic::<S, W>);
+
+        if let Some(context) = &self.config.trace_context {
+            self.write_trace_context(context);
+        }
+
+        #[cfg(all(feature = "signals", target_os = "linux"))]
+        if self.config.signal_dump {
+            let output = self.output.clone();
+            let open_spans = self.open_spans.clone();
+            let sequence_id = self.sequence_id.get() as u32;
+            let toggled = self.output.clone();
+            self.signal_dump = signals::SignalDump::start(
+                move || {
+                    output.trigger("SIGUSR1");
+                    let now = chrono::Local::now()
+                        .timestamp_nanos_opt()
+                        .unwrap_or_default() as u64;
+                    let stacks = open_spans.snapshot(now);
+                    output.write(open_spans::snapshot_trace(stacks, now, sequence_id));
+                    _ = output.writer.flush();
+                },
+                move || _ = toggled.paused.fetch_xor(true, Ordering::Relaxed),
+            )
+            .map_err(|e| self.report_start_error("signal dump", e))
+            .ok();
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(interval) = self.config.process_counters_interval {
+            let output = self.output.clone();
+            self.process_counters = proc_stats::start(
+                interval,
+                self.process_track_uuid.get(),
+                rand::random(),
+                move |trace| output.write(trace),
+            )
+            .map_err(|e| self.report_start_error("process counters", e))
+            .ok();
+        }
+
+        #[cfg(feature = "tokio")]
+        if let Some((handle, interval)) = self.config.tokio_metrics.clone() {
+            let output = self.output.clone();
+            self.tokio_metrics = tokio::start_metrics(
+                handle,
+                interval,
+                self.process_track_uuid.get(),
+                rand::random(),
+                move |trace| output.write(trace),
+            )
+            .map_err(|e| self.report_start_error("tokio metrics", e))
+            .ok();
+        }
+
+        #[cfg(all(feature = "profiler", target_os = "linux"))]
+        if let Some(frequency) = self.config.cpu_profiler_frequency {
+            let output = self.output.clone();
+            self.profiler = profiler::Profiler::start(
+                frequency,
+                self.symbolizer.clone(),
+                self.callstack_sequence_id.get() as _,
+                move |trace| output.write(trace),
+            )
+            .map_err(|e| self.report_start_error("CPU profiler", e))
+            .ok();
+        }
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        if let Some(parent) = span.parent() {
+            if parent.extensions().get::<SampledOut>().is_some() {
+                span.extensions_mut().insert(SampledOut);
+                return;
+            }
+        }
+
+        let enabled = self
+            .config
+            .filter
+            .map(|f| {
+                let mut visitor = PerfettoVisitor::new(f);
+                attrs.record(&mut visitor);
+                visitor.perfetto
+            })
+            .unwrap_or(true);
+
+        if !enabled {
+            return;
+        }
+
+        if !self.sample_span(&span) {
+            span.extensions_mut().insert(SampledOut);
+            return;
+        }
+
+        let mut debug_annotations = DebugAnnotations::default();
+        if self.config.span_ids {
+            let slice_id = self.next_slice_id.fetch_add(1, Ordering::Relaxed);
+            let ids = [
+                ("tracing_span_id", Some(id.into_u64())),
+                (
+                    "tracing_parent_id",
+                    span.parent().map(|p| p.id().into_u64()),
+                ),
+                ("slice_id", Some(slice_id)),
+            ];
+            debug_annotations.annotations.extend(
+                ids.into_iter()
+                    .filter_map(|(name, id)| Some(idl_helpers::uint_annotation(name, id?))),
+            );
+            span.extensions_mut().insert(SliceId(slice_id));
+        }
+        if self.config.debug_annotations {
+            attrs.record(&mut debug_annotations);
+        }
+
+        let mut packet = idl::TracePacket::default();
+
+        // check if parent span has a non default track descriptor
+        let inherited_track_descriptor = span
+            .parent()
+            // If the span has a parent, try retrieving the track descriptor from the parent's state
+            .and_then(|parent_span| {
+                parent_span
+                    .extensions()
+                    .get::<PerfettoSpanState>()
+                    .map(|state| state.track_descriptor.clone())
+            })
+            .flatten();
+
+        // retrieve the user set track (via `perfetto.track` or `perfetto.track_name` fields)
+        let mut user_track_name = None;
+        let mut user_track = None;
+        let mut visitor = TrackNameVisitor {
+            user_track_name: &mut user_track_name,
+            user_track: &mut user_track,
+        };
+        attrs.record(&mut visitor);
+
+        // named tracks are `Track`s under the process, spans on them, their own or inherited,
+        // take lanes of them where their slices nest
+        let user_track = user_track.or_else(|| user_track_name.map(|name| Track::new(&name).id()));
+        let parent_lane = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<track::TrackLane>().cloned());
+        let user_track = user_track
+            .or_else(|| {
+                parent_lane
+                    .as_ref()
+                    .and(inherited_track_descriptor.as_ref()?.uuid)
+            })
+            .and_then(|uuid| self.lanes.claim(uuid, parent_lane.as_ref(), id));
+
+        // the ancestors of a track are written right away, ahead of the span's records
+        let user_track_descriptor = user_track.and_then(|(track, lane)| {
+            span.extensions_mut().insert(lane);
+            let mut descriptors = track::descriptors(track.id(), self.process_track_uuid.get())?;
+            let track = descriptors.pop()?;
+            if !descriptors.is_empty() {
+                let packet = descriptors
+                    .into_iter()
+                    .map(|d| idl::TracePacket {
+                        data: Some(idl::trace_packet::Data::TrackDescriptor(d)),
+                        ..Default::default()
+                    })
+                    .collect();
+                self.output.write(idl::Trace { packet });
+            }
+            Some(track)
+        });
+
+        // resolve the optional track descriptor for this span (either inherited from parent or user set, or None)
+        let span_track_descriptor = user_track_descriptor.or(inherited_track_descriptor);
+
+        let final_uuid = span_track_descriptor
+            .as_ref()
+            .map(|desc| desc.uuid())
+            .unwrap_or_else(current_thread_uuid);
+
+        let mut event = create_event(
+            final_uuid, // span track id if exists, otherwise thread track id
             Some(span.name()),
             span.metadata().file().zip(span.metadata().line()),
             debug_annotations,
             Some(idl::track_event::Type::SliceBegin),
         );
+        // the target is the category, for the `TrackEventConfig` of system traces
+        event.categories = vec![span.metadata().target().to_string()];
+        #[cfg(unix)]
+        if self.config.thread_cpu_time {
+            let counter = idl_helpers::current_thread_cpu_time_track_descriptor();
+            event.extra_counter_track_uuids.push(counter.uuid());
+            event.extra_counter_values.push(thread_time::now() as i64);
+            span.extensions_mut().insert(thread_time::SpanCpuTime {
+                begin_descriptors: [idl_helpers::current_thread_track_descriptor(), counter],
+                entered: None,
+                total: 0,
+            });
+        }
+        #[cfg(feature = "allocator")]
+        if self.config.allocations {
+            span.extensions_mut()
+                .insert(allocator::SpanAllocations::default());
+        }
+        #[cfg(feature = "allocator")]
+        if self.config.heap_counter {
+            event.extra_counter_track_uuids.push(self.heap_track.uuid());
+            event.extra_counter_values.push(allocator::heap_size());
+        }
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
         packet.data = Some(idl::trace_packet::Data::TrackEvent(event));
-        packet.timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as _);
+        packet.timestamp = timestamp;
         packet.trusted_pid = Some(std::process::id() as _);
         packet.optional_trusted_packet_sequence_id = Some(
             idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
@@ -280,15 +1338,68 @@ where
             ),
         );
 
-        let span_state = PerfettoSpanState {
+        self.open_spans.opened(
+            id,
+            span_track_descriptor
+                .clone()
+                .unwrap_or_else(idl_helpers::current_thread_track_descriptor),
+        );
+        #[allow(unused_mut)]
+        let mut span_state = PerfettoSpanState {
             track_descriptor: span_track_descriptor,
-            trace: idl::Trace {
-                packet: vec![packet],
-            },
+            begin: vec![packet],
+            trace: idl::Trace::default(),
+            start: timestamp.unwrap_or_default(),
+            children: 0,
         };
+        #[cfg(feature = "callstacks")]
+        span_state
+            .begin
+            .extend(self.callstack_packet(span.metadata(), attrs, timestamp));
         span.extensions_mut().insert(span_state);
     }
 
+    #[allow(unused_variables)]
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.open_spans.entered(id);
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        #[allow(unused_mut, unused_variables)]
+        let mut extensions = span.extensions_mut();
+        #[cfg(feature = "allocator")]
+        if let Some(allocations) = extensions.get_mut::<allocator::SpanAllocations>() {
+            allocations.entered = Some(allocator::AllocationCounters::current_thread());
+        }
+        #[cfg(unix)]
+        if let Some(cpu_time) = extensions.get_mut::<thread_time::SpanCpuTime>() {
+            cpu_time.entered = Some(thread_time::now());
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.open_spans.exited(id);
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        #[allow(unused_mut, unused_variables)]
+        let mut extensions = span.extensions_mut();
+        #[cfg(unix)]
+        if let Some(cpu_time) = extensions.get_mut::<thread_time::SpanCpuTime>() {
+            if let Some(entered) = cpu_time.entered.take() {
+                cpu_time.total += thread_time::now().saturating_sub(entered);
+            }
+        }
+        #[cfg(feature = "allocator")]
+        if let Some(allocations) = extensions.get_mut::<allocator::SpanAllocations>() {
+            if let Some(entered) = allocations.entered.take() {
+                let now = allocator::AllocationCounters::current_thread();
+                allocations.total.add_since(entered, now);
+            }
+        }
+    }
+
     fn on_record(&self, span: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
         let Some(span) = ctx.span(span) else {
             return;
@@ -299,7 +1410,7 @@ where
         // update the trace packet with the debug data here.
         if let Some(extension) = span.extensions_mut().get_mut::<PerfettoSpanState>() {
             if let Some(idl::trace_packet::Data::TrackEvent(ref mut event)) =
-                &mut extension.trace.packet[0].data
+                &mut extension.begin[0].data
             {
                 let mut debug_annotations = DebugAnnotations::default();
                 values.record(&mut debug_annotations);
@@ -311,6 +1422,13 @@ where
     }
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // triggers fire whether or not the event itself gets recorded
+        let mut trigger = trigger::TriggerVisitor::default();
+        event.record(&mut trigger);
+        if let Some(name) = trigger.name {
+            self.output.trigger(&name);
+        }
+
         let enabled = self
             .config
             .filter
@@ -325,11 +1443,35 @@ where
             return;
         }
 
-        let metadata = event.metadata();
+        if let Some(span) = ctx.event_span(event) {
+            if span.extensions().get::<SampledOut>().is_some() {
+                return;
+            }
+        }
+        if let Some(limit) = self.config.callsite_rate_limit {
+            if !self.rate_limiter.allow(event.metadata().callsite(), limit) {
+                return;
+            }
+        }
+
+        let metadata = event.metadata();
         let location = metadata.file().zip(metadata.line());
 
         let mut debug_annotations = DebugAnnotations::default();
 
+        if self.config.span_ids {
+            if let Some(span) = ctx.event_span(event) {
+                let slice_id = span.extensions().get::<SliceId>().map(SliceId::get);
+                let ids = [
+                    ("tracing_span_id", Some(span.id().into_u64())),
+                    ("slice_id", slice_id),
+                ];
+                debug_annotations.annotations.extend(
+                    ids.into_iter()
+                        .filter_map(|(name, id)| Some(idl_helpers::uint_annotation(name, id?))),
+                );
+            }
+        }
         if self.config.debug_annotations {
             event.record(&mut debug_annotations);
         }
@@ -342,10 +1484,16 @@ where
             debug_annotations,
             Some(idl::track_event::Type::Instant),
         );
+        track_event.categories = vec![metadata.target().to_string()];
+        let mut flows = context::FlowVisitor::default();
+        event.record(&mut flows);
+        track_event.flow_ids = flows.flow_ids;
+        track_event.terminating_flow_ids = flows.terminating_flow_ids;
 
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
         let mut packet = idl::TracePacket {
             trusted_pid: Some(std::process::id() as _),
-            timestamp: chrono::Local::now().timestamp_nanos_opt().map(|t| t as _),
+            timestamp,
             optional_trusted_packet_sequence_id: Some(
                 idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
                     self.sequence_id.get() as _,
@@ -353,6 +1501,10 @@ where
             ),
             ..Default::default()
         };
+        #[cfg(feature = "callstacks")]
+        let callstack = self.callstack_packet(metadata, event, timestamp);
+        #[cfg(not(feature = "callstacks"))]
+        let callstack: Option<idl::TracePacket> = None;
 
         if let Some(span) = ctx.event_span(event) {
             if let Some(span_state) = span.extensions_mut().get_mut::<PerfettoSpanState>() {
@@ -363,6 +1515,7 @@ where
                     .or(Some(current_thread_uuid()));
                 packet.data = Some(idl::trace_packet::Data::TrackEvent(track_event));
                 span_state.trace.packet.push(packet);
+                span_state.trace.packet.extend(callstack);
                 return;
             }
         }
@@ -371,7 +1524,7 @@ where
         track_event.track_uuid = Some(thread_track_uuid);
         packet.data = Some(idl::trace_packet::Data::TrackEvent(track_event));
         let trace = idl::Trace {
-            packet: vec![packet],
+            packet: std::iter::once(packet).chain(callstack).collect(),
         };
         self.write_log(trace, idl_helpers::current_thread_track_descriptor());
     }
@@ -384,8 +1537,30 @@ where
         let Some(mut span_state) = span.extensions_mut().remove::<PerfettoSpanState>() else {
             return;
         };
+        self.open_spans.closed(&id);
+        if let Some(lane) = span.extensions_mut().remove::<track::TrackLane>() {
+            self.lanes.release(lane);
+        }
 
-        let debug_annotations = DebugAnnotations::default();
+        #[allow(unused_mut)]
+        let mut debug_annotations = DebugAnnotations::default();
+        #[cfg(unix)]
+        let cpu_time = span.extensions_mut().remove::<thread_time::SpanCpuTime>();
+        #[cfg(unix)]
+        if let Some(cpu_time) = &cpu_time {
+            debug_annotations
+                .annotations
+                .push(idl_helpers::uint_annotation(
+                    "thread_cpu_time_ns",
+                    cpu_time.total,
+                ));
+        }
+        #[cfg(feature = "allocator")]
+        if let Some(allocations) = span.extensions_mut().remove::<allocator::SpanAllocations>() {
+            debug_annotations
+                .annotations
+                .extend(allocations.total.debug_annotations());
+        }
 
         let track_uuid = span_state
             .track_descriptor
@@ -395,15 +1570,29 @@ where
 
         let mut packet = idl::TracePacket::default();
         let meta = span.metadata();
-        let event = create_event(
+        let mut event = create_event(
             track_uuid,
             Some(meta.name()),
             meta.file().zip(meta.line()),
             debug_annotations,
             Some(idl::track_event::Type::SliceEnd),
         );
+        event.categories = vec![meta.target().to_string()];
+        #[cfg(unix)]
+        if cpu_time.is_some() {
+            event
+                .extra_counter_track_uuids
+                .push(idl_helpers::current_thread_cpu_time_track_uuid());
+            event.extra_counter_values.push(thread_time::now() as i64);
+        }
+        #[cfg(feature = "allocator")]
+        if self.config.heap_counter {
+            event.extra_counter_track_uuids.push(self.heap_track.uuid());
+            event.extra_counter_values.push(allocator::heap_size());
+        }
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
         packet.data = Some(idl::trace_packet::Data::TrackEvent(event));
-        packet.timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as _);
+        packet.timestamp = timestamp;
         packet.trusted_pid = Some(std::process::id() as _);
         packet.optional_trusted_packet_sequence_id = Some(
             idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
@@ -411,14 +1600,111 @@ where
             ),
         );
 
-        span_state.trace.packet.push(packet);
+        let duration = timestamp
+            .unwrap_or_default()
+            .saturating_sub(span_state.start);
+        if self.config.span_stats {
+            self.stats
+                .record(meta, duration, duration.saturating_sub(span_state.children));
+        }
 
-        self.write_log(
-            span_state.trace,
-            span_state
-                .track_descriptor
-                .unwrap_or_else(idl_helpers::current_thread_track_descriptor),
-        );
+        let recorded_parent = span
+            .scope()
+            .skip(1)
+            .find(|parent| parent.extensions().get::<PerfettoSpanState>().is_some());
+        if let Some(parent) = &recorded_parent {
+            // charge this span to the closest recorded ancestor, for its self time
+            parent
+                .extensions_mut()
+                .get_mut::<PerfettoSpanState>()
+                .expect("recorded parent has a span state")
+                .children += duration;
+        }
+
+        let below = |threshold: Option<Duration>| {
+            threshold.is_some_and(|threshold| duration < threshold.as_nanos() as u64)
+        };
+        let short = below(self.config.min_span_duration);
+        let too_short = short || below(self.config.tail_sampling);
+        if too_short {
+            // drop our own slice, and with it the buffered events unless they get folded: tail
+            // sampling hands them over to the recorded parent, which decides for them
+            let fold = if short {
+                self.config.fold_dropped_spans
+            } else {
+                recorded_parent.is_some()
+            };
+            if !fold || span_state.trace.packet.is_empty() {
+                return;
+            }
+        } else {
+            let begin = std::mem::take(&mut span_state.begin);
+            span_state.trace.packet.splice(0..0, begin);
+            span_state.trace.packet.push(packet);
+        }
+
+        #[cfg(unix)]
+        if let Some(cpu_time) = cpu_time.filter(|_| !too_short) {
+            // the counter tracks go before the slice events sampling them
+            let mut descriptors = cpu_time.begin_descriptors.to_vec();
+            if descriptors[1].uuid() != idl_helpers::current_thread_cpu_time_track_uuid() {
+                descriptors.push(idl_helpers::current_thread_track_descriptor());
+                descriptors.push(idl_helpers::current_thread_cpu_time_track_descriptor());
+            }
+            let descriptors = descriptors.into_iter().map(|desc| idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackDescriptor(desc)),
+                ..Default::default()
+            });
+            span_state.trace.packet.splice(0..0, descriptors);
+        }
+        #[cfg(feature = "allocator")]
+        if self.config.heap_counter && !too_short {
+            let descriptor = idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackDescriptor(
+                    self.heap_track.clone(),
+                )),
+                ..Default::default()
+            };
+            span_state.trace.packet.insert(0, descriptor);
+        }
+
+        let track_descriptor = span_state
+            .track_descriptor
+            .unwrap_or_else(idl_helpers::current_thread_track_descriptor);
+
+        if let Some(parent) = recorded_parent {
+            let mut extensions = parent.extensions_mut();
+            let parent_state = extensions
+                .get_mut::<PerfettoSpanState>()
+                .expect("recorded parent has a span state");
+
+            if too_short {
+                // we were dropped and fold our events into the parent: hand them over to it
+                parent_state.trace.packet.push(idl::TracePacket {
+                    data: Some(idl::trace_packet::Data::TrackDescriptor(track_descriptor)),
+                    ..Default::default()
+                });
+                parent_state
+                    .trace
+                    .packet
+                    .append(&mut span_state.trace.packet);
+                return;
+            }
+        }
+
+        self.write_log(span_state.trace, track_descriptor);
+    }
+
+    // lets the panic hook reach the layer whatever its writer
+    #[allow(unsafe_code)]
+    unsafe fn downcast_raw(&self, id: std::any::TypeId) -> Option<*const ()> {
+        if id == std::any::TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if id == std::any::TypeId::of::<panic::PanicHandle>() {
+            Some(&self.panic as *const panic::PanicHandle as *const ())
+        } else {
+            None
+        }
     }
 }
 
@@ -492,6 +1778,7 @@ impl Visit for DebugAnnotations {
 }
 
 #[cfg(test)]
+#[allow(clippy::len_zero)]
 mod tests {
     use std::sync::Arc;
     use std::sync::Mutex;
@@ -501,6 +1788,9 @@ mod tests {
 
     use crate::idl;
     use crate::idl::track_event;
+    use crate::ArgValue;
+    #[cfg(feature = "chrome-json")]
+    use crate::OutputFormat;
     use crate::PerfettoLayer;
     use prost::Message;
 
@@ -540,6 +1830,27 @@ mod tests {
         }
     }
 
+    /// Decodes the track events written to `writer`, in write order.
+    fn track_events(writer: &TestWriter) -> Vec<idl::TrackEvent> {
+        let trace = idl::Trace::decode(writer.buf.lock().unwrap().as_slice()).unwrap();
+        trace
+            .packet
+            .into_iter()
+            .filter_map(|packet| match packet.data {
+                Some(idl::trace_packet::Data::TrackEvent(event)) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn count_named(events: &[idl::TrackEvent], name: &str, kind: track_event::Type) -> usize {
+        let name = Some(track_event::NameField::Name(name.to_string()));
+        events
+            .iter()
+            .filter(|e| e.name_field == name && e.r#type() == kind)
+            .count()
+    }
+
     // Check that we are able to write a span and confirm that it's written as protobuf data to the
     // output
     #[test]
@@ -682,4 +1993,1644 @@ mod tests {
         }
         assert_eq!(extra_writer.buf.lock().unwrap().len(), 0);
     }
+
+    // Check that the Chrome JSON output of a span is a streamable array of matching B/E events
+    #[cfg(feature = "chrome-json")]
+    #[test]
+    fn test_chrome_json_span() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer)
+            .with_debug_annotations(true)
+            .with_output_format(OutputFormat::ChromeJson);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        {
+            let demo_span = trace_span!("json_span", regular_arg = "Arg data");
+            let _enter = demo_span.enter();
+            tracing::info!(answer = 42, "inside");
+        }
+
+        let buf = extra_writer.buf.lock().unwrap();
+        let text = std::str::from_utf8(&buf).unwrap();
+        assert!(text.starts_with("[\n"));
+        // The closing bracket is optional in the array form, close it to parse the output.
+        let events: Vec<serde_json::Value> =
+            serde_json::from_str(&format!("{}]", text.trim_end().trim_end_matches(','))).unwrap();
+
+        let begin = events.iter().find(|e| e["ph"] == "B").unwrap();
+        let end = events.iter().find(|e| e["ph"] == "E").unwrap();
+        let instant = events.iter().find(|e| e["ph"] == "i").unwrap();
+        assert_eq!(begin["name"], "json_span");
+        assert_eq!(begin["args"]["regular_arg"], "Arg data");
+        assert_eq!(instant["args"]["answer"], 42);
+        assert_eq!(begin["pid"], std::process::id());
+        assert_eq!(begin["tid"], end["tid"]);
+        assert_eq!(begin["tid"], instant["tid"]);
+        assert!(begin["ts"].as_f64() <= end["ts"].as_f64());
+        assert!(events
+            .iter()
+            .any(|e| e["ph"] == "M" && e["name"] == "thread_name" && e["tid"] == begin["tid"]));
+    }
+
+    // Check that span durations are aggregated per callsite, and summarized when the layer drops
+    #[test]
+    fn test_span_stats() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_span_stats(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            trace_span!("outer").in_scope(|| {
+                for _ in 0..3 {
+                    let _inner = trace_span!("inner").entered();
+                    std::thread::sleep(std::time::Duration::from_millis(2));
+                }
+            });
+
+            let stats = tracing::dispatcher::get_default(|dispatch| {
+                let layer = dispatch
+                    .downcast_ref::<PerfettoLayer<TestWriter>>()
+                    .unwrap();
+                layer.stats_snapshot()
+            });
+            assert_eq!(stats.len(), 2);
+            let (outer, inner) = (&stats[0], &stats[1]);
+            assert_eq!((outer.name(), outer.count()), ("outer", 1));
+            assert_eq!((inner.name(), inner.count()), ("inner", 3));
+            assert!(inner.min() >= std::time::Duration::from_millis(2));
+            assert!(inner.min() <= inner.percentile(0.5));
+            assert!(inner.percentile(0.99) <= inner.max());
+            assert_eq!(inner.self_time(), inner.total());
+            assert_eq!(outer.self_time(), outer.total() - inner.total());
+        }
+
+        // The summary is written once the subscriber (and the layer) is dropped.
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let summary = trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == track_event::Type::Instant =>
+                {
+                    Some(event)
+                }
+                _ => None,
+            })
+            .find(|event| {
+                event.name_field == Some(track_event::NameField::Name("inner".to_string()))
+            })
+            .unwrap();
+        assert_eq!(
+            summary.debug_annotations[0].value,
+            Some(idl::debug_annotation::Value::UintValue(3))
+        );
+    }
+
+    // Check that an explicit summary is written right away, and not again when the layer drops
+    #[test]
+    fn test_write_stats_summary() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_span_stats(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let summaries = || {
+            let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+            trace
+                .packet
+                .iter()
+                .filter(|packet| match &packet.data {
+                    Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                        event.r#type() == track_event::Type::Instant
+                            && event.name_field
+                                == Some(track_event::NameField::Name("work".to_string()))
+                    }
+                    _ => false,
+                })
+                .count()
+        };
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            trace_span!("work").in_scope(|| {});
+            tracing::dispatcher::get_default(|dispatch| {
+                let layer = dispatch
+                    .downcast_ref::<PerfettoLayer<TestWriter>>()
+                    .unwrap();
+                layer.write_stats_summary();
+            });
+            assert_eq!(summaries(), 1);
+        }
+        assert_eq!(summaries(), 1);
+    }
+
+    // A sampled out root span drops its whole tree, but not the events outside of it
+    #[test]
+    fn test_root_span_sampling() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_root_span_sampling(0.0);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        trace_span!("root").in_scope(|| {
+            trace_span!("child").in_scope(|| tracing::info!("in child"));
+        });
+        tracing::info!("outside");
+
+        let events = track_events(&extra_writer);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            count_named(&events, "root", track_event::Type::SliceBegin),
+            0
+        );
+        assert_eq!(
+            count_named(&events, "child", track_event::Type::SliceBegin),
+            0
+        );
+    }
+
+    // Only `max_per_second` records of a callsite are kept, the subtree of dropped spans included
+    #[test]
+    fn test_callsite_rate_limit() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_callsite_rate_limit(2);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        for _ in 0..5 {
+            trace_span!("limited").in_scope(|| {
+                trace_span!("nested").in_scope(|| {});
+            });
+        }
+
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "limited", track_event::Type::SliceBegin),
+            2
+        );
+        assert_eq!(
+            count_named(&events, "nested", track_event::Type::SliceBegin),
+            2
+        );
+    }
+
+    // Spans are only written when they are slower than the threshold, each checked as it closes
+    #[test]
+    fn test_tail_sampling() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer =
+            PerfettoLayer::new(writer).with_tail_sampling(std::time::Duration::from_millis(20));
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        trace_span!("fast").in_scope(|| {
+            trace_span!("fast_child").in_scope(|| tracing::info!("in fast child"));
+        });
+        trace_span!("slow").in_scope(|| {
+            trace_span!("fast_child").in_scope(|| tracing::info!("in fast child of slow"));
+            trace_span!("slow_child").in_scope(|| {
+                std::thread::sleep(std::time::Duration::from_millis(25));
+            });
+            // kept spans are written as they close, not with their parent
+            let events = track_events(&extra_writer);
+            assert_eq!(
+                count_named(&events, "slow_child", track_event::Type::SliceBegin),
+                1
+            );
+        });
+
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "fast", track_event::Type::SliceBegin),
+            0
+        );
+        assert_eq!(
+            count_named(&events, "fast_child", track_event::Type::SliceBegin),
+            0
+        );
+        assert_eq!(
+            count_named(&events, "slow", track_event::Type::SliceBegin),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "slow_child", track_event::Type::SliceEnd),
+            1
+        );
+        // the event of the fast child is handed over to the slow span
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| e.r#type() == track_event::Type::Instant)
+                .count(),
+            1
+        );
+        assert_eq!(events.len(), 5);
+    }
+
+    // Spans shorter than the minimum duration are discarded along with their events
+    #[test]
+    fn test_min_span_duration() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer)
+            .with_min_span_duration(std::time::Duration::from_millis(20))
+            .with_span_stats(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        trace_span!("long").in_scope(|| {
+            trace_span!("short").in_scope(|| {
+                tracing::info!("in short");
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            });
+            std::thread::sleep(std::time::Duration::from_millis(25));
+        });
+
+        // the dropped span still counts as a child for the self time of its parent
+        let stats = tracing::dispatcher::get_default(|dispatch| {
+            let layer = dispatch
+                .downcast_ref::<PerfettoLayer<TestWriter>>()
+                .unwrap();
+            layer.stats_snapshot()
+        });
+        let (long, short) = (&stats[0], &stats[1]);
+        assert_eq!((long.name(), short.name()), ("long", "short"));
+        assert_eq!(long.self_time(), long.total() - short.total());
+
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "long", track_event::Type::SliceBegin),
+            1
+        );
+        assert_eq!(count_named(&events, "long", track_event::Type::SliceEnd), 1);
+        assert_eq!(events.len(), 2);
+    }
+
+    // The events of discarded spans can be kept in their parent instead
+    #[test]
+    fn test_fold_dropped_spans() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer)
+            .with_min_span_duration(std::time::Duration::from_millis(20))
+            .with_fold_dropped_spans(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        trace_span!("long").in_scope(|| {
+            trace_span!("short").in_scope(|| {
+                trace_span!("shorter").in_scope(|| tracing::info!("in shorter"));
+            });
+            std::thread::sleep(std::time::Duration::from_millis(25));
+        });
+        trace_span!("short_root").in_scope(|| tracing::info!("in short root"));
+
+        let events = track_events(&extra_writer);
+        let kinds: Vec<_> = events.iter().map(|e| e.r#type()).collect();
+        assert_eq!(
+            kinds,
+            [
+                track_event::Type::SliceBegin,
+                track_event::Type::Instant,
+                track_event::Type::SliceEnd,
+                track_event::Type::Instant,
+            ]
+        );
+        assert_eq!(
+            count_named(&events, "long", track_event::Type::SliceBegin),
+            1
+        );
+    }
+
+    // The call stack of a discarded span goes away with it, its events' stacks are folded
+    #[cfg(feature = "callstacks")]
+    #[test]
+    fn test_fold_dropped_spans_with_callstacks() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer)
+            .with_min_span_duration(std::time::Duration::from_millis(20))
+            .with_fold_dropped_spans(true)
+            .with_callstacks(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        trace_span!("long").in_scope(|| {
+            trace_span!("short", perfetto.callstack = true).in_scope(|| {
+                tracing::info!(perfetto.callstack = true, "in short");
+            });
+            std::thread::sleep(std::time::Duration::from_millis(25));
+        });
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let samples: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|packet| matches!(packet.data, Some(idl::trace_packet::Data::PerfSample(_))))
+            .collect();
+        let events = track_events(&extra_writer);
+        let instant = trace
+            .packet
+            .iter()
+            .find(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                    event.r#type() == track_event::Type::Instant
+                }
+                _ => false,
+            })
+            .expect("the folded event");
+        assert_eq!(samples.len(), 1, "only the stack of the folded event");
+        assert_eq!(samples[0].timestamp, instant.timestamp);
+        assert_eq!(
+            count_named(&events, "short", track_event::Type::SliceBegin),
+            0
+        );
+    }
+
+    #[cfg(feature = "callstacks")]
+    #[inline(never)]
+    fn record_callstacks() {
+        tracing::info!("unmarked");
+        tracing::info!(perfetto.callstack = true, "marked");
+        tracing::warn!("warning");
+    }
+
+    #[test]
+    fn test_panic_hook() {
+        crate::install_panic_hook();
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_debug_annotations(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let elsewhere = std::thread::spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || trace_span!("elsewhere"))
+        })
+        .join()
+        .unwrap();
+        let outer = trace_span!("outer").entered();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _elsewhere = elsewhere.enter();
+            let _inner = trace_span!("inner").entered();
+            tracing::info!(name: "about to fail", "buffered in the inner span");
+            panic!("boom");
+        }));
+        assert!(result.is_err());
+
+        let events = track_events(&extra_writer);
+        let panic = events
+            .iter()
+            .find(|e| e.name_field == Some(track_event::NameField::Name("panic".into())))
+            .expect("a panic instant");
+        assert_eq!(
+            panic.debug_annotations[0].value,
+            Some(idl::debug_annotation::Value::StringValue("boom".into()))
+        );
+        assert_eq!(
+            count_named(&events, "about to fail", track_event::Type::Instant),
+            1
+        );
+        for name in ["inner", "outer", "elsewhere"] {
+            assert_eq!(
+                count_named(&events, name, track_event::Type::SliceEnd),
+                1,
+                "{name}"
+            );
+        }
+        let track_of = |name: &str, kind| {
+            let name = Some(track_event::NameField::Name(name.to_string()));
+            events
+                .iter()
+                .find(|e| e.name_field == name && e.r#type() == kind)
+                .and_then(|e| e.track_uuid)
+        };
+        assert_eq!(
+            track_of("elsewhere", track_event::Type::SliceEnd),
+            track_of("elsewhere", track_event::Type::SliceBegin),
+            "ended on the track of the thread it was opened on"
+        );
+        assert_ne!(
+            track_of("elsewhere", track_event::Type::SliceEnd),
+            track_of("outer", track_event::Type::SliceEnd)
+        );
+
+        // the finalized spans aren't written again when they close
+        drop(outer);
+        drop(elsewhere);
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "outer", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "elsewhere", track_event::Type::SliceEnd),
+            1
+        );
+    }
+
+    // Check that a caught panic leaves the spans open on other threads to close normally
+    #[test]
+    fn test_caught_panic_keeps_other_threads() {
+        crate::install_panic_hook();
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let (opened_tx, opened_rx) = std::sync::mpsc::channel();
+        let (close_tx, close_rx) = std::sync::mpsc::channel::<()>();
+        let worker = std::thread::spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                let _span = trace_span!("working").entered();
+                opened_tx.send(()).unwrap();
+                close_rx.recv().unwrap();
+                tracing::info!(name: "still working", "after the panic");
+            })
+        });
+        opened_rx.recv().unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let _span = trace_span!("failing").entered();
+            panic!("caught");
+        });
+        assert!(result.is_err());
+
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "failing", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "working", track_event::Type::SliceEnd),
+            0,
+            "not ended by the panic of another thread"
+        );
+
+        close_tx.send(()).unwrap();
+        worker.join().unwrap();
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "working", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "still working", track_event::Type::Instant),
+            1
+        );
+    }
+
+    #[cfg(all(feature = "signals", target_os = "linux"))]
+    #[test]
+    fn test_signal_dump() {
+        use signal_hook::consts::{SIGUSR1, SIGUSR2};
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_signal_dump(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let wait_for = |condition: &dyn Fn() -> bool| {
+            for _ in 0..500 {
+                if condition() {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("timed out");
+        };
+        let paused = || {
+            tracing::dispatcher::get_default(|dispatch| {
+                let layer = dispatch
+                    .downcast_ref::<PerfettoLayer<TestWriter>>()
+                    .unwrap();
+                layer
+                    .output
+                    .paused
+                    .load(std::sync::atomic::Ordering::Relaxed)
+            })
+        };
+
+        let stuck = trace_span!("stuck").entered();
+        let waiting = trace_span!("waiting").entered();
+        signal_hook::low_level::raise(SIGUSR1).unwrap();
+        wait_for(&|| {
+            count_named(
+                &track_events(&extra_writer),
+                "open spans",
+                track_event::Type::Instant,
+            ) == 1
+        });
+        let events = track_events(&extra_writer);
+        let snapshot = events
+            .iter()
+            .find(|e| e.name_field == Some(track_event::NameField::Name("open spans".into())))
+            .unwrap();
+        let names: Vec<_> = snapshot
+            .debug_annotations
+            .iter()
+            .map(|a| a.name_field.clone())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                Some(idl::debug_annotation::NameField::Name("stuck".into())),
+                Some(idl::debug_annotation::NameField::Name("waiting".into())),
+            ]
+        );
+        drop(waiting);
+        drop(stuck);
+
+        signal_hook::low_level::raise(SIGUSR2).unwrap();
+        wait_for(&paused);
+        trace_span!("while paused").in_scope(|| {});
+        signal_hook::low_level::raise(SIGUSR2).unwrap();
+        wait_for(&|| !paused());
+        trace_span!("resumed").in_scope(|| {});
+
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "stuck", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "while paused", track_event::Type::SliceEnd),
+            0
+        );
+        assert_eq!(
+            count_named(&events, "resumed", track_event::Type::SliceEnd),
+            1
+        );
+    }
+
+    #[test]
+    fn test_open_spans_snapshot() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_debug_annotations(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let with_layer = |f: &dyn Fn(&PerfettoLayer<TestWriter>)| {
+            tracing::dispatcher::get_default(|dispatch| {
+                f(dispatch
+                    .downcast_ref::<PerfettoLayer<TestWriter>>()
+                    .unwrap())
+            })
+        };
+
+        let outer = trace_span!("outer", id = 42).entered();
+        let inner = trace_span!("inner").entered();
+        let job = trace_span!("job", perfetto.track_name = "jobs");
+        with_layer(&|layer| {
+            let stacks = layer.open_spans_snapshot();
+            assert_eq!(stacks.len(), 2);
+            let thread = stacks
+                .iter()
+                .find(|stack| stack.thread_id() == Some(thread_id::get() as _))
+                .expect("a stack for this thread");
+            let names: Vec<_> = thread.spans().iter().map(|span| span.name()).collect();
+            assert_eq!(names, ["outer", "inner"]);
+            assert_eq!(thread.spans()[0].args()["id"], ArgValue::Int(42));
+            assert!(thread.spans()[0].open_for() >= thread.spans()[1].open_for());
+
+            let jobs = stacks
+                .iter()
+                .find(|stack| stack.track_name() == Some("jobs"))
+                .expect("a stack for the named track");
+            assert_eq!(jobs.thread_id(), None);
+            assert_eq!(jobs.spans()[0].name(), "job");
+
+            layer.write_open_spans_snapshot();
+        });
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "open spans", track_event::Type::Instant),
+            2
+        );
+
+        drop(job);
+        drop(inner);
+        drop(outer);
+        with_layer(&|layer| assert!(layer.open_spans_snapshot().is_empty()));
+    }
+
+    #[test]
+    fn test_span_ids() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_span_ids(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let outer = trace_span!("outer").entered();
+        for _ in 0..2 {
+            let _inner = trace_span!("inner").entered();
+            tracing::info!(name: "in inner", "logged");
+        }
+        drop(outer);
+
+        let ids = |event: &idl::TrackEvent| -> std::collections::HashMap<String, u64> {
+            event
+                .debug_annotations
+                .iter()
+                .filter_map(|a| match (&a.name_field, &a.value) {
+                    (
+                        Some(idl::debug_annotation::NameField::Name(name)),
+                        Some(idl::debug_annotation::Value::UintValue(id)),
+                    ) => Some((name.clone(), *id)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let events = track_events(&extra_writer);
+        let named = |name: &str, kind| -> Vec<_> {
+            let name = Some(track_event::NameField::Name(name.to_string()));
+            events
+                .iter()
+                .filter(|e| e.name_field == name && e.r#type() == kind)
+                .map(ids)
+                .collect()
+        };
+        let outer = &named("outer", track_event::Type::SliceBegin)[0];
+        let inner = named("inner", track_event::Type::SliceBegin);
+        let logged = named("in inner", track_event::Type::Instant);
+        assert!(!outer.contains_key("tracing_parent_id"));
+        assert_eq!(inner.len(), 2);
+        for (inner, logged) in inner.iter().zip(&logged) {
+            assert_eq!(inner["tracing_parent_id"], outer["tracing_span_id"]);
+            assert_eq!(logged["tracing_span_id"], inner["tracing_span_id"]);
+            assert_eq!(logged["slice_id"], inner["slice_id"]);
+        }
+        assert_ne!(
+            inner[0]["slice_id"], inner[1]["slice_id"],
+            "slice ids are never reused"
+        );
+    }
+
+    #[test]
+    fn test_trace_context() {
+        let context = crate::TraceContext::new();
+        let mut command = std::process::Command::new("worker");
+        context.inject(&mut command);
+        let (_, injected) = command.get_envs().next().unwrap();
+        let injected = crate::TraceContext::parse(injected.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(injected.trace_uuid(), context.trace_uuid());
+        assert!(injected.flow_id().is_some());
+
+        let parent_writer = TestWriter::new();
+        let parent_buf = parent_writer.make_writer();
+        let layer = PerfettoLayer::new(parent_writer).with_trace_context(context.clone());
+        let child =
+            tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+                context.for_child()
+            });
+        let flow_id = child.flow_id().unwrap();
+
+        // children whose clock is a second behind, or ahead of, their parent's
+        for skew in [1_000_000_000, -1_000_000_000] {
+            let skewed = crate::TraceContext::parse(&format!(
+                "{:032x}:{:016x}:{}",
+                child.trace_uuid(),
+                flow_id,
+                child
+                    .parent_timestamp()
+                    .unwrap()
+                    .saturating_add_signed(skew)
+            ))
+            .unwrap();
+            let child_writer = TestWriter::new();
+            let child_buf = child_writer.make_writer();
+            let layer = PerfettoLayer::new(child_writer).with_trace_context(skewed.clone());
+            tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+                tracing::info!(name: "work", "in the child");
+            });
+
+            let named = |events: &[idl::TrackEvent], name: &str| -> idl::TrackEvent {
+                let name = Some(track_event::NameField::Name(name.to_string()));
+                events
+                    .iter()
+                    .find(|e| e.name_field == name)
+                    .unwrap()
+                    .clone()
+            };
+            let spawn = named(&track_events(&parent_buf), "spawn child");
+            let start = named(&track_events(&child_buf), "trace context");
+            assert_eq!(spawn.flow_ids, [flow_id]);
+            assert_eq!(start.terminating_flow_ids, [flow_id]);
+
+            let traces = [
+                parent_buf.buf.lock().unwrap().clone(),
+                child_buf.buf.lock().unwrap().clone(),
+            ];
+            let mut merged = Vec::new();
+            crate::convert::merge(&traces, &mut merged).unwrap();
+            let merged = idl::Trace::decode(merged.as_slice()).unwrap();
+            let uuids: Vec<_> = merged
+                .packet
+                .iter()
+                .filter_map(|p| match &p.data {
+                    Some(idl::trace_packet::Data::TraceUuid(uuid)) => Some(*uuid),
+                    _ => None,
+                })
+                .collect();
+            let uuid = context.trace_uuid();
+            let uuid = idl::TraceUuid {
+                msb: Some((uuid >> 64) as i64),
+                lsb: Some(uuid as i64),
+            };
+            assert_eq!(uuids, [uuid]);
+            let start_timestamp = merged.packet.iter().find_map(|p| match &p.data {
+                Some(idl::trace_packet::Data::TrackEvent(event))
+                    if event.terminating_flow_ids == [flow_id] =>
+                {
+                    p.timestamp
+                }
+                _ => None,
+            });
+            assert_eq!(
+                start_timestamp,
+                skewed.parent_timestamp(),
+                "the child's clock is aligned on its parent's, {skew}ns off"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tracks() {
+        use crate::track::{ChildOrdering, Track};
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer);
+        let pool = Track::new("db pool").with_child_ordering(ChildOrdering::Explicit);
+        assert_eq!(Track::new("db pool"), pool, "tracks are reused by key");
+        tracing::subscriber::with_default(
+            tracing_subscriber::registry().with(perfetto_layer),
+            || {
+                for index in 0..2 {
+                    let connection = pool.child(&format!("conn {index}")).with_rank(index);
+                    tracing::info_span!("query", perfetto.track = connection.id()).in_scope(|| {
+                        tracing::info_span!("fetch").in_scope(|| {});
+                    });
+                }
+            },
+        );
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let mut descriptors = std::collections::HashMap::new();
+        for packet in trace.packet {
+            match packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(track)) => {
+                    descriptors.insert(track.uuid(), track);
+                }
+                Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                    let track = &descriptors[&event.track_uuid()];
+                    let parent = &descriptors[&track.parent_uuid()];
+                    assert_eq!(parent.uuid(), pool.id(), "parents are written first");
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            descriptors[&pool.id()].child_ordering(),
+            idl::track_descriptor::ChildTracksOrdering::Explicit
+        );
+        let connection = &descriptors[&pool.child("conn 1").id()];
+        assert_eq!(connection.parent_uuid(), pool.id());
+        assert_eq!(connection.sibling_order_rank(), 1);
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "fetch", track_event::Type::SliceBegin),
+            2
+        );
+    }
+
+    #[test]
+    fn test_track_name_lanes() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer);
+        tracing::subscriber::with_default(
+            tracing_subscriber::registry().with(perfetto_layer),
+            || {
+                tracing::info_span!("first", perfetto.track_name = "worker").in_scope(|| {});
+                tracing::info_span!("again", perfetto.track_name = "worker").in_scope(|| {
+                    tracing::info_span!("nested", perfetto.track_name = "worker").in_scope(|| {});
+                    let overlapping =
+                        tracing::info_span!(parent: None, "overlapping", perfetto.track_name = "worker");
+                    drop(overlapping);
+                });
+                tracing::info_span!("last", perfetto.track_name = "worker").in_scope(|| {});
+            },
+        );
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let mut descriptors = std::collections::HashMap::new();
+        let mut tracks = std::collections::HashMap::new();
+        for packet in trace.packet {
+            match packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(track)) => {
+                    descriptors.insert(track.uuid(), track);
+                }
+                Some(idl::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == track_event::Type::SliceBegin =>
+                {
+                    let Some(track_event::NameField::Name(name)) = &event.name_field else {
+                        continue;
+                    };
+                    tracks.insert(name.clone(), event.track_uuid());
+                }
+                _ => {}
+            }
+        }
+        let worker = crate::Track::new("worker").id();
+        for name in ["first", "again", "nested", "last"] {
+            assert_eq!(tracks[name], worker, "{name} is on the named track");
+        }
+        let lane = &descriptors[&tracks["overlapping"]];
+        assert_ne!(lane.uuid(), worker);
+        assert_eq!(
+            lane.static_or_dynamic_name,
+            descriptors[&worker].static_or_dynamic_name
+        );
+        assert_eq!(lane.parent_uuid(), descriptors[&worker].parent_uuid());
+    }
+
+    // Concurrent children of a span on a named track, inheriting it, go to lanes of it, and their
+    // own children nest on their lanes.
+    #[test]
+    fn test_track_lanes_of_children() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer);
+        tracing::subscriber::with_default(
+            tracing_subscriber::registry().with(perfetto_layer),
+            || {
+                tracing::info_span!("parent", perfetto.track_name = "tasks").in_scope(|| {
+                    let first = tracing::info_span!("first");
+                    let second = tracing::info_span!("second");
+                    second.in_scope(|| tracing::info_span!("inner").in_scope(|| {}));
+                    drop(first);
+                    drop(second);
+                    tracing::info_span!("third").in_scope(|| {});
+                });
+            },
+        );
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let mut tracks = std::collections::HashMap::new();
+        for packet in trace.packet {
+            if let Some(idl::trace_packet::Data::TrackEvent(event)) = packet.data {
+                if let Some(track_event::NameField::Name(name)) = &event.name_field {
+                    if event.r#type() == track_event::Type::SliceBegin {
+                        tracks.insert(name.clone(), event.track_uuid());
+                    }
+                }
+            }
+        }
+        let tasks = crate::Track::new("tasks").id();
+        for name in ["parent", "first", "third"] {
+            assert_eq!(tracks[name], tasks, "{name} nests on the named track");
+        }
+        assert_ne!(tracks["second"], tasks, "second overlaps first");
+        assert_eq!(tracks["inner"], tracks["second"]);
+    }
+
+    #[test]
+    fn test_triggers() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer =
+            PerfettoLayer::new(writer).with_triggers(crate::TriggerMode::CloneSnapshot {
+                pre: std::time::Duration::from_secs(60),
+                post: std::time::Duration::ZERO,
+            });
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        trace_span!("before").in_scope(|| {});
+        assert!(extra_writer.buf.lock().unwrap().is_empty(), "buffered");
+        tracing::warn!(perfetto.trigger = "oom_suspect");
+        trace_span!("after").in_scope(|| {});
+
+        let triggers = |writer: &TestWriter| -> Vec<String> {
+            let trace = idl::Trace::decode(writer.buf.lock().unwrap().as_slice()).unwrap();
+            trace
+                .packet
+                .into_iter()
+                .filter_map(|packet| match packet.data {
+                    Some(idl::trace_packet::Data::Trigger(trigger)) => trigger.trigger_name,
+                    _ => None,
+                })
+                .collect()
+        };
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "before", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "after", track_event::Type::SliceEnd),
+            0
+        );
+        assert_eq!(triggers(&extra_writer), ["oom_suspect"]);
+
+        // the next snapshot picks up what was recorded since
+        crate::trigger("slow_request");
+        let events = track_events(&extra_writer);
+        assert_eq!(
+            count_named(&events, "before", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(
+            count_named(&events, "after", track_event::Type::SliceEnd),
+            1
+        );
+        assert_eq!(triggers(&extra_writer), ["oom_suspect", "slow_request"]);
+    }
+
+    #[cfg(feature = "callstacks")]
+    #[test]
+    fn test_callstacks() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer)
+            .with_callstacks(true)
+            .with_callstack_level(tracing::Level::WARN);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        record_callstacks();
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let samples: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|packet| matches!(packet.data, Some(idl::trace_packet::Data::PerfSample(_))))
+            .collect();
+        // the marked event and the warning
+        assert_eq!(samples.len(), 2);
+        for sample in samples {
+            let interned = sample.interned_data.as_ref().unwrap();
+            let names: Vec<_> = interned
+                .function_names
+                .iter()
+                .map(|name| String::from_utf8_lossy(name.str()).into_owned())
+                .collect();
+            assert!(
+                names.iter().any(|name| name.ends_with("record_callstacks")),
+                "{names:?}"
+            );
+            assert!(
+                !names.iter().any(|name| name.starts_with("tracing_core::")),
+                "{names:?}"
+            );
+            assert_eq!(
+                interned.callstacks[0].frame_ids.len(),
+                interned.frames.len()
+            );
+        }
+    }
+
+    #[cfg(all(feature = "profiler", target_os = "linux"))]
+    #[inline(never)]
+    fn burn_cpu() -> u64 {
+        let start = std::time::Instant::now();
+        let mut x = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(300) {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+        x
+    }
+
+    #[cfg(all(feature = "profiler", target_os = "linux"))]
+    #[test]
+    fn test_cpu_profiler() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_cpu_profiler(1000);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        // the profiler stops and writes its last samples when the subscriber is dropped
+        tracing::subscriber::with_default(subscriber, || {
+            // only one profiler runs at a time, the others report why they didn't start
+            let second = TestWriter::new();
+            let second_writer = second.make_writer();
+            drop(
+                tracing_subscriber::registry()
+                    .with(PerfettoLayer::new(second).with_cpu_profiler(1000)),
+            );
+            assert_eq!(
+                count_named(
+                    &track_events(&second_writer),
+                    "warning",
+                    track_event::Type::Instant
+                ),
+                1
+            );
+            burn_cpu();
+        });
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let tid = thread_id::get() as u32;
+        let stacks: Vec<Vec<String>> = trace
+            .packet
+            .iter()
+            .filter(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::PerfSample(sample)) => sample.tid() == tid,
+                _ => false,
+            })
+            .map(|packet| {
+                let interned = packet.interned_data.as_ref().unwrap();
+                interned
+                    .function_names
+                    .iter()
+                    .map(|name| String::from_utf8_lossy(name.str()).into_owned())
+                    .collect()
+            })
+            .collect();
+        assert!(!stacks.is_empty());
+        let in_burn_cpu = stacks
+            .iter()
+            .filter(|names| names.iter().any(|name| name.ends_with("burn_cpu")))
+            .count();
+        assert!(
+            in_burn_cpu * 2 > stacks.len(),
+            "{in_burn_cpu}/{}",
+            stacks.len()
+        );
+        assert!(!stacks
+            .iter()
+            .any(|names| names.iter().any(|name| name.contains("on_sigprof"))));
+
+        // the previous `SIGPROF` action is back once the profiler stopped
+        #[allow(unsafe_code)]
+        // SAFETY: only reads the current action into a zeroed sigaction.
+        let action = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGPROF, std::ptr::null(), &mut action);
+            action
+        };
+        assert_eq!(action.sa_sigaction, libc::SIG_DFL);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_counters() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer =
+            PerfettoLayer::new(writer).with_process_counters(std::time::Duration::from_millis(10));
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        tracing::subscriber::with_default(subscriber, || {
+            std::thread::sleep(std::time::Duration::from_millis(50))
+        });
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let rss_track = trace
+            .packet
+            .iter()
+            .find_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(desc))
+                    if desc.display_name() == Some("mem.rss") =>
+                {
+                    Some(desc.clone())
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert!(rss_track.counter.is_some());
+        let rss: Vec<_> = track_events(&extra_writer)
+            .into_iter()
+            .filter(|event| event.track_uuid() == rss_track.uuid())
+            .collect();
+        assert!(rss.len() >= 2, "{}", rss.len());
+        assert!(rss.iter().all(|event| matches!(
+            event.counter_value_field,
+            Some(track_event::CounterValueField::CounterValue(v)) if v > 0
+        )));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_thread_cpu_time() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_thread_cpu_time(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let busy_for = |duration| {
+            let start = std::time::Instant::now();
+            while start.elapsed() < duration {
+                std::hint::black_box(start.elapsed());
+            }
+        };
+        trace_span!("busy").in_scope(|| busy_for(std::time::Duration::from_millis(30)));
+        trace_span!("sleeping")
+            .in_scope(|| std::thread::sleep(std::time::Duration::from_millis(30)));
+
+        let cpu_time = |name: &str| {
+            let events = track_events(&extra_writer);
+            let end = events
+                .iter()
+                .find(|e| {
+                    e.r#type() == track_event::Type::SliceEnd
+                        && e.name_field == Some(track_event::NameField::Name(name.to_string()))
+                })
+                .unwrap();
+            assert_eq!(end.extra_counter_track_uuids.len(), 1);
+            match end.debug_annotations[0].value {
+                Some(idl::debug_annotation::Value::UintValue(ns)) => ns,
+                _ => panic!("no thread_cpu_time_ns"),
+            }
+        };
+        assert!(cpu_time("busy") > 20_000_000);
+        assert!(cpu_time("sleeping") < 10_000_000);
+    }
+
+    #[cfg(feature = "allocator")]
+    #[global_allocator]
+    static ALLOCATOR: crate::PerfettoAllocator = crate::PerfettoAllocator::new(std::alloc::System);
+
+    #[cfg(feature = "allocator")]
+    #[test]
+    fn test_allocations() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer)
+            .with_allocations(true)
+            .with_heap_counter(true);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        trace_span!("allocating").in_scope(|| {
+            let buf = std::hint::black_box(vec![0u8; 1 << 20]);
+            drop(buf);
+        });
+
+        let events = track_events(&extra_writer);
+        let end = events
+            .iter()
+            .find(|e| e.r#type() == track_event::Type::SliceEnd)
+            .unwrap();
+        let annotation = |name: &str| {
+            end.debug_annotations
+                .iter()
+                .find_map(|a| match (&a.name_field, &a.value) {
+                    (
+                        Some(idl::debug_annotation::NameField::Name(n)),
+                        Some(idl::debug_annotation::Value::UintValue(v)),
+                    ) if n == name => Some(*v),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert!(annotation("allocated_bytes") >= 1 << 20);
+        assert!(annotation("freed_bytes") >= 1 << 20);
+        assert!(annotation("allocations") >= 1);
+        assert_eq!(end.extra_counter_values.len(), 1);
+        assert!(end.extra_counter_values[0] > 0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_integration() {
+        use tracing::instrument::WithSubscriber;
+
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        crate::tokio::instrument_runtime(builder.worker_threads(2));
+        let runtime = builder.enable_all().build().unwrap();
+        // workers are registered as they first park, once started with nothing to do yet
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer).with_tokio_metrics(
+            runtime.handle().clone(),
+            std::time::Duration::from_millis(10),
+        );
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        tracing::subscriber::with_default(subscriber, || {
+            runtime.block_on(async {
+                crate::tokio::spawn_named("named task", async { tracing::info!("in task") })
+                    .await
+                    .unwrap();
+                tokio::spawn(async { tracing::info!("on worker") }.with_current_subscriber())
+                    .await
+                    .unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+            })
+        });
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let descriptors: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(desc)) => Some(desc),
+                _ => None,
+            })
+            .collect();
+        let find = |name: &str| {
+            descriptors
+                .iter()
+                .find(|desc| {
+                    desc.display_name() == Some(name)
+                        || desc.thread.as_ref().and_then(|t| t.thread_name.as_deref()) == Some(name)
+                })
+                .copied()
+        };
+
+        let task_track = find("named task").unwrap();
+        assert!(track_events(&extra_writer).iter().any(
+            |e| e.track_uuid() == task_track.uuid() && e.r#type() == track_event::Type::Instant
+        ));
+
+        // with `tokio_unstable`, the events of the tasks land on their own tracks instead
+        #[cfg(not(tokio_unstable))]
+        {
+            let group = find("tokio runtime").unwrap();
+            let worker = descriptors
+                .iter()
+                .find(|desc| {
+                    desc.thread
+                        .as_ref()
+                        .and_then(|t| t.thread_name.as_deref())
+                        .is_some_and(|name| name.starts_with("tokio-worker-"))
+                })
+                .unwrap();
+            assert_eq!(worker.parent_uuid, Some(group.uuid()));
+            assert!(worker.sibling_order_rank.unwrap() < 2);
+        }
+
+        assert!(find("tokio.alive_tasks").unwrap().counter.is_some());
+        assert!(find("tokio.worker.1.busy_percent").is_some());
+    }
+
+    // Run with `RUSTFLAGS="--cfg tokio_unstable"`
+    #[cfg(all(feature = "tokio", tokio_unstable))]
+    #[test]
+    fn test_tokio_task_tracks() {
+        use tracing::instrument::WithSubscriber;
+
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        crate::tokio::instrument_runtime(builder.worker_threads(2));
+        let runtime = builder.enable_all().build().unwrap();
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let perfetto_layer = PerfettoLayer::new(writer);
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        tracing::subscriber::with_default(subscriber, || {
+            runtime.block_on(async {
+                for _ in 0..2 {
+                    tokio::spawn(async { tracing::info!("in task") }.with_current_subscriber())
+                        .await
+                        .unwrap();
+                }
+            })
+        });
+        drop(runtime);
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let task_tracks: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(desc))
+                    if desc
+                        .display_name()
+                        .is_some_and(|name| name.starts_with("task src/lib.rs:")) =>
+                {
+                    Some(desc.uuid())
+                }
+                _ => None,
+            })
+            .collect();
+        assert!(!task_tracks.is_empty());
+        let events = track_events(&extra_writer);
+        let on_task_tracks = |kind| {
+            events
+                .iter()
+                .filter(|e| task_tracks.contains(&e.track_uuid()) && e.r#type() == kind)
+                .count()
+        };
+        assert_eq!(on_task_tracks(track_event::Type::Instant), 2);
+        assert_eq!(on_task_tracks(track_event::Type::SliceEnd), 2);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_rayon_workers() {
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(PerfettoLayer::new(writer)));
+        let builder = rayon::ThreadPoolBuilder::new().num_threads(3);
+        let pool = crate::thread_pool::instrument_rayon(builder, "compute")
+            .build()
+            .unwrap();
+        pool.broadcast(|_| {
+            tracing::dispatcher::with_default(&dispatch, || tracing::info!("on worker"))
+        });
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let descriptors: Vec<_> = trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(desc)) => Some(desc),
+                _ => None,
+            })
+            .collect();
+        let group = descriptors
+            .iter()
+            .find(|desc| desc.display_name() == Some("compute"))
+            .unwrap();
+        for index in 0..3 {
+            let name = format!("compute-{index}");
+            let worker = descriptors
+                .iter()
+                .find(|desc| desc.display_name() == Some(name.as_str()))
+                .unwrap();
+            assert_eq!(worker.thread.as_ref().unwrap().thread_name(), name);
+            assert_eq!(worker.parent_uuid, Some(group.uuid()));
+            assert_eq!(worker.sibling_order_rank, Some(index));
+        }
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_log_bridge() {
+        use ::log::Log;
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let logger = PerfettoLayer::new(writer)
+            .log_bridge()
+            .with_max_level(::log::LevelFilter::Info);
+        let record = |level| {
+            ::log::Record::builder()
+                .args(format_args!("connection reset"))
+                .level(level)
+                .target("hyper::proto")
+                .module_path_static(Some("hyper::proto::h1"))
+                .file_static(Some("src/proto/h1/io.rs"))
+                .line(Some(42))
+                .build()
+        };
+        logger.log(&record(::log::Level::Warn));
+        logger.log(&record(::log::Level::Debug));
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let thread_track = trace
+            .packet
+            .iter()
+            .find_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(desc)) if desc.thread.is_some() => {
+                    Some(desc.uuid())
+                }
+                _ => None,
+            })
+            .unwrap();
+        let packets: Vec<_> = trace
+            .packet
+            .iter()
+            .filter(|packet| {
+                matches!(&packet.data, Some(idl::trace_packet::Data::TrackEvent(event))
+                    if event.log_message.is_some())
+            })
+            .collect();
+        assert_eq!(packets.len(), 1, "debug records are filtered out");
+
+        let Some(idl::trace_packet::Data::TrackEvent(event)) = &packets[0].data else {
+            unreachable!()
+        };
+        assert_eq!(event.track_uuid(), thread_track);
+        assert_eq!(event.categories, ["hyper::proto"]);
+        let message = event.log_message.as_ref().unwrap();
+        assert_eq!(message.prio(), idl::log_message::Priority::PrioWarn);
+
+        let interned = packets[0].interned_data.as_ref().unwrap();
+        let body = &interned.log_message_body[0];
+        assert_eq!(body.iid, message.body_iid);
+        assert_eq!(body.body(), "connection reset");
+        let location = &interned.source_locations[0];
+        assert_eq!(location.iid, message.source_location_iid);
+        assert_eq!(location.file_name(), "src/proto/h1/io.rs");
+        assert_eq!(location.function_name(), "hyper::proto::h1");
+        assert_eq!(location.line_number(), 42);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_recorder() {
+        use ::metrics::Recorder;
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let recorder = PerfettoLayer::new(writer).metrics_recorder();
+        let metadata = ::metrics::Metadata::new(module_path!(), ::metrics::Level::INFO, None);
+        recorder.describe_gauge(
+            "queue.depth".into(),
+            Some(::metrics::Unit::Count),
+            "jobs waiting".into(),
+        );
+
+        let key =
+            ::metrics::Key::from_parts("jobs.done", vec![::metrics::Label::new("kind", "io")]);
+        recorder.register_counter(&key, &metadata).increment(2);
+        recorder.register_counter(&key, &metadata).increment(3);
+        let gauge = recorder.register_gauge(&"queue.depth".into(), &metadata);
+        gauge.set(4.0);
+        gauge.decrement(1.5);
+        let histogram = recorder.register_histogram(&"latency".into(), &metadata);
+        histogram.record(0.25);
+
+        let trace = idl::Trace::decode(extra_writer.buf.lock().unwrap().as_slice()).unwrap();
+        let track = |name: &str| {
+            trace
+                .packet
+                .iter()
+                .find_map(|packet| match &packet.data {
+                    Some(idl::trace_packet::Data::TrackDescriptor(desc))
+                        if desc.display_name() == Some(name) =>
+                    {
+                        Some(desc.clone())
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let values = |track: &idl::TrackDescriptor| -> Vec<_> {
+            track_events(&extra_writer)
+                .into_iter()
+                .filter(|e| e.track_uuid() == track.uuid())
+                .map(|e| {
+                    assert_eq!(e.r#type(), track_event::Type::Counter);
+                    e.counter_value_field.unwrap()
+                })
+                .collect()
+        };
+        use track_event::CounterValueField::{CounterValue, DoubleCounterValue};
+
+        let counter = track("jobs.done{kind=io}");
+        assert_eq!(values(&counter), [CounterValue(2), CounterValue(5)]);
+
+        let gauge = track("queue.depth");
+        assert_eq!(
+            gauge.counter.as_ref().unwrap().unit(),
+            idl::counter_descriptor::Unit::Count
+        );
+        assert_eq!(
+            values(&gauge),
+            [DoubleCounterValue(4.0), DoubleCounterValue(2.5)]
+        );
+
+        assert_eq!(values(&track("latency")), [DoubleCounterValue(0.25)]);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn test_opentelemetry_processor() {
+        use ::opentelemetry::trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer,
+            TracerProvider,
+        };
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let provider = ::opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_span_processor(PerfettoLayer::new(writer).opentelemetry_processor())
+            .build();
+        let tracer = provider.tracer("test");
+
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+        let remote_parent = SpanId::from_hex("b7ad6b7169203331").unwrap();
+        let cx = ::opentelemetry::Context::new().with_remote_span_context(SpanContext::new(
+            trace_id,
+            remote_parent,
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+        tracer.in_span_with_context("server", &cx, |_cx| {
+            tracer.in_span("child", |cx| {
+                cx.span()
+                    .add_event("retry", vec![::opentelemetry::KeyValue::new("attempt", 2)]);
+            });
+        });
+        drop(provider);
+
+        let events = track_events(&extra_writer);
+        let begin = |name: &str| {
+            events
+                .iter()
+                .find(|e| {
+                    e.r#type() == track_event::Type::SliceBegin
+                        && e.name_field == Some(track_event::NameField::Name(name.to_string()))
+                })
+                .unwrap()
+        };
+        let annotation = |event: &idl::TrackEvent, name: &str| {
+            event
+                .debug_annotations
+                .iter()
+                .find(|a| {
+                    a.name_field == Some(idl::debug_annotation::NameField::Name(name.to_string()))
+                })
+                .and_then(|a| a.value.clone())
+        };
+        let string =
+            |value: &str| Some(idl::debug_annotation::Value::StringValue(value.to_string()));
+
+        let server = begin("server");
+        let child = begin("child");
+        assert_eq!(server.track_uuid(), child.track_uuid());
+        assert_eq!(
+            annotation(server, "trace_id"),
+            string("0af7651916cd43dd8448eb211c80319c")
+        );
+        assert_eq!(
+            annotation(server, "parent_span_id"),
+            string("b7ad6b7169203331")
+        );
+        assert_eq!(
+            annotation(child, "trace_id"),
+            annotation(server, "trace_id")
+        );
+        assert_eq!(
+            annotation(child, "parent_span_id"),
+            annotation(server, "span_id")
+        );
+        assert_eq!(server.terminating_flow_ids.len(), 1);
+        assert!(child.terminating_flow_ids.is_empty());
+        assert_eq!(server.flow_ids.len(), 1);
+        assert_ne!(server.flow_ids, child.flow_ids);
+
+        let retry = events
+            .iter()
+            .find(|e| e.r#type() == track_event::Type::Instant)
+            .unwrap();
+        assert_eq!(count_named(&events, "retry", track_event::Type::Instant), 1);
+        assert_eq!(retry.track_uuid(), child.track_uuid());
+        assert_eq!(
+            annotation(retry, "attempt"),
+            Some(idl::debug_annotation::Value::IntValue(2))
+        );
+        assert_eq!(
+            count_named(&events, "server", track_event::Type::SliceEnd),
+            1
+        );
+    }
+
+    // Concurrent sibling spans go to lanes of the trace's track, and spans ending after their
+    // root still find its tracks
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn test_opentelemetry_lanes() {
+        use ::opentelemetry::trace::{Span, TraceContextExt, Tracer, TracerProvider};
+        use std::time::{Duration, SystemTime};
+
+        let writer = TestWriter::new();
+        let extra_writer = writer.make_writer();
+        let provider = ::opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_span_processor(PerfettoLayer::new(writer).opentelemetry_processor())
+            .build();
+        let tracer = provider.tracer("test");
+
+        let t0 = SystemTime::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let root = tracer
+            .span_builder("root")
+            .with_start_time(at(0))
+            .start(&tracer);
+        let cx = ::opentelemetry::Context::current_with_span(root);
+        let child = |name: &'static str, start: u64| {
+            tracer
+                .span_builder(name)
+                .with_start_time(at(start))
+                .start_with_context(&tracer, &cx)
+        };
+        let (mut first, mut second, mut detached) =
+            (child("first", 1), child("second", 3), child("detached", 2));
+        first.end_with_timestamp(at(5));
+        second.end_with_timestamp(at(8));
+        cx.span().end_with_timestamp(at(10));
+        detached.end_with_timestamp(at(4));
+        drop(provider);
+
+        let events = track_events(&extra_writer);
+        let track = |name: &str| {
+            events
+                .iter()
+                .find(|e| {
+                    e.r#type() == track_event::Type::SliceBegin
+                        && e.name_field == Some(track_event::NameField::Name(name.to_string()))
+                })
+                .unwrap()
+                .track_uuid()
+        };
+        assert_eq!(track("first"), track("root"));
+        assert_ne!(track("second"), track("root"));
+        assert_eq!(track("detached"), track("root"));
+    }
 }
@@ -0,0 +1,151 @@
+//! Bridge from the `log` crate, behind the `log` feature.
+//!
+//! [`PerfettoLogger`] writes `log` records straight into the trace as Perfetto `LogMessage`s,
+//! with their level, target and source location, on the track of the thread that logged them.
+//! Unlike going through `tracing-log`, they show up as log messages in the Perfetto UI rather
+//! than as generic events.
+//!
+//! ```rust
+//! use tracing_perfetto::PerfettoLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let layer = PerfettoLayer::new(std::io::sink);
+//! layer.log_bridge().init().unwrap();
+//! tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer)).unwrap();
+//!
+//! log::warn!(target: "dependency", "retrying");
+//! ```
+//!
+//! `tracing_subscriber`'s `init` installs `tracing-log`'s `LogTracer` as the logger, which
+//! conflicts with this one: set the subscriber with `set_global_default` instead.
+
+use crate::idl;
+use crate::idl_helpers::{current_thread_uuid, unique_uuid};
+use ::log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use idl::log_message::Priority;
+
+/// A `log::Log` implementation writing records into the trace of a [`PerfettoLayer`], created
+/// by [`PerfettoLayer::log_bridge`].
+///
+/// [`PerfettoLayer`]: crate::PerfettoLayer
+/// [`PerfettoLayer::log_bridge`]: crate::PerfettoLayer::log_bridge
+pub struct PerfettoLogger {
+    sink: Box<dyn Fn(idl::Trace) + Send + Sync>,
+    sequence_id: u32,
+    max_level: LevelFilter,
+}
+
+impl PerfettoLogger {
+    /// `sink` writes the records on the track of the current thread.
+    pub(crate) fn new(sink: impl Fn(idl::Trace) + Send + Sync + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            sequence_id: unique_uuid() as _,
+            max_level: LevelFilter::Trace,
+        }
+    }
+
+    /// Configures the most verbose level recorded, `Trace` by default.
+    pub fn with_max_level(mut self, level: LevelFilter) -> Self {
+        self.max_level = level;
+        self
+    }
+
+    /// Installs this logger as the logger of the `log` crate, and sets its maximum level.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = self.max_level;
+        ::log::set_boxed_logger(Box::new(self))?;
+        ::log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for PerfettoLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerfettoLogger")
+            .field("max_level", &self.max_level)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Log for PerfettoLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.max_level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = chrono::Local::now().timestamp_nanos_opt().map(|t| t as u64);
+        let packet = log_packet(record, timestamp, self.sequence_id);
+        (self.sink)(idl::Trace {
+            packet: vec![packet],
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn priority(level: Level) -> Priority {
+    match level {
+        Level::Error => Priority::PrioError,
+        Level::Warn => Priority::PrioWarn,
+        Level::Info => Priority::PrioInfo,
+        Level::Debug => Priority::PrioDebug,
+        Level::Trace => Priority::PrioVerbose,
+    }
+}
+
+/// An instant on the current thread's track carrying the `LogMessage` of `record`. The packet is
+/// self-contained: it interns its body and source location, clearing the state of the sequence.
+fn log_packet(record: &Record<'_>, timestamp: Option<u64>, sequence_id: u32) -> idl::TracePacket {
+    let body = record.args().to_string();
+    let mut interned = idl::InternedData {
+        log_message_body: vec![idl::LogMessageBody {
+            iid: Some(1),
+            body: Some(body.clone()),
+        }],
+        ..Default::default()
+    };
+    let mut message = idl::LogMessage {
+        body_iid: Some(1),
+        ..Default::default()
+    };
+    message.set_prio(priority(record.level()));
+    if let Some(file) = record.file() {
+        interned.source_locations.push(idl::SourceLocation {
+            iid: Some(1),
+            file_name: Some(file.to_string()),
+            function_name: record.module_path().map(str::to_string),
+            line_number: record.line(),
+        });
+        message.source_location_iid = Some(1);
+    }
+
+    let mut event = idl::TrackEvent {
+        track_uuid: Some(current_thread_uuid()),
+        categories: vec![record.target().to_string()],
+        name_field: Some(idl::track_event::NameField::Name(body)),
+        log_message: Some(message),
+        ..Default::default()
+    };
+    event.set_type(idl::track_event::Type::Instant);
+
+    idl::TracePacket {
+        timestamp,
+        trusted_pid: Some(std::process::id() as _),
+        optional_trusted_packet_sequence_id: Some(
+            idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                sequence_id,
+            ),
+        ),
+        interned_data: Some(interned),
+        sequence_flags: Some(
+            idl::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32
+                | idl::trace_packet::SequenceFlags::SeqNeedsIncrementalState as u32,
+        ),
+        data: Some(idl::trace_packet::Data::TrackEvent(event)),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,229 @@
+//! `metrics` crate recorder, behind the `metrics` feature.
+//!
+//! [`PerfettoRecorder`] turns every counter, gauge and histogram into a counter track under the
+//! process track, named after the metric and its labels, e.g. `requests{method=GET}`. Each update
+//! is written right away as a `TYPE_COUNTER` event, on the same timeline as the spans:
+//!
+//! - counters are written as their running total,
+//! - gauges as their current value,
+//! - histograms as one sample per recorded value.
+//!
+//! ```rust
+//! use tracing_perfetto::PerfettoLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let layer = PerfettoLayer::new(std::io::sink);
+//! metrics::set_global_recorder(layer.metrics_recorder()).unwrap();
+//! tracing_subscriber::registry().with(layer).init();
+//!
+//! metrics::describe_gauge!("queue.depth", metrics::Unit::Count, "jobs waiting");
+//! metrics::gauge!("queue.depth", "queue" => "default").set(3.0);
+//! metrics::counter!("jobs.done").increment(1);
+//! ```
+//!
+//! Units given to `describe_*` apply to the metrics registered after them.
+
+use crate::background::counter_trace;
+use crate::idl;
+use crate::idl_helpers::unique_uuid;
+use ::metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use idl::counter_descriptor::Unit as TrackUnit;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Sink = Arc<dyn Fn(idl::Trace) + Send + Sync>;
+
+/// A `metrics::Recorder` writing metrics as counter tracks into the trace of a
+/// [`PerfettoLayer`], created by [`PerfettoLayer::metrics_recorder`].
+///
+/// [`PerfettoLayer`]: crate::PerfettoLayer
+/// [`PerfettoLayer::metrics_recorder`]: crate::PerfettoLayer::metrics_recorder
+pub struct PerfettoRecorder {
+    sink: Sink,
+    process_track_uuid: u64,
+    sequence_id: u32,
+    units: Mutex<HashMap<KeyName, Unit>>,
+    tracks: Mutex<HashMap<Key, Arc<MetricTrack>>>,
+}
+
+impl PerfettoRecorder {
+    /// `sink` writes the counters, whose tracks are children of `process_track_uuid`.
+    pub(crate) fn new(
+        process_track_uuid: u64,
+        sink: impl Fn(idl::Trace) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            sink: Arc::new(sink),
+            process_track_uuid,
+            sequence_id: unique_uuid() as _,
+            units: Mutex::default(),
+            tracks: Mutex::default(),
+        }
+    }
+
+    fn describe(&self, key: KeyName, unit: Option<Unit>) {
+        if let Some(unit) = unit {
+            self.units
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(key, unit);
+        }
+    }
+
+    /// The track of `key`, the same for every registration of the metric.
+    fn track(&self, key: &Key) -> Arc<MetricTrack> {
+        let mut tracks = self.tracks.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(track) = tracks.get(key) {
+            return track.clone();
+        }
+
+        let unit = self
+            .units
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key.name())
+            .copied();
+        let track = Arc::new(MetricTrack {
+            descriptor: track_descriptor(key, unit, self.process_track_uuid),
+            value: AtomicU64::new(0),
+            sink: self.sink.clone(),
+            sequence_id: self.sequence_id,
+        });
+        tracks.insert(key.clone(), track.clone());
+        track
+    }
+}
+
+impl std::fmt::Debug for PerfettoRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerfettoRecorder")
+            .field("process_track_uuid", &self.process_track_uuid)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Recorder for PerfettoRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, _: SharedString) {
+        self.describe(key, unit);
+    }
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.track(key))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.track(key))
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(self.track(key))
+    }
+}
+
+/// The counter track of one metric, along with its current value: the total of a counter, or
+/// the bits of the `f64` of a gauge.
+struct MetricTrack {
+    descriptor: idl::TrackDescriptor,
+    value: AtomicU64,
+    sink: Sink,
+    sequence_id: u32,
+}
+
+impl MetricTrack {
+    fn write(&self, value: impl Into<idl::track_event::CounterValueField>) {
+        (self.sink)(counter_trace(
+            std::slice::from_ref(&self.descriptor),
+            [Some(value)],
+            self.sequence_id,
+        ));
+    }
+
+    fn update_gauge(&self, update: impl Fn(f64) -> f64) {
+        let previous = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(update(f64::from_bits(bits)).to_bits())
+            })
+            .expect("the update always succeeds");
+        self.write(update(f64::from_bits(previous)));
+    }
+}
+
+impl CounterFn for MetricTrack {
+    fn increment(&self, value: u64) {
+        let total = self
+            .value
+            .fetch_add(value, Ordering::Relaxed)
+            .wrapping_add(value);
+        self.write(total as i64);
+    }
+
+    fn absolute(&self, value: u64) {
+        let total = self.value.fetch_max(value, Ordering::Relaxed).max(value);
+        self.write(total as i64);
+    }
+}
+
+impl GaugeFn for MetricTrack {
+    fn increment(&self, value: f64) {
+        self.update_gauge(|gauge| gauge + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update_gauge(|gauge| gauge - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.value.store(value.to_bits(), Ordering::Relaxed);
+        self.write(value);
+    }
+}
+
+impl HistogramFn for MetricTrack {
+    fn record(&self, value: f64) {
+        self.write(value);
+    }
+}
+
+/// The counter track of `key`, named after the metric and its labels.
+fn track_descriptor(
+    key: &Key,
+    unit: Option<Unit>,
+    process_track_uuid: u64,
+) -> idl::TrackDescriptor {
+    let mut name = key.name().to_string();
+    let labels: Vec<_> = key
+        .labels()
+        .map(|label| format!("{}={}", label.key(), label.value()))
+        .collect();
+    if !labels.is_empty() {
+        name = format!("{name}{{{}}}", labels.join(","));
+    }
+
+    let track_unit = match unit {
+        Some(Unit::Count) => TrackUnit::Count,
+        Some(Unit::Bytes) => TrackUnit::SizeBytes,
+        Some(Unit::Nanoseconds) => TrackUnit::TimeNs,
+        _ => TrackUnit::Unspecified,
+    };
+    let mut descriptor =
+        idl::TrackDescriptor::counter_child_for(&name, process_track_uuid, track_unit);
+    if let (TrackUnit::Unspecified, Some(unit)) = (track_unit, unit) {
+        if let Some(counter) = descriptor.counter.as_mut() {
+            counter.unit_name = Some(unit.as_canonical_label().to_string());
+        }
+    }
+    descriptor
+}
@@ -0,0 +1,299 @@
+//! The spans currently open, for the panic hook and the snapshots to reach them.
+//!
+//! The registry has no way to list its spans, so the layer keeps the ids of those it records,
+//! along with the track they are on: a span opened on another thread has to be reported on
+//! that thread's track. It also keeps the spans entered on each thread, which are the ones a
+//! panic unwinds through.
+
+use crate::idl;
+use crate::idl_helpers::{create_event, string_annotation, DebugAnnotations};
+use crate::PerfettoSpanState;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime};
+use tracing::dispatcher::WeakDispatch;
+use tracing::{span, Dispatch, Subscriber};
+use tracing_subscriber::registry::LookupSpan;
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<span::Id>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Looks the spans up in the subscriber of a dispatcher.
+type Collect = fn(&Dispatch, &OpenSpans, u64) -> Vec<(idl::TrackDescriptor, OpenSpan)>;
+
+/// The recorded spans which are still open, with the track of each.
+#[derive(Default)]
+pub(crate) struct OpenSpans {
+    spans: Mutex<HashMap<span::Id, idl::TrackDescriptor>>,
+    /// The dispatcher the layer is registered in.
+    registry: OnceLock<(WeakDispatch, Collect)>,
+}
+
+impl OpenSpans {
+    /// Remembers the dispatcher of the layer, whose subscriber `S` holds the spans.
+    pub fn register<S>(&self, dispatch: &Dispatch)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        _ = self.registry.set((dispatch.downgrade(), collect::<S>));
+    }
+
+    pub fn opened(&self, id: &span::Id, track: idl::TrackDescriptor) {
+        self.spans().insert(id.clone(), track);
+    }
+
+    pub fn closed(&self, id: &span::Id) {
+        self.spans().remove(id);
+    }
+
+    pub fn entered(&self, id: &span::Id) {
+        ENTERED.with_borrow_mut(|entered| entered.push(id.clone()));
+    }
+
+    pub fn exited(&self, id: &span::Id) {
+        ENTERED.with_borrow_mut(|entered| {
+            if let Some(index) = entered.iter().rposition(|entered| entered == id) {
+                entered.remove(index);
+            }
+        });
+    }
+
+    /// Takes the open spans entered on the current thread, innermost first, which won't be
+    /// closed by the layer anymore.
+    ///
+    /// Spans open on other threads are left alone: the panic may be caught, and those threads
+    /// keep running.
+    pub fn take_entered(&self) -> Vec<(span::Id, idl::TrackDescriptor)> {
+        let entered = ENTERED.with_borrow(|entered| entered.clone());
+        let mut spans = self.spans();
+        entered
+            .iter()
+            .rev()
+            .filter_map(|id| spans.remove_entry(id))
+            .collect()
+    }
+
+    /// The open spans grouped by track, outermost first, as of `now` in nanoseconds.
+    pub fn snapshot(&self, now: u64) -> Vec<OpenSpanStack> {
+        let Some((dispatch, collect)) = self.registry.get() else {
+            return Vec::new();
+        };
+        let Some(dispatch) = dispatch.upgrade() else {
+            return Vec::new();
+        };
+
+        let mut tracks: BTreeMap<u64, OpenSpanStack> = BTreeMap::new();
+        for (track, span) in collect(&dispatch, self, now) {
+            tracks
+                .entry(track.uuid())
+                .or_insert_with(|| OpenSpanStack {
+                    track,
+                    spans: Vec::new(),
+                })
+                .spans
+                .push(span);
+        }
+        let mut stacks: Vec<_> = tracks.into_values().collect();
+        for stack in &mut stacks {
+            stack.spans.sort_by_key(|span| (span.depth, span.start));
+        }
+        stacks
+    }
+
+    fn spans(&self) -> MutexGuard<'_, HashMap<span::Id, idl::TrackDescriptor>> {
+        self.spans.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Looks the spans of `open_spans` up in the subscriber `S` of `dispatch`.
+fn collect<S>(
+    dispatch: &Dispatch,
+    open_spans: &OpenSpans,
+    now: u64,
+) -> Vec<(idl::TrackDescriptor, OpenSpan)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(subscriber) = dispatch.downcast_ref::<S>() else {
+        return Vec::new();
+    };
+    let ids: Vec<_> = open_spans.spans().clone().into_iter().collect();
+    ids.into_iter()
+        .filter_map(|(id, track)| {
+            let span = subscriber.span(&id)?;
+            let extensions = span.extensions();
+            let state = extensions.get::<PerfettoSpanState>()?;
+            let args = match &state.begin[0].data {
+                Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                    annotations_to_args(&event.debug_annotations)
+                }
+                _ => Default::default(),
+            };
+            let span = OpenSpan {
+                name: span.name(),
+                start: state.start,
+                open_for: Duration::from_nanos(now.saturating_sub(state.start)),
+                depth: span.scope().count() - 1,
+                args,
+            };
+            Some((track, span))
+        })
+        .collect()
+}
+
+/// The spans open on a thread, or on a named track, at the time of
+/// [`PerfettoLayer::open_spans_snapshot`](crate::PerfettoLayer::open_spans_snapshot).
+#[derive(Clone, Debug)]
+pub struct OpenSpanStack {
+    track: idl::TrackDescriptor,
+    spans: Vec<OpenSpan>,
+}
+
+impl OpenSpanStack {
+    /// The name of the thread, or of the track set by `perfetto.track_name`.
+    pub fn track_name(&self) -> Option<&str> {
+        match &self.track.static_or_dynamic_name {
+            Some(idl::track_descriptor::StaticOrDynamicName::Name(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The id of the thread, for the spans on a thread's track.
+    pub fn thread_id(&self) -> Option<i32> {
+        self.track.thread.as_ref().map(|thread| thread.tid())
+    }
+
+    /// The open spans, outermost first.
+    pub fn spans(&self) -> &[OpenSpan] {
+        &self.spans
+    }
+}
+
+/// A span open at the time of a snapshot.
+#[derive(Clone, Debug)]
+pub struct OpenSpan {
+    name: &'static str,
+    /// Timestamp of its `TYPE_SLICE_BEGIN`, in nanoseconds.
+    start: u64,
+    open_for: Duration,
+    /// Number of open ancestors, recorded or not.
+    depth: usize,
+    args: BTreeMap<String, ArgValue>,
+}
+
+impl OpenSpan {
+    /// The name of the span.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// When the span was opened.
+    pub fn start(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(self.start)
+    }
+
+    /// How long the span had been open for when the snapshot was taken.
+    pub fn open_for(&self) -> Duration {
+        self.open_for
+    }
+
+    /// The fields of the span, as recorded with
+    /// [`PerfettoLayer::with_debug_annotations`](crate::PerfettoLayer::with_debug_annotations).
+    pub fn args(&self) -> &BTreeMap<String, ArgValue> {
+        &self.args
+    }
+}
+
+/// The value of a field of an [`OpenSpan`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArgValue {
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Double(f64),
+    String(String),
+    Array(Vec<ArgValue>),
+    Dict(BTreeMap<String, ArgValue>),
+}
+
+fn annotations_to_args(annotations: &[idl::DebugAnnotation]) -> BTreeMap<String, ArgValue> {
+    annotations
+        .iter()
+        .filter_map(|annotation| {
+            let name = match &annotation.name_field {
+                Some(idl::debug_annotation::NameField::Name(name)) => name.clone(),
+                Some(idl::debug_annotation::NameField::NameIid(iid)) => iid.to_string(),
+                None => String::new(),
+            };
+            Some((name, annotation_value(annotation)?))
+        })
+        .collect()
+}
+
+fn annotation_value(annotation: &idl::DebugAnnotation) -> Option<ArgValue> {
+    use idl::debug_annotation::Value as V;
+
+    if !annotation.dict_entries.is_empty() {
+        return Some(ArgValue::Dict(annotations_to_args(
+            &annotation.dict_entries,
+        )));
+    }
+    if !annotation.array_values.is_empty() {
+        return Some(ArgValue::Array(
+            annotation
+                .array_values
+                .iter()
+                .filter_map(annotation_value)
+                .collect(),
+        ));
+    }
+    Some(match annotation.value.as_ref()? {
+        V::BoolValue(v) => ArgValue::Bool(*v),
+        V::UintValue(v) => ArgValue::Uint(*v),
+        V::IntValue(v) => ArgValue::Int(*v),
+        V::DoubleValue(v) => ArgValue::Double(*v),
+        V::PointerValue(v) => ArgValue::String(format!("0x{v:x}")),
+        V::StringValue(v) | V::LegacyJsonValue(v) => ArgValue::String(v.clone()),
+        V::StringValueIid(iid) => ArgValue::Uint(*iid),
+        V::NestedValue(_) => return None,
+    })
+}
+
+/// Builds the marker of a snapshot taken at `now`: an `open spans` instant on the track of each
+/// of `stacks`, listing its spans, outermost first, with how long they have been open.
+pub(crate) fn snapshot_trace(stacks: Vec<OpenSpanStack>, now: u64, sequence_id: u32) -> idl::Trace {
+    let mut packet = Vec::new();
+    for stack in stacks {
+        let annotations = stack
+            .spans
+            .iter()
+            .map(|span| string_annotation(span.name, format!("open for {:?}", span.open_for)))
+            .collect();
+        let event = create_event(
+            stack.track.uuid(),
+            Some("open spans"),
+            None,
+            DebugAnnotations { annotations },
+            Some(idl::track_event::Type::Instant),
+        );
+        packet.push(idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackDescriptor(stack.track)),
+            ..Default::default()
+        });
+        packet.push(idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            timestamp: Some(now),
+            trusted_pid: Some(std::process::id() as _),
+            optional_trusted_packet_sequence_id: Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    sequence_id,
+                ),
+            ),
+            ..Default::default()
+        });
+    }
+    idl::Trace { packet }
+}
@@ -0,0 +1,260 @@
+//! OpenTelemetry bridge, behind the `opentelemetry` feature.
+//!
+//! [`PerfettoSpanProcessor`] is an `opentelemetry_sdk` span processor recording every finished
+//! OpenTelemetry span as a slice, with its `trace_id`, `span_id`, `parent_span_id`, kind, status
+//! and attributes as arguments, and its events as instants. The spans of a trace share a
+//! `trace {trace_id}` track under the process track, concurrent siblings going to lanes of it:
+//! sibling tracks of the same name, as their slices wouldn't nest.
+//!
+//! Each slice starts a flow keyed by its trace and span ids, which the slices of its remote
+//! children terminate: once the `.pftrace` files of several services are merged, the Perfetto UI
+//! draws an arrow from a client span to the server span it caused in another process.
+//!
+//! ```rust
+//! use opentelemetry::trace::{Tracer, TracerProvider};
+//! use tracing_perfetto::PerfettoLayer;
+//!
+//! let layer = PerfettoLayer::new(std::io::sink);
+//! let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+//!     .with_span_processor(layer.opentelemetry_processor())
+//!     .build();
+//!
+//! provider.tracer("checkout").in_span("charge card", |_cx| {});
+//! ```
+
+use crate::idl;
+use crate::idl_helpers::{create_event, string_annotation, unique_uuid, DebugAnnotations};
+use ::opentelemetry::trace::{Span as _, SpanId, Status, TraceId};
+use ::opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// An OpenTelemetry span processor writing finished spans into the trace of a
+/// [`PerfettoLayer`], created by [`PerfettoLayer::opentelemetry_processor`].
+///
+/// [`PerfettoLayer`]: crate::PerfettoLayer
+/// [`PerfettoLayer::opentelemetry_processor`]: crate::PerfettoLayer::opentelemetry_processor
+pub struct PerfettoSpanProcessor {
+    sink: Box<dyn Fn(idl::Trace) + Send + Sync>,
+    process_track_uuid: u64,
+    sequence_id: u32,
+    /// The tracks of each trace with spans still open in this process.
+    tracks: Mutex<HashMap<TraceId, TraceTracks>>,
+}
+
+/// The lanes of the track of a trace, with the slices written on each of them so far.
+#[derive(Default)]
+struct TraceTracks {
+    /// Spans of the trace started and not ended yet.
+    open: usize,
+    lanes: Vec<(idl::TrackDescriptor, Vec<(u64, u64)>)>,
+}
+
+impl PerfettoSpanProcessor {
+    /// `sink` writes the spans, whose tracks are children of `process_track_uuid`.
+    pub(crate) fn new(
+        process_track_uuid: u64,
+        sink: impl Fn(idl::Trace) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            sink: Box::new(sink),
+            process_track_uuid,
+            sequence_id: unique_uuid() as _,
+            tracks: Mutex::default(),
+        }
+    }
+
+    /// The track of an ended span of `trace_id` lasting from `start` to `end`: the first lane
+    /// of the trace's track where it nests with the slices already there. The lanes are
+    /// forgotten once no span of the trace is open anymore.
+    fn track(&self, trace_id: TraceId, start: u64, end: u64) -> idl::TrackDescriptor {
+        let mut tracks = self.tracks.lock().unwrap_or_else(|e| e.into_inner());
+        let trace = tracks.entry(trace_id).or_default();
+        let nests = |&(other_start, other_end): &(u64, u64)| {
+            end <= other_start
+                || other_end <= start
+                || (other_start <= start && end <= other_end)
+                || (start <= other_start && other_end <= end)
+        };
+        let lane = match trace
+            .lanes
+            .iter()
+            .position(|(_, slices)| slices.iter().all(nests))
+        {
+            Some(lane) => lane,
+            None => {
+                let track = idl::TrackDescriptor::named_child_for(
+                    &format!("trace {trace_id}"),
+                    self.process_track_uuid,
+                );
+                trace.lanes.push((track, Vec::new()));
+                trace.lanes.len() - 1
+            }
+        };
+        let (track, slices) = &mut trace.lanes[lane];
+        slices.push((start, end));
+        let track = track.clone();
+
+        trace.open = trace.open.saturating_sub(1);
+        if trace.open == 0 {
+            tracks.remove(&trace_id);
+        }
+        track
+    }
+
+    fn packet(&self, timestamp: SystemTime, event: idl::TrackEvent) -> idl::TracePacket {
+        idl::TracePacket {
+            timestamp: Some(nanos_since_epoch(timestamp)),
+            trusted_pid: Some(std::process::id() as _),
+            optional_trusted_packet_sequence_id: Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    self.sequence_id,
+                ),
+            ),
+            data: Some(idl::trace_packet::Data::TrackEvent(event)),
+            ..Default::default()
+        }
+    }
+}
+
+impl std::fmt::Debug for PerfettoSpanProcessor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerfettoSpanProcessor")
+            .field("process_track_uuid", &self.process_track_uuid)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SpanProcessor for PerfettoSpanProcessor {
+    fn on_start(&self, span: &mut Span, _: &Context) {
+        let trace_id = span.span_context().trace_id();
+        let mut tracks = self.tracks.lock().unwrap_or_else(|e| e.into_inner());
+        tracks.entry(trace_id).or_default().open += 1;
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let trace_id = span.span_context.trace_id();
+        let span_id = span.span_context.span_id();
+        let track = self.track(
+            trace_id,
+            nanos_since_epoch(span.start_time),
+            nanos_since_epoch(span.end_time),
+        );
+
+        let mut annotations = DebugAnnotations::default();
+        annotations.annotations.extend([
+            string_annotation("trace_id", trace_id.to_string()),
+            string_annotation("span_id", span_id.to_string()),
+        ]);
+        if span.parent_span_id != SpanId::INVALID {
+            annotations.annotations.push(string_annotation(
+                "parent_span_id",
+                span.parent_span_id.to_string(),
+            ));
+        }
+        annotations.annotations.push(string_annotation(
+            "span.kind",
+            format!("{:?}", span.span_kind),
+        ));
+        match &span.status {
+            Status::Unset => {}
+            Status::Ok => annotations
+                .annotations
+                .push(string_annotation("status", "ok".to_string())),
+            Status::Error { description } => annotations
+                .annotations
+                .push(string_annotation("status", format!("error: {description}"))),
+        }
+        annotations
+            .annotations
+            .extend(span.attributes.iter().map(attribute_annotation));
+
+        let mut begin = create_event(
+            track.uuid(),
+            Some(&span.name),
+            None,
+            annotations,
+            Some(idl::track_event::Type::SliceBegin),
+        );
+        begin.flow_ids.push(flow_id(trace_id, span_id));
+        if span.parent_span_is_remote {
+            begin
+                .terminating_flow_ids
+                .push(flow_id(trace_id, span.parent_span_id));
+        }
+
+        let mut packet = vec![
+            idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackDescriptor(track.clone())),
+                ..Default::default()
+            },
+            self.packet(span.start_time, begin),
+        ];
+        for event in span.events.iter() {
+            let annotations = DebugAnnotations {
+                annotations: event.attributes.iter().map(attribute_annotation).collect(),
+            };
+            let instant = create_event(
+                track.uuid(),
+                Some(&event.name),
+                None,
+                annotations,
+                Some(idl::track_event::Type::Instant),
+            );
+            packet.push(self.packet(event.timestamp, instant));
+        }
+        let end = create_event(
+            track.uuid(),
+            Some(&span.name),
+            None,
+            DebugAnnotations::default(),
+            Some(idl::track_event::Type::SliceEnd),
+        );
+        packet.push(self.packet(span.end_time, end));
+
+        (self.sink)(idl::Trace { packet });
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// The id of the flow leaving the span `span_id`, the same in every process.
+fn flow_id(trace_id: TraceId, span_id: SpanId) -> u64 {
+    let trace_id = u128::from_be_bytes(trace_id.to_bytes());
+    (trace_id as u64)
+        ^ ((trace_id >> 64) as u64).rotate_left(32)
+        ^ u64::from_be_bytes(span_id.to_bytes())
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn attribute_annotation(attribute: &KeyValue) -> idl::DebugAnnotation {
+    use idl::debug_annotation::Value as Annotation;
+
+    let value = match &attribute.value {
+        Value::Bool(value) => Annotation::BoolValue(*value),
+        Value::I64(value) => Annotation::IntValue(*value),
+        Value::F64(value) => Annotation::DoubleValue(*value),
+        value => Annotation::StringValue(value.to_string()),
+    };
+    idl::DebugAnnotation {
+        name_field: Some(idl::debug_annotation::NameField::Name(
+            attribute.key.to_string(),
+        )),
+        value: Some(value),
+        ..Default::default()
+    }
+}
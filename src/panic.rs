@@ -0,0 +1,52 @@
+//! A panic hook finalizing the trace before the process goes down.
+//!
+//! Open spans only get their `TYPE_SLICE_END` when they close, and the packets of their events
+//! are buffered until then, so a panic would otherwise lose the spans that led to it. See
+//! [`install_panic_hook`].
+
+use std::panic::PanicHookInfo;
+use std::sync::Once;
+use tracing::Dispatch;
+
+static INSTALL: Once = Once::new();
+
+/// Installs a panic hook finalizing the trace of the [`PerfettoLayer`](crate::PerfettoLayer) of
+/// the panicking thread's subscriber, before chaining to the previous hook.
+///
+/// The hook records a `panic` instant on the thread's track, with the panic message, location
+/// and backtrace, ends the open spans entered on the panicking thread on their track, and
+/// flushes the writer. Spans open on other threads are left to close normally, as the panic may
+/// be caught. Installing it again does nothing.
+pub fn install_panic_hook() {
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            tracing::dispatcher::get_default(|dispatch| {
+                if let Some(handle) = dispatch.downcast_ref::<PanicHandle>() {
+                    if let Some(finalize) = handle.finalize {
+                        finalize(dispatch, info);
+                    }
+                }
+            });
+            previous(info);
+        }));
+    });
+}
+
+/// The part of a layer reached by the hook, through [`Dispatch::downcast_ref`], whatever the
+/// writer of the layer.
+#[derive(Default)]
+pub(crate) struct PanicHandle {
+    /// Finalizes the trace of the layer registered in the dispatcher.
+    pub finalize: Option<fn(&Dispatch, &PanicHookInfo<'_>)>,
+}
+
+/// The message of a panic, for the usual `&str` and `String` payloads.
+pub(crate) fn panic_message(info: &PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
@@ -0,0 +1,203 @@
+//! Process and system counters sampled from `/proc`, see
+//! [`PerfettoLayer::with_process_counters`].
+//!
+//! [`PerfettoLayer::with_process_counters`]: crate::PerfettoLayer::with_process_counters
+
+use crate::background::{counter_trace, Periodic};
+use crate::idl;
+use idl::counter_descriptor::Unit;
+use std::time::Duration;
+
+/// Returns the clock ticks per second of the CPU times in `/proc`, falling back to the usual 100
+/// when `sysconf` can't tell.
+#[allow(unsafe_code)]
+fn clock_ticks_per_second() -> i64 {
+    // SAFETY: sysconf only reads a configuration value.
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 {
+        ticks as i64
+    } else {
+        100
+    }
+}
+
+/// Counters of one sample, `None` when they couldn't be read.
+#[derive(Debug, Default, PartialEq)]
+struct Sample {
+    rss: Option<i64>,
+    rss_watermark: Option<i64>,
+    user_time: Option<i64>,
+    system_time: Option<i64>,
+    voluntary_switches: Option<i64>,
+    involuntary_switches: Option<i64>,
+    open_fds: Option<i64>,
+    threads: Option<i64>,
+    system_cpu_percent: Option<i64>,
+}
+
+/// Reads one counter out of a sample.
+type Field = fn(&Sample) -> Option<i64>;
+
+/// The counter tracks, under the process track.
+const COUNTERS: &[(&str, Unit, Field)] = &[
+    ("mem.rss", Unit::SizeBytes, |s| s.rss),
+    ("mem.rss.watermark", Unit::SizeBytes, |s| s.rss_watermark),
+    ("cpu.user", Unit::TimeNs, |s| s.user_time),
+    ("cpu.system", Unit::TimeNs, |s| s.system_time),
+    ("ctx_switches.voluntary", Unit::Count, |s| {
+        s.voluntary_switches
+    }),
+    ("ctx_switches.involuntary", Unit::Count, |s| {
+        s.involuntary_switches
+    }),
+    ("fds.open", Unit::Count, |s| s.open_fds),
+    ("threads", Unit::Count, |s| s.threads),
+    ("system.cpu.busy_percent", Unit::Unspecified, |s| {
+        s.system_cpu_percent
+    }),
+];
+
+/// Starts sampling every `interval`, handing the counters to `sink`.
+pub(crate) fn start(
+    interval: Duration,
+    process_track_uuid: u64,
+    sequence_id: u32,
+    sink: impl Fn(idl::Trace) + Send + 'static,
+) -> std::io::Result<Periodic> {
+    let tracks: Vec<_> = COUNTERS
+        .iter()
+        .map(|(name, unit, _)| {
+            idl::TrackDescriptor::counter_child_for(name, process_track_uuid, *unit)
+        })
+        .collect();
+
+    let ticks_per_second = clock_ticks_per_second();
+    let mut system_cpu = None;
+    Periodic::start("perfetto-counters", interval, move || {
+        let sample = Sample::read(ticks_per_second, &mut system_cpu);
+        let values = COUNTERS.iter().map(|(_, _, value)| value(&sample));
+        sink(counter_trace(&tracks, values, sequence_id));
+    })
+}
+
+impl Sample {
+    /// Reads the counters of the current process, the CPU times counting `ticks_per_second`;
+    /// `system_cpu` holds the `/proc/stat` CPU times of the previous sample, the busy percentage
+    /// being computed over the interval.
+    fn read(ticks_per_second: i64, system_cpu: &mut Option<(u64, u64)>) -> Self {
+        let mut sample = Sample::default();
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            sample.parse_status(&status);
+        }
+        if let Ok(stat) = std::fs::read_to_string("/proc/self/stat") {
+            sample.parse_stat(&stat, ticks_per_second);
+        }
+        sample.open_fds = std::fs::read_dir("/proc/self/fd")
+            .ok()
+            .map(|fds| fds.count() as i64);
+
+        let current = std::fs::read_to_string("/proc/stat")
+            .ok()
+            .and_then(|stat| system_cpu_times(&stat));
+        if let (Some((busy, total)), Some((previous_busy, previous_total))) = (current, *system_cpu)
+        {
+            let busy = busy.saturating_sub(previous_busy);
+            sample.system_cpu_percent = (busy * 100)
+                .checked_div(total.saturating_sub(previous_total))
+                .map(|percent| percent as i64);
+        }
+        *system_cpu = current.or(*system_cpu);
+        sample
+    }
+
+    /// Parses `/proc/self/status`.
+    fn parse_status(&mut self, status: &str) {
+        for line in status.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let mut value = value.split_whitespace();
+            let Some(number) = value.next().and_then(|v| v.parse::<i64>().ok()) else {
+                continue;
+            };
+            let number = match value.next() {
+                Some("kB") => number * 1024,
+                _ => number,
+            };
+            match key {
+                "VmRSS" => self.rss = Some(number),
+                "VmHWM" => self.rss_watermark = Some(number),
+                "Threads" => self.threads = Some(number),
+                "voluntary_ctxt_switches" => self.voluntary_switches = Some(number),
+                "nonvoluntary_ctxt_switches" => self.involuntary_switches = Some(number),
+                _ => {}
+            }
+        }
+    }
+
+    /// Parses `/proc/self/stat`, whose CPU times count `ticks_per_second`.
+    fn parse_stat(&mut self, stat: &str, ticks_per_second: i64) {
+        // The command name in parentheses may contain spaces, the fields start after it.
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            return;
+        };
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        let ticks_to_ns = |field: Option<&&str>| {
+            field
+                .and_then(|v| v.parse::<i64>().ok())
+                .map(|ticks| ticks * (1_000_000_000 / ticks_per_second))
+        };
+        // utime and stime, fields 14 and 15 counting from the pid
+        self.user_time = ticks_to_ns(fields.get(11));
+        self.system_time = ticks_to_ns(fields.get(12));
+    }
+}
+
+/// Returns the (busy, total) CPU times of the whole system from `/proc/stat`, in ticks.
+fn system_cpu_times(stat: &str) -> Option<(u64, u64)> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let times: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|v| v.parse().ok())
+        .collect();
+    // user nice system idle iowait irq softirq steal; guest times are already in user and nice
+    let total: u64 = times.iter().take(8).sum();
+    let idle = times.get(3)? + times.get(4).unwrap_or(&0);
+    Some((total - idle, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_files() {
+        let mut sample = Sample::default();
+        sample.parse_status(
+            "Name:\tcargo\nVmHWM:\t   2048 kB\nVmRSS:\t   1024 kB\nThreads:\t4\n\
+             voluntary_ctxt_switches:\t10\nnonvoluntary_ctxt_switches:\t2\n",
+        );
+        sample.parse_stat(
+            "42 (a (weird) name) S 1 42 42 0 -1 4194304 1 0 0 0 150 25 0 0 20 0 4",
+            100,
+        );
+        assert_eq!(
+            sample,
+            Sample {
+                rss: Some(1024 * 1024),
+                rss_watermark: Some(2048 * 1024),
+                user_time: Some(1_500_000_000),
+                system_time: Some(250_000_000),
+                voluntary_switches: Some(10),
+                involuntary_switches: Some(2),
+                threads: Some(4),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            system_cpu_times("cpu  10 0 5 80 5 0 0 0 0 0\ncpu0 10 0 5 80 5 0 0 0 0 0\n"),
+            Some((15, 100))
+        );
+    }
+}
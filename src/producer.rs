@@ -0,0 +1,967 @@
+//! Recording into system-wide traces of a running `traced`, behind the `producer` feature (Unix).
+//!
+//! [`SystemProducer`] connects to the producer socket of the tracing service, the way the
+//! Perfetto SDK does, and registers the `track_event` data source. Used as the writer of a
+//! [`PerfettoLayer`], it writes nothing until a trace enabling `track_event` starts, e.g. with
+//! the `perfetto` command line client; the spans then show up in that trace next to the ftrace
+//! and scheduling data of the system.
+//!
+//! ```rust,no_run
+//! use tracing_perfetto::producer::SystemProducer;
+//! use tracing_perfetto::PerfettoLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let layer = PerfettoLayer::new(SystemProducer::connect("my-service").unwrap());
+//! tracing_subscriber::registry().with(layer).init();
+//! ```
+//!
+//! with a trace config such as:
+//!
+//! ```text
+//! data_sources {
+//!   config {
+//!     name: "track_event"
+//!     track_event_config { disabled_categories: "noisy::module" }
+//!   }
+//! }
+//! ```
+//!
+//! The `TrackEventConfig` of the session is honored: events whose categories (the target of
+//! spans, events and `log` records) are disabled are dropped, and so are debug annotations with
+//! `filter_debug_annotations`. Packets are handed to the service through
+//! the shared memory buffer it sends, see the `smb` module.
+//!
+//! [`PerfettoLayer`]: crate::PerfettoLayer
+
+use crate::idl;
+use crate::ipc::get_async_command_response::Cmd;
+use crate::ipc::ipc_frame::{self, Msg};
+use crate::ipc::{self, FrameReader, IpcFrame};
+use crate::smb::{Chunk, SharedMemory};
+use crate::PerfettoWriter;
+use bytes::BytesMut;
+use prost::Message;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+const DATA_SOURCE_NAME: &str = "track_event";
+/// How long the service has to answer the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to the producer socket of `traced`, writing the records of a [`PerfettoLayer`]
+/// into the system traces enabling `track_event`.
+///
+/// [`PerfettoLayer`]: crate::PerfettoLayer
+pub struct SystemProducer {
+    shared: Arc<Shared>,
+}
+
+/// State shared with the thread receiving the commands of the service.
+struct Shared {
+    socket: Mutex<UnixStream>,
+    port: ProducerPort,
+    next_request_id: AtomicU64,
+    state: Mutex<State>,
+}
+
+/// Ids of the `ProducerPort` service and of the methods called on it.
+struct ProducerPort {
+    service_id: u32,
+    initialize_connection: u32,
+    register_data_source: u32,
+    commit_data: u32,
+    get_async_command: u32,
+    notify_data_source_started: u32,
+    notify_data_source_stopped: u32,
+}
+
+#[derive(Default)]
+struct State {
+    smb: Option<SharedMemory>,
+    /// Page from which the next free chunk is looked for.
+    next_page: usize,
+    /// Started `track_event` instances, by id.
+    sessions: HashMap<u64, Session>,
+    /// Writers of each instance, by the packet sequence of the layer they carry.
+    writers: HashMap<(u64, u32), Writer>,
+    last_writer_id: u16,
+    /// Descriptors of the process tracks by uuid, written once by the layer and replayed at the
+    /// start of every session.
+    process_tracks: HashMap<u64, idl::TracePacket>,
+}
+
+struct Session {
+    target_buffer: u32,
+    config: idl::TrackEventConfig,
+}
+
+/// A packet sequence in the shared memory buffer, the equivalent of a `TraceWriter` of the SDK.
+struct Writer {
+    id: u16,
+    next_chunk_id: u32,
+    /// Whether packets were dropped since the last one written, for lack of free chunks.
+    dropped: bool,
+}
+
+impl SystemProducer {
+    /// Connects to the producer socket of `traced`, `$PERFETTO_PRODUCER_SOCK_NAME` or the
+    /// platform default, as `producer_name`.
+    pub fn connect(producer_name: &str) -> io::Result<Self> {
+        Self::connect_to(default_socket(), producer_name)
+    }
+
+    /// Connects to the producer socket at `path` as `producer_name`. On Linux, a path starting
+    /// with `@` names an abstract socket.
+    pub fn connect_to(path: impl AsRef<Path>, producer_name: &str) -> io::Result<Self> {
+        let socket = connect_socket(path.as_ref())?;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut reader = FrameReader::default();
+
+        send(
+            &socket,
+            0,
+            Msg::BindService(ipc_frame::BindService {
+                service_name: Some("ProducerPort".to_string()),
+            }),
+        )?;
+        let port = match reader.read_frame(&socket)?.msg {
+            Some(Msg::BindServiceReply(reply)) if reply.success() => ProducerPort::new(reply)?,
+            _ => {
+                return Err(io::Error::other(
+                    "the service refused the ProducerPort binding",
+                ))
+            }
+        };
+        let shared = Arc::new(Shared {
+            socket: Mutex::new(socket.try_clone()?),
+            port,
+            next_request_id: AtomicU64::new(1),
+            state: Mutex::default(),
+        });
+
+        let request_id = shared.invoke(
+            shared.port.initialize_connection,
+            ipc::InitializeConnectionRequest {
+                producer_name: Some(producer_name.to_string()),
+                sdk_version: Some(format!("tracing-perfetto {}", env!("CARGO_PKG_VERSION"))),
+                ..Default::default()
+            },
+        )?;
+        let response: ipc::InitializeConnectionResponse =
+            read_reply(&mut reader, &socket, request_id)?;
+        if response.use_shmem_emulation() {
+            return Err(io::Error::other("shared memory emulation is not supported"));
+        }
+
+        let request_id = shared.invoke(
+            shared.port.register_data_source,
+            ipc::RegisterDataSourceRequest {
+                data_source_descriptor: Some(ipc::DataSourceDescriptor {
+                    name: Some(DATA_SOURCE_NAME.to_string()),
+                    will_notify_on_start: Some(true),
+                    will_notify_on_stop: Some(true),
+                }),
+            },
+        )?;
+        let response: ipc::RegisterDataSourceResponse =
+            read_reply(&mut reader, &socket, request_id)?;
+        if let Some(error) = response.error.filter(|e| !e.is_empty()) {
+            return Err(io::Error::other(error));
+        }
+
+        let commands = shared.invoke(
+            shared.port.get_async_command,
+            ipc::GetAsyncCommandRequest::default(),
+        )?;
+        socket.set_read_timeout(None)?;
+        let receiver = shared.clone();
+        std::thread::Builder::new()
+            .name("perfetto-producer".to_string())
+            .spawn(move || receiver.receive_commands(socket, reader, commands))?;
+        Ok(Self { shared })
+    }
+}
+
+impl Drop for SystemProducer {
+    fn drop(&mut self) {
+        // wakes the command thread up, which exits
+        _ = self.shared.socket().shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl std::fmt::Debug for SystemProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SystemProducer")
+            .field("sessions", &self.shared.state().sessions.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PerfettoWriter for SystemProducer {
+    fn write_log(&self, buf: BytesMut) -> io::Result<()> {
+        let trace = idl::Trace::decode(buf).map_err(io::Error::other)?;
+        let mut state = self.shared.state();
+        let chunks = state.write(trace.packet, None);
+        self.shared.commit(chunks, None)
+    }
+}
+
+impl ProducerPort {
+    fn new(reply: ipc_frame::BindServiceReply) -> io::Result<Self> {
+        let method = |name: &str| {
+            reply
+                .methods
+                .iter()
+                .find(|method| method.name() == name)
+                .map(|method| method.id())
+                .ok_or_else(|| io::Error::other(format!("ProducerPort has no {name} method")))
+        };
+        Ok(Self {
+            service_id: reply.service_id(),
+            initialize_connection: method("InitializeConnection")?,
+            register_data_source: method("RegisterDataSource")?,
+            commit_data: method("CommitData")?,
+            get_async_command: method("GetAsyncCommand")?,
+            notify_data_source_started: method("NotifyDataSourceStarted")?,
+            notify_data_source_stopped: method("NotifyDataSourceStopped")?,
+        })
+    }
+}
+
+impl Shared {
+    fn socket(&self) -> MutexGuard<'_, UnixStream> {
+        self.socket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Calls `method` of the `ProducerPort`, returning the id of the request.
+    fn invoke(&self, method: u32, args: impl Message) -> io::Result<u64> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let invoke = ipc_frame::InvokeMethod {
+            service_id: Some(self.port.service_id),
+            method_id: Some(method),
+            args_proto: Some(args.encode_to_vec()),
+            drop_reply: None,
+        };
+        send(&self.socket(), request_id, Msg::InvokeMethod(invoke))?;
+        Ok(request_id)
+    }
+
+    /// Asks the service to move `chunks` into its buffers, acknowledging `flush_request_id` if
+    /// any.
+    fn commit(
+        &self,
+        chunks: Vec<ipc::ChunksToMove>,
+        flush_request_id: Option<u64>,
+    ) -> io::Result<()> {
+        if chunks.is_empty() && flush_request_id.is_none() {
+            return Ok(());
+        }
+        let request = ipc::CommitDataRequest {
+            chunks_to_move: chunks,
+            flush_request_id,
+        };
+        self.invoke(self.port.commit_data, request).map(|_| ())
+    }
+
+    /// Runs the commands streamed by the service in reply to `GetAsyncCommand`, until the
+    /// connection is closed.
+    fn receive_commands(&self, socket: UnixStream, mut reader: FrameReader, commands: u64) {
+        while let Ok(frame) = reader.read_frame(&socket) {
+            let Some(Msg::InvokeMethodReply(reply)) = frame.msg else {
+                continue;
+            };
+            if frame.request_id != Some(commands) {
+                // replies to CommitData and the notifications
+                continue;
+            }
+            let Ok(response) = ipc::GetAsyncCommandResponse::decode(reply.reply_proto()) else {
+                continue;
+            };
+            let Some(command) = response.cmd else {
+                continue;
+            };
+            if self.run_command(command, &mut reader).is_err() {
+                break;
+            }
+        }
+
+        let mut state = self.state();
+        state.sessions.clear();
+        state.writers.clear();
+        state.smb = None;
+    }
+
+    fn run_command(&self, command: Cmd, reader: &mut FrameReader) -> io::Result<()> {
+        match command {
+            Cmd::SetupTracing(setup) => {
+                let Some(fd) = reader.take_fd() else {
+                    return Ok(());
+                };
+                let page_size = setup.shared_buffer_page_size_kb.unwrap_or(4) as usize * 1024;
+                self.state().smb = Some(SharedMemory::map(&fd, page_size)?);
+            }
+            Cmd::StartDataSource(start) => {
+                let instance = start.new_instance_id();
+                let config = start.config.map(|config| *config).unwrap_or_default();
+                if config.name() != DATA_SOURCE_NAME {
+                    return Ok(());
+                }
+                let session = Session {
+                    target_buffer: config.target_buffer(),
+                    config: config.track_event_config.unwrap_or_default(),
+                };
+                let mut state = self.state();
+                state.sessions.insert(instance, session);
+                let process_tracks = state.process_tracks.values().cloned().collect();
+                let chunks = state.write(process_tracks, Some(instance));
+                self.commit(chunks, None)?;
+                let notify = ipc::NotifyDataSourceStartedRequest {
+                    data_source_id: Some(instance),
+                };
+                self.invoke(self.port.notify_data_source_started, notify)?;
+            }
+            Cmd::StopDataSource(stop) => {
+                let instance = stop.instance_id();
+                let mut state = self.state();
+                if state.sessions.remove(&instance).is_none() {
+                    return Ok(());
+                }
+                state.writers.retain(|(id, _), _| *id != instance);
+                let notify = ipc::NotifyDataSourceStoppedRequest {
+                    data_source_id: Some(instance),
+                };
+                self.invoke(self.port.notify_data_source_stopped, notify)?;
+            }
+            Cmd::Flush(flush) => {
+                // chunks are committed as soon as they are written, there is nothing pending
+                self.commit(Vec::new(), flush.request_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    /// Writes `packets` into the shared memory buffer, for every session or only `instance`,
+    /// returning the chunks to commit.
+    fn write(
+        &mut self,
+        packets: Vec<idl::TracePacket>,
+        instance: Option<u64>,
+    ) -> Vec<ipc::ChunksToMove> {
+        for packet in &packets {
+            let Some(idl::trace_packet::Data::TrackDescriptor(track)) = &packet.data else {
+                continue;
+            };
+            if track.process.is_some() {
+                self.process_tracks.insert(track.uuid(), packet.clone());
+            }
+        }
+
+        let mut chunks = Vec::new();
+        let Some(smb) = &self.smb else {
+            return chunks;
+        };
+        for (&id, session) in &self.sessions {
+            if instance.is_some_and(|instance| instance != id) {
+                continue;
+            }
+            // the layer writes several packet sequences, each gets its own writer
+            let mut sequences: Vec<(u32, Vec<idl::TracePacket>)> = Vec::new();
+            for packet in packets.iter().filter_map(|p| session.filter(p.clone())) {
+                let (sequence, packet) = untrusted(packet);
+                match sequences.iter_mut().find(|(s, _)| *s == sequence) {
+                    Some((_, packets)) => packets.push(packet),
+                    None => sequences.push((sequence, vec![packet])),
+                }
+            }
+            for (sequence, packets) in sequences {
+                let writer = self.writers.entry((id, sequence)).or_insert_with(|| {
+                    self.last_writer_id = self.last_writer_id.checked_add(1).unwrap_or(1);
+                    Writer {
+                        id: self.last_writer_id,
+                        next_chunk_id: 0,
+                        dropped: false,
+                    }
+                });
+                for chunk in writer.write(smb, &mut self.next_page, packets) {
+                    chunks.push(ipc::ChunksToMove {
+                        page: Some(chunk),
+                        chunk: Some(0),
+                        target_buffer: Some(session.target_buffer),
+                    });
+                }
+            }
+        }
+        chunks
+    }
+}
+
+impl Session {
+    /// Applies the `TrackEventConfig` of the session to `packet`, `None` if it is dropped.
+    fn filter(&self, mut packet: idl::TracePacket) -> Option<idl::TracePacket> {
+        let Some(idl::trace_packet::Data::TrackEvent(event)) = &mut packet.data else {
+            return Some(packet);
+        };
+        if !event.categories.is_empty()
+            && !event
+                .categories
+                .iter()
+                .any(|category| category_enabled(&self.config, category))
+        {
+            return None;
+        }
+        if self.config.filter_debug_annotations() {
+            event.debug_annotations.clear();
+        }
+        Some(packet)
+    }
+}
+
+impl Writer {
+    /// Writes `packets` in as many chunks as needed, returning the pages of the completed chunks.
+    fn write(
+        &mut self,
+        smb: &SharedMemory,
+        next_page: &mut usize,
+        packets: Vec<idl::TracePacket>,
+    ) -> Vec<u32> {
+        let mut completed = Vec::new();
+        let mut chunk: Option<Chunk<'_>> = None;
+        for mut packet in packets {
+            if self.dropped {
+                packet.previous_packet_dropped = Some(true);
+            }
+            let bytes = packet.encode_to_vec();
+            let mut written = 0;
+            while written < bytes.len() {
+                let current = match &mut chunk {
+                    Some(current) => current,
+                    None => match smb.acquire_chunk(*next_page) {
+                        Some(acquired) => {
+                            *next_page = acquired.page() as usize + 1;
+                            chunk.insert(acquired)
+                        }
+                        // the service is late draining the buffer, like the SDK in its default
+                        // mode, drop rather than stall
+                        None => break,
+                    },
+                };
+                written += current.append(&bytes[written..], written > 0);
+                if written < bytes.len() {
+                    completed.extend(chunk.take().map(|full| self.complete(full)));
+                }
+            }
+            self.dropped = written < bytes.len();
+        }
+        completed.extend(chunk.map(|last| self.complete(last)));
+        completed
+    }
+
+    fn complete(&mut self, chunk: Chunk<'_>) -> u32 {
+        let page = chunk.page();
+        chunk.complete(self.id, self.next_chunk_id);
+        self.next_chunk_id = self.next_chunk_id.wrapping_add(1);
+        page
+    }
+}
+
+/// Strips `packet` of the fields only the service may set, returning the layer sequence it
+/// belongs to. Its timestamp, from the realtime clock, is marked as such.
+fn untrusted(mut packet: idl::TracePacket) -> (u32, idl::TracePacket) {
+    let sequence = match packet.optional_trusted_packet_sequence_id.take() {
+        Some(idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(id)) => id,
+        None => 0,
+    };
+    packet.trusted_pid = None;
+    packet.optional_trusted_uid = None;
+    if packet.timestamp.is_some() && packet.timestamp_clock_id.is_none() {
+        packet.timestamp_clock_id = Some(idl::BuiltinClock::Realtime as u32);
+    }
+    (sequence, packet)
+}
+
+/// Whether `category` is enabled by `config`, following the precedence of the SDK: exact
+/// matches before patterns, enabled before disabled, enabled if nothing matches.
+fn category_enabled(config: &idl::TrackEventConfig, category: &str) -> bool {
+    let exact = |patterns: &[String]| patterns.iter().any(|p| p == category);
+    let pattern = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, category));
+    if exact(&config.enabled_categories) {
+        true
+    } else if exact(&config.disabled_categories) {
+        false
+    } else if pattern(&config.enabled_categories) {
+        true
+    } else {
+        !pattern(&config.disabled_categories)
+    }
+}
+
+/// Matches `text` against `pattern`, in which `*` matches any run of characters and `?` any
+/// single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` in the pattern, and of the text it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    t = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn default_socket() -> PathBuf {
+    if let Some(path) = std::env::var_os("PERFETTO_PRODUCER_SOCK_NAME") {
+        return path.into();
+    }
+    if cfg!(target_os = "android") {
+        "/dev/socket/traced_producer".into()
+    } else {
+        "/tmp/perfetto-producer".into()
+    }
+}
+
+fn connect_socket(path: &Path) -> io::Result<UnixStream> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        return UnixStream::connect_addr(&address);
+    }
+    UnixStream::connect(path)
+}
+
+fn send(socket: &UnixStream, request_id: u64, msg: Msg) -> io::Result<()> {
+    let frame = IpcFrame {
+        request_id: Some(request_id),
+        msg: Some(msg),
+    };
+    ipc::send_frame(socket, &frame, None)
+}
+
+/// Reads frames until the reply to `request_id`, during the handshake.
+fn read_reply<M: Message + Default>(
+    reader: &mut FrameReader,
+    socket: &UnixStream,
+    request_id: u64,
+) -> io::Result<M> {
+    loop {
+        let frame = reader.read_frame(socket)?;
+        if frame.request_id != Some(request_id) {
+            continue;
+        }
+        return match frame.msg {
+            Some(Msg::InvokeMethodReply(reply)) if reply.success() => {
+                M::decode(reply.reply_proto()).map_err(io::Error::other)
+            }
+            Some(Msg::RequestError(error)) => Err(io::Error::other(error.error().to_string())),
+            _ => Err(io::Error::other(format!("request {request_id} failed"))),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smb::{CompletedChunk, FIRST_PACKET_CONTINUES, LAST_PACKET_CONTINUES};
+    use std::os::fd::{AsFd, OwnedFd};
+    use std::os::unix::net::UnixListener;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const METHODS: [&str; 6] = [
+        "InitializeConnection",
+        "RegisterDataSource",
+        "CommitData",
+        "GetAsyncCommand",
+        "NotifyDataSourceStarted",
+        "NotifyDataSourceStopped",
+    ];
+
+    /// The service end of a producer connection, playing the part of `traced`.
+    struct StandIn {
+        socket: UnixStream,
+        reader: FrameReader,
+        commands: u64,
+    }
+
+    impl StandIn {
+        /// Accepts a producer and answers its handshake.
+        fn accept(listener: UnixListener) -> Self {
+            let (socket, _) = listener.accept().unwrap();
+            let mut reader = FrameReader::default();
+            let frame = reader.read_frame(&socket).unwrap();
+            let request_id = frame.request_id();
+            let Some(Msg::BindService(bind)) = frame.msg else {
+                panic!("{frame:?}")
+            };
+            assert_eq!(bind.service_name(), "ProducerPort");
+            let methods = METHODS
+                .iter()
+                .enumerate()
+                .map(|(id, name)| ipc_frame::MethodInfo {
+                    id: Some(id as u32 + 1),
+                    name: Some(name.to_string()),
+                });
+            let reply = ipc_frame::BindServiceReply {
+                success: Some(true),
+                service_id: Some(7),
+                methods: methods.collect(),
+            };
+            send(&socket, request_id, Msg::BindServiceReply(reply)).unwrap();
+
+            let mut stand_in = Self {
+                socket,
+                reader,
+                commands: 0,
+            };
+            let (id, init) =
+                stand_in.invoked::<ipc::InitializeConnectionRequest>("InitializeConnection");
+            assert_eq!(init.producer_name(), "test-producer");
+            stand_in.reply(
+                id,
+                ipc::InitializeConnectionResponse::default(),
+                false,
+                None,
+            );
+            let (id, register) =
+                stand_in.invoked::<ipc::RegisterDataSourceRequest>("RegisterDataSource");
+            assert_eq!(
+                register.data_source_descriptor.unwrap().name(),
+                DATA_SOURCE_NAME
+            );
+            stand_in.reply(id, ipc::RegisterDataSourceResponse::default(), false, None);
+            let (id, _) = stand_in.invoked::<ipc::GetAsyncCommandRequest>("GetAsyncCommand");
+            stand_in.commands = id;
+            stand_in
+        }
+
+        /// Reads the next call, which must be to `method`.
+        fn invoked<M: Message + Default>(&mut self, method: &str) -> (u64, M) {
+            let frame = self.reader.read_frame(&self.socket).unwrap();
+            let request_id = frame.request_id();
+            let Some(Msg::InvokeMethod(invoke)) = frame.msg else {
+                panic!("{frame:?}")
+            };
+            assert_eq!(invoke.service_id(), 7);
+            assert_eq!(METHODS[invoke.method_id() as usize - 1], method);
+            (request_id, M::decode(invoke.args_proto()).unwrap())
+        }
+
+        fn reply(
+            &self,
+            request_id: u64,
+            reply: impl Message,
+            has_more: bool,
+            fd: Option<&OwnedFd>,
+        ) {
+            let frame = IpcFrame {
+                request_id: Some(request_id),
+                msg: Some(Msg::InvokeMethodReply(ipc_frame::InvokeMethodReply {
+                    success: Some(true),
+                    has_more: Some(has_more),
+                    reply_proto: Some(reply.encode_to_vec()),
+                })),
+            };
+            ipc::send_frame(&self.socket, &frame, fd.map(|fd| fd.as_fd())).unwrap();
+        }
+
+        fn command(&self, cmd: Cmd, fd: Option<&OwnedFd>) {
+            let response = ipc::GetAsyncCommandResponse { cmd: Some(cmd) };
+            self.reply(self.commands, response, true, fd);
+        }
+
+        /// Reads the chunks of the next `CommitData`, with their target buffer.
+        fn committed(&mut self, smb: &SharedMemory) -> Vec<(u32, CompletedChunk)> {
+            let (_, commit) = self.invoked::<ipc::CommitDataRequest>("CommitData");
+            commit
+                .chunks_to_move
+                .iter()
+                .map(|chunk| {
+                    let completed = smb.read_chunk(chunk.page() as usize).unwrap();
+                    (chunk.target_buffer(), completed)
+                })
+                .collect()
+        }
+    }
+
+    fn packet(sequence_id: u32, data: idl::trace_packet::Data) -> idl::TracePacket {
+        idl::TracePacket {
+            timestamp: Some(42),
+            trusted_pid: Some(1),
+            optional_trusted_packet_sequence_id: Some(
+                idl::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                    sequence_id,
+                ),
+            ),
+            data: Some(data),
+            ..Default::default()
+        }
+    }
+
+    fn instant(category: &str, name: &str) -> idl::trace_packet::Data {
+        let mut event = idl::TrackEvent {
+            categories: vec![category.to_string()],
+            name_field: Some(idl::track_event::NameField::Name(name.to_string())),
+            ..Default::default()
+        };
+        event.set_type(idl::track_event::Type::Instant);
+        idl::trace_packet::Data::TrackEvent(event)
+    }
+
+    fn write(producer: &SystemProducer, packet: Vec<idl::TracePacket>) {
+        let buf = idl::Trace { packet }.encode_to_vec();
+        producer.write_log(BytesMut::from(buf.as_slice())).unwrap();
+    }
+
+    /// A producer shared by a layer and the test.
+    struct SharedProducer(Arc<SystemProducer>);
+
+    impl PerfettoWriter for SharedProducer {
+        fn write_log(&self, buf: BytesMut) -> io::Result<()> {
+            self.0.write_log(buf)
+        }
+    }
+
+    #[test]
+    fn test_stand_in_traced() {
+        let dir = std::env::temp_dir().join(format!("tracing-perfetto-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("producer.sock");
+        _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let service = std::thread::spawn(move || StandIn::accept(listener));
+        let producer = Arc::new(SystemProducer::connect_to(&path, "test-producer").unwrap());
+        let mut service = service.join().unwrap();
+
+        // written before any session: only the process track is kept, to be replayed
+        let process_track = idl::trace_packet::Data::TrackDescriptor(idl::TrackDescriptor {
+            uuid: Some(1),
+            process: Some(idl::ProcessDescriptor {
+                pid: Some(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        write(
+            &producer,
+            vec![
+                idl::TracePacket {
+                    data: Some(process_track),
+                    ..Default::default()
+                },
+                packet(1, instant("", "too early")),
+            ],
+        );
+
+        let shm = tempfile(&dir, 4 * 4096);
+        let smb = SharedMemory::map(&shm, 4096).unwrap();
+        service.command(Cmd::SetupTracing(Default::default()), Some(&shm));
+        let config = idl::DataSourceConfig {
+            name: Some(DATA_SOURCE_NAME.to_string()),
+            target_buffer: Some(2),
+            track_event_config: Some(idl::TrackEventConfig {
+                disabled_categories: vec!["noisy*".to_string()],
+                filter_debug_annotations: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let start = ipc::get_async_command_response::StartDataSource {
+            new_instance_id: Some(5),
+            config: Some(Box::new(config)),
+        };
+        service.command(Cmd::StartDataSource(start), None);
+
+        let replayed = service.committed(&smb);
+        assert_eq!(replayed.len(), 1);
+        let (target_buffer, replay) = &replayed[0];
+        assert_eq!((*target_buffer, replay.chunk_id, replay.flags), (2, 0, 0));
+        let descriptor = idl::TracePacket::decode(replay.fragments[0].as_slice()).unwrap();
+        assert!(
+            matches!(descriptor.data, Some(idl::trace_packet::Data::TrackDescriptor(t)) if t.uuid == Some(1))
+        );
+        let (_, started) =
+            service.invoked::<ipc::NotifyDataSourceStartedRequest>("NotifyDataSourceStarted");
+        assert_eq!(started.data_source_id(), 5);
+
+        let mut annotated = instant("net", "kept");
+        if let idl::trace_packet::Data::TrackEvent(event) = &mut annotated {
+            event.debug_annotations.push(Default::default());
+        }
+        let big = instant("net", &"x".repeat(9000));
+        write(
+            &producer,
+            vec![
+                packet(1, annotated),
+                packet(1, instant("noisy::module", "dropped")),
+                packet(3, instant("other", "other sequence")),
+                packet(3, big),
+            ],
+        );
+        let chunks = service.committed(&smb);
+        assert_eq!(
+            chunks.len(),
+            4,
+            "one chunk for sequence 1, three for sequence 3"
+        );
+
+        let (_, kept) = &chunks[0];
+        assert_ne!(
+            kept.writer_id, replay.writer_id,
+            "packets without a sequence have their own writer"
+        );
+        assert_eq!(kept.flags, 0);
+        assert_eq!(kept.fragments.len(), 1);
+        let kept = idl::TracePacket::decode(kept.fragments[0].as_slice()).unwrap();
+        assert_eq!(kept.optional_trusted_packet_sequence_id, None);
+        assert_eq!(kept.trusted_pid, None);
+        assert_eq!(
+            kept.timestamp_clock_id,
+            Some(idl::BuiltinClock::Realtime as u32)
+        );
+        let Some(idl::trace_packet::Data::TrackEvent(event)) = kept.data else {
+            panic!("{kept:?}")
+        };
+        assert!(event.debug_annotations.is_empty());
+
+        let other_writer = chunks[1].1.writer_id;
+        assert_ne!(other_writer, chunks[0].1.writer_id);
+        let ids: Vec<_> = chunks[1..]
+            .iter()
+            .map(|(_, c)| (c.chunk_id, c.writer_id, c.flags))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                (0, other_writer, LAST_PACKET_CONTINUES),
+                (
+                    1,
+                    other_writer,
+                    FIRST_PACKET_CONTINUES | LAST_PACKET_CONTINUES
+                ),
+                (2, other_writer, FIRST_PACKET_CONTINUES),
+            ]
+        );
+        let mut big = chunks[1].1.fragments[1].clone();
+        big.extend(chunks[2].1.fragments.concat());
+        big.extend(chunks[3].1.fragments.concat());
+        let big = idl::TracePacket::decode(big.as_slice()).unwrap();
+        let Some(idl::trace_packet::Data::TrackEvent(event)) = big.data else {
+            panic!("{big:?}")
+        };
+        assert_eq!(
+            event.name_field,
+            Some(idl::track_event::NameField::Name("x".repeat(9000)))
+        );
+
+        // the spans and events of a layer are categorized by their target
+        let layer = crate::PerfettoLayer::new(SharedProducer(producer.clone()));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::info_span!(target: "noisy::module", "noisy").in_scope(|| {
+                tracing::info!(target: "net", "kept");
+            });
+        });
+        let events: Vec<_> = service
+            .committed(&smb)
+            .iter()
+            .flat_map(|(_, chunk)| &chunk.fragments)
+            .filter_map(
+                |fragment| match idl::TracePacket::decode(fragment.as_slice()) {
+                    Ok(idl::TracePacket {
+                        data: Some(idl::trace_packet::Data::TrackEvent(event)),
+                        ..
+                    }) => Some(event),
+                    _ => None,
+                },
+            )
+            .collect();
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert_eq!(events[0].categories, ["net"]);
+        assert_eq!(events[0].r#type(), idl::track_event::Type::Instant);
+
+        let flush = ipc::get_async_command_response::Flush {
+            data_source_ids: vec![5],
+            request_id: Some(9),
+        };
+        service.command(Cmd::Flush(flush), None);
+        let (_, commit) = service.invoked::<ipc::CommitDataRequest>("CommitData");
+        assert_eq!(commit.flush_request_id, Some(9));
+
+        let stop = ipc::get_async_command_response::StopDataSource {
+            instance_id: Some(5),
+        };
+        service.command(Cmd::StopDataSource(stop), None);
+        let (_, stopped) =
+            service.invoked::<ipc::NotifyDataSourceStoppedRequest>("NotifyDataSourceStopped");
+        assert_eq!(stopped.data_source_id(), 5);
+        write(&producer, vec![packet(1, instant("net", "too late"))]);
+
+        drop(producer);
+        assert!(
+            service.reader.read_frame(&service.socket).is_err(),
+            "nothing written after the stop"
+        );
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_category_enabled() {
+        let config = idl::TrackEventConfig {
+            enabled_categories: vec!["hyper::proto".to_string(), "tokio*".to_string()],
+            disabled_categories: vec!["*".to_string(), "tokio::task".to_string()],
+            ..Default::default()
+        };
+        assert!(category_enabled(&config, "hyper::proto"));
+        assert!(!category_enabled(&config, "hyper::client"));
+        assert!(category_enabled(&config, "tokio::net"));
+        assert!(!category_enabled(&config, "tokio::task"));
+        assert!(category_enabled(&Default::default(), ""));
+        assert!(glob_match("a*b?d*", "aXXbcdYY"));
+        assert!(!glob_match("a*b?d", "abd"));
+    }
+
+    fn tempfile(dir: &Path, len: u64) -> OwnedFd {
+        let path = dir.join("smb");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(len).unwrap();
+        std::fs::remove_file(path).unwrap();
+        file.into()
+    }
+}
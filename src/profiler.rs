@@ -0,0 +1,313 @@
+//! In-process CPU sampling profiler, see [`PerfettoLayer::with_cpu_profiler`].
+//!
+//! A process-wide `ITIMER_PROF` timer delivers `SIGPROF` to whichever thread is burning CPU, so
+//! the samples of every thread are proportional to the CPU time it uses. The signal handler only
+//! walks the frame pointers of the interrupted code into a preallocated ring of atomics; a
+//! collector thread drains it, symbolizes the stacks and writes them as `PerfSample` packets on
+//! the sampled thread.
+//!
+//! The handler can't use the DWARF unwinder: it takes the `dl_iterate_phdr` lock and may
+//! allocate, so a sample landing in the middle of another unwind (a captured call stack, a panic
+//! backtrace) or of `dlopen` would deadlock. Frame pointers are read with `process_vm_readv`,
+//! which fails instead of faulting on a bogus pointer. Stacks are only complete for code built
+//! with frame pointers (`-C force-frame-pointers=yes`).
+//!
+//! [`PerfettoLayer::with_cpu_profiler`]: crate::PerfettoLayer::with_cpu_profiler
+
+use crate::background::Periodic;
+use crate::callstack::Symbolizer;
+use crate::idl;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Deepest stack sampled, in frames.
+const MAX_DEPTH: usize = 64;
+/// Samples waiting for the collector, on top of which new samples are dropped.
+const SLOTS: usize = 512;
+/// How often the collector drains the samples.
+const COLLECT_INTERVAL: Duration = Duration::from_millis(100);
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+struct Slot {
+    state: AtomicU8,
+    tid: AtomicU64,
+    timestamp: AtomicU64,
+    depth: AtomicUsize,
+    ips: [AtomicU64; MAX_DEPTH],
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            tid: AtomicU64::new(0),
+            timestamp: AtomicU64::new(0),
+            depth: AtomicUsize::new(0),
+            ips: [const { AtomicU64::new(0) }; MAX_DEPTH],
+        }
+    }
+}
+
+/// Ring of samples shared by the signal handler and the collector. Only atomics, so that the
+/// handler never takes a lock nor allocates.
+static SAMPLES: [Slot; SLOTS] = [const { Slot::new() }; SLOTS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigprof(_: libc::c_int, _: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    // SAFETY: `__errno_location` always returns a valid pointer to the thread's errno.
+    let errno = unsafe { *libc::__errno_location() };
+
+    let slot = &SAMPLES[NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % SLOTS];
+    if slot
+        .state
+        .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+    {
+        // SAFETY: `SA_SIGINFO` handlers are passed the `ucontext_t` of the interrupted code.
+        let depth = match unsafe { interrupted_registers(context) } {
+            Some((pc, fp)) => walk_frame_pointers(pc, fp, &slot.ips),
+            None => 0,
+        };
+        slot.depth.store(depth, Ordering::Relaxed);
+        slot.tid.store(thread_id::get() as u64, Ordering::Relaxed);
+        slot.timestamp.store(now(), Ordering::Relaxed);
+        slot.state.store(READY, Ordering::Release);
+    }
+
+    // SAFETY: see above.
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// The program counter and frame pointer of the code interrupted by a signal.
+#[cfg(target_arch = "x86_64")]
+unsafe fn interrupted_registers(context: *mut libc::c_void) -> Option<(u64, u64)> {
+    let context = &*(context as *const libc::ucontext_t);
+    let registers = &context.uc_mcontext.gregs;
+    Some((
+        registers[libc::REG_RIP as usize] as u64,
+        registers[libc::REG_RBP as usize] as u64,
+    ))
+}
+
+/// The program counter and frame pointer of the code interrupted by a signal.
+#[cfg(target_arch = "aarch64")]
+unsafe fn interrupted_registers(context: *mut libc::c_void) -> Option<(u64, u64)> {
+    let context = &*(context as *const libc::ucontext_t);
+    Some((context.uc_mcontext.pc, context.uc_mcontext.regs[29]))
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn interrupted_registers(_context: *mut libc::c_void) -> Option<(u64, u64)> {
+    None
+}
+
+/// Walks the chain of frame pointers starting at `fp` into `ips`, innermost first, and returns
+/// the number of frames.
+///
+/// Each frame starts with the frame pointer of its caller, followed by the return address.
+fn walk_frame_pointers(pc: u64, mut fp: u64, ips: &[AtomicU64; MAX_DEPTH]) -> usize {
+    ips[0].store(pc, Ordering::Relaxed);
+    let mut depth = 1;
+    while depth < MAX_DEPTH && fp != 0 && fp.is_multiple_of(8) {
+        let Some([caller_fp, return_address]) = read_frame(fp) else {
+            break;
+        };
+        if return_address == 0 {
+            break;
+        }
+        ips[depth].store(return_address, Ordering::Relaxed);
+        depth += 1;
+        // the stack grows down, a caller's frame is above
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+    depth
+}
+
+/// Reads the two words at `address` of the process, or `None` if they aren't mapped.
+fn read_frame(address: u64) -> Option<[u64; 2]> {
+    let mut frame = [0u64; 2];
+    let local = libc::iovec {
+        iov_base: frame.as_mut_ptr().cast(),
+        iov_len: std::mem::size_of_val(&frame),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: std::mem::size_of_val(&frame),
+    };
+    // SAFETY: `local` is a valid buffer of `iov_len` bytes; the kernel checks `remote` and fails
+    // with `EFAULT` rather than faulting. `getpid` and `process_vm_readv` don't lock nor allocate.
+    let read = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+    (read == local.iov_len as isize).then_some(frame)
+}
+
+/// Nanoseconds since the epoch, the clock of the layer's timestamps.
+fn now() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec, and `clock_gettime` is async-signal-safe.
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// A timer firing `frequency` times per second of CPU time, or a stopped one for 0.
+fn timer_at(frequency: u32) -> libc::itimerval {
+    let period = if frequency == 0 {
+        libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        }
+    } else {
+        let micros = (1_000_000 / frequency as u64).max(1);
+        libc::timeval {
+            tv_sec: (micros / 1_000_000) as _,
+            tv_usec: (micros % 1_000_000) as _,
+        }
+    };
+    libc::itimerval {
+        it_interval: period,
+        it_value: period,
+    }
+}
+
+/// Sets the `ITIMER_PROF` timer, returning the previous one.
+fn set_timer(timer: &libc::itimerval) -> std::io::Result<libc::itimerval> {
+    let mut previous = timer_at(0);
+    // SAFETY: `timer` and `previous` are valid itimervals.
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, timer, &mut previous) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(previous)
+}
+
+/// Installs `action` for `SIGPROF`, returning the previous action.
+fn set_action(action: &libc::sigaction) -> std::io::Result<libc::sigaction> {
+    // SAFETY: `action` is a valid sigaction, and `previous` is only used once filled in.
+    unsafe {
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGPROF, action, &mut previous) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(previous)
+    }
+}
+
+fn install_handler() -> std::io::Result<libc::sigaction> {
+    // SAFETY: the action is fully initialized before use and the handler has the signature
+    // `SA_SIGINFO` expects.
+    let action = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigprof as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        action
+    };
+    set_action(&action)
+}
+
+/// A running profiler, stopped when dropped.
+pub(crate) struct Profiler {
+    collector: Option<Periodic>,
+    /// The `SIGPROF` action and `ITIMER_PROF` timer in place before the profiler started.
+    previous: (libc::sigaction, libc::itimerval),
+}
+
+impl Profiler {
+    /// Starts sampling at `frequency` Hz, handing the samples to `sink` as they are collected.
+    ///
+    /// Only one profiler can run at a time in a process.
+    pub fn start(
+        frequency: u32,
+        symbolizer: Arc<Symbolizer>,
+        sequence_id: u32,
+        sink: impl Fn(idl::Trace) + Send + 'static,
+    ) -> std::io::Result<Self> {
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "a CPU profiler is already running",
+            ));
+        }
+
+        let previous_action = match install_handler() {
+            Ok(action) => action,
+            Err(e) => {
+                RUNNING.store(false, Ordering::Release);
+                return Err(e);
+            }
+        };
+        let previous_timer = match set_timer(&timer_at(frequency)) {
+            Ok(timer) => timer,
+            Err(e) => {
+                _ = set_action(&previous_action);
+                RUNNING.store(false, Ordering::Release);
+                return Err(e);
+            }
+        };
+        let mut profiler = Self {
+            collector: None,
+            previous: (previous_action, previous_timer),
+        };
+
+        profiler.collector = Some(Periodic::start(
+            "perfetto-profiler",
+            COLLECT_INTERVAL,
+            move || {
+                let packet = collect(&symbolizer, sequence_id);
+                if !packet.is_empty() {
+                    sink(idl::Trace { packet });
+                }
+            },
+        )?);
+        Ok(profiler)
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        let (action, timer) = &self.previous;
+        _ = set_timer(timer);
+        // the collector picks up the last samples as it stops
+        drop(self.collector.take());
+        _ = set_action(action);
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+/// Drains the ready samples into `PerfSample` packets.
+fn collect(symbolizer: &Symbolizer, sequence_id: u32) -> Vec<idl::TracePacket> {
+    let mut packets = vec![];
+    let mut ips = Vec::with_capacity(MAX_DEPTH);
+    for slot in &SAMPLES {
+        if slot.state.load(Ordering::Acquire) != READY {
+            continue;
+        }
+        ips.clear();
+        let depth = slot.depth.load(Ordering::Relaxed);
+        ips.extend(
+            slot.ips[..depth]
+                .iter()
+                .map(|ip| ip.load(Ordering::Relaxed)),
+        );
+        let tid = slot.tid.load(Ordering::Relaxed);
+        let timestamp = slot.timestamp.load(Ordering::Relaxed);
+        slot.state.store(EMPTY, Ordering::Release);
+
+        if ips.is_empty() {
+            continue;
+        }
+        let callstack = symbolizer.symbolize(&ips);
+        // same truncation as the tid of the thread descriptors
+        packets.push(callstack.sample_packet(tid as _, Some(timestamp), sequence_id));
+    }
+    packets
+}
@@ -0,0 +1,101 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Instant;
+use tracing::callsite;
+
+/// Number of independently locked parts of the callsite map, so that registering a callsite
+/// doesn't stall the others.
+const SHARDS: usize = 16;
+
+/// Marks a span that was dropped by sampling, so that its whole subtree (child spans and
+/// events) is dropped with it.
+pub(crate) struct SampledOut;
+
+/// Limits the number of records per callsite and per second.
+pub(crate) struct RateLimiter {
+    epoch: Instant,
+    // callsite -> second of the current window in the high half, records in that window in the
+    // low half, updated without taking the write lock once the callsite is known
+    windows: [RwLock<HashMap<callsite::Identifier, AtomicU64>>; SHARDS],
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            windows: Default::default(),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Returns whether a record of `callsite` fits in the `max_per_second` budget of the current
+    /// second, and consumes it.
+    pub fn allow(&self, callsite: callsite::Identifier, max_per_second: u32) -> bool {
+        let second = self.epoch.elapsed().as_secs() as u32;
+        let mut hasher = DefaultHasher::new();
+        callsite.hash(&mut hasher);
+        let shard = &self.windows[hasher.finish() as usize % SHARDS];
+
+        let windows = shard.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(window) = windows.get(&callsite) {
+            return Self::consume(window, second, max_per_second);
+        }
+        drop(windows);
+        let mut windows = shard.write().unwrap_or_else(|e| e.into_inner());
+        let window = windows.entry(callsite).or_default();
+        Self::consume(window, second, max_per_second)
+    }
+
+    fn consume(window: &AtomicU64, second: u32, max_per_second: u32) -> bool {
+        let mut current = window.load(Ordering::Relaxed);
+        loop {
+            let count = if (current >> 32) as u32 == second {
+                current as u32
+            } else {
+                0
+            };
+            if count >= max_per_second {
+                return false;
+            }
+            let next = (u64::from(second) << 32) | u64::from(count + 1);
+            match window.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_shares_the_budget_across_threads() {
+        let callsite =
+            tracing::callsite!(name: "limited", kind: tracing::metadata::Kind::EVENT, fields:);
+        let callsite = callsite::Identifier(callsite);
+        let limiter = RateLimiter::default();
+        let allowed: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let callsite = callsite.clone();
+                    let limiter = &limiter;
+                    scope.spawn(move || {
+                        (0..100)
+                            .filter(|_| limiter.allow(callsite.clone(), 50))
+                            .count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        // the threads may straddle a second boundary
+        assert!(allowed == 50 || allowed == 100, "{allowed}");
+    }
+}
@@ -0,0 +1,49 @@
+//! Dumps on `SIGUSR1` and `SIGUSR2`, to grab a trace from a running process (Linux).
+//!
+//! The signals are caught by `signal-hook` and handled on a `perfetto-signals` thread, where
+//! the layer is free to lock and write.
+
+use signal_hook::consts::{SIGUSR1, SIGUSR2};
+use signal_hook::iterator::{Handle, Signals};
+use std::thread::JoinHandle;
+
+/// The thread handling the signals, stopped when dropped.
+pub(crate) struct SignalDump {
+    handle: Handle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SignalDump {
+    /// Spawns a thread calling `dump` on every `SIGUSR1` and `toggle` on every `SIGUSR2`.
+    pub fn start(
+        dump: impl Fn() + Send + 'static,
+        toggle: impl Fn() + Send + 'static,
+    ) -> std::io::Result<Self> {
+        let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+        let handle = signals.handle();
+        let thread = std::thread::Builder::new()
+            .name("perfetto-signals".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    match signal {
+                        SIGUSR1 => dump(),
+                        SIGUSR2 => toggle(),
+                        _ => {}
+                    }
+                }
+            })?;
+        Ok(Self {
+            handle,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for SignalDump {
+    fn drop(&mut self) {
+        self.handle.close();
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! The shared memory buffer (SMB) through which a producer hands its packets to `traced`, laid
+//! out as in Perfetto's `shared_memory_abi.h`.
+//!
+//! The buffer is split in pages, each starting with a header whose bitmap holds the layout of
+//! the page (how many chunks it is divided in) and the state of every chunk. Pages are used
+//! whole here, as a single chunk: the producer claims a free page, fills its chunk with packet
+//! fragments, marks it complete and asks the service to move it with a `CommitData` request.
+//! The service then copies the chunk into its trace buffer and frees the page.
+
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+const PAGE_HEADER_SIZE: usize = 8;
+const CHUNK_HEADER_SIZE: usize = 8;
+/// Size of the length prefix of the fragments, a varint padded to 4 bytes.
+const FRAGMENT_HEADER_SIZE: usize = 4;
+const MAX_FRAGMENTS_PER_CHUNK: u16 = (1 << 10) - 1;
+
+const LAYOUT_SHIFT: u32 = 28;
+const ALL_CHUNKS_MASK: u32 = 0x0FFF_FFFF;
+/// `kPageDiv1`: the page holds a single chunk.
+const LAYOUT_DIV1: u32 = 1;
+
+const CHUNK_BEING_WRITTEN: u32 = 1;
+const CHUNK_COMPLETE: u32 = 3;
+
+/// The first fragment of the chunk continues a packet started in the previous chunk.
+pub const FIRST_PACKET_CONTINUES: u16 = 1 << 0;
+/// The last fragment of the chunk is continued in the next chunk.
+pub const LAST_PACKET_CONTINUES: u16 = 1 << 1;
+
+/// A shared memory buffer mapped in this process.
+pub struct SharedMemory {
+    base: NonNull<u8>,
+    len: usize,
+    page_size: usize,
+}
+
+// SAFETY: the mapping is only accessed through atomics or within chunks owned by a writer.
+unsafe impl Send for SharedMemory {}
+// SAFETY: see above.
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    /// Maps the whole file `fd`, made of pages of `page_size` bytes.
+    pub fn map(fd: &OwnedFd, page_size: usize) -> io::Result<Self> {
+        if page_size == 0 || !page_size.is_multiple_of(4096) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid shared memory page size {page_size}"),
+            ));
+        }
+        let len = std::fs::File::from(fd.try_clone()?).metadata()?.len() as usize;
+        if len < page_size || !len.is_multiple_of(page_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid shared memory size {len}"),
+            ));
+        }
+
+        // SAFETY: a fresh shared mapping of the file, released in `drop`.
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            base: NonNull::new(base.cast()).expect("mmap succeeded"),
+            len,
+            page_size,
+        })
+    }
+
+    pub fn pages(&self) -> usize {
+        self.len / self.page_size
+    }
+
+    fn page_bitmap(&self, page: usize) -> &AtomicU32 {
+        assert!(page < self.pages());
+        // SAFETY: pages are in the mapping and aligned on `page_size`, the bitmap is their first
+        // word, and it is only accessed atomically.
+        unsafe { AtomicU32::from_ptr(self.base.as_ptr().add(page * self.page_size).cast()) }
+    }
+
+    /// Claims the first free page from `first_page` on, wrapping around, as a chunk to write.
+    pub fn acquire_chunk(&self, first_page: usize) -> Option<Chunk<'_>> {
+        (0..self.pages())
+            .map(|i| (first_page + i) % self.pages())
+            .find_map(|page| {
+                let bitmap = self.page_bitmap(page);
+                let current = bitmap.load(Ordering::Acquire);
+                if current & ALL_CHUNKS_MASK != 0 {
+                    return None;
+                }
+                let claimed = (LAYOUT_DIV1 << LAYOUT_SHIFT) | CHUNK_BEING_WRITTEN;
+                bitmap
+                    .compare_exchange(current, claimed, Ordering::AcqRel, Ordering::Relaxed)
+                    .ok()?;
+                Some(Chunk {
+                    smb: self,
+                    page,
+                    len: 0,
+                    fragments: 0,
+                    flags: 0,
+                    completed: false,
+                })
+            })
+    }
+
+    /// Start of the single chunk of `page`.
+    fn chunk_ptr(&self, page: usize) -> *mut u8 {
+        assert!(page < self.pages());
+        // SAFETY: the chunk is within the page, hence within the mapping.
+        unsafe {
+            self.base
+                .as_ptr()
+                .add(page * self.page_size + PAGE_HEADER_SIZE)
+        }
+    }
+
+    fn chunk_payload_size(&self) -> usize {
+        (self.page_size - PAGE_HEADER_SIZE - CHUNK_HEADER_SIZE) & !3
+    }
+
+    /// Reads the complete chunk of `page` as the service would, then frees the page.
+    #[cfg(test)]
+    pub fn read_chunk(&self, page: usize) -> Option<CompletedChunk> {
+        let bitmap = self.page_bitmap(page);
+        let state = bitmap.load(Ordering::Acquire);
+        if state != (LAYOUT_DIV1 << LAYOUT_SHIFT) | CHUNK_COMPLETE {
+            return None;
+        }
+        let chunk = self.chunk_ptr(page);
+        // SAFETY: the chunk is complete, so its writer is done with it.
+        let (chunk_id, writer_id, packets, payload) = unsafe {
+            (
+                chunk.cast::<u32>().read(),
+                chunk.add(4).cast::<u16>().read(),
+                chunk.add(6).cast::<u16>().read(),
+                std::slice::from_raw_parts(chunk.add(CHUNK_HEADER_SIZE), self.chunk_payload_size()),
+            )
+        };
+        let mut fragments = Vec::new();
+        let mut payload = payload;
+        for _ in 0..packets & MAX_FRAGMENTS_PER_CHUNK {
+            let mut size = 0;
+            for (i, byte) in payload[..FRAGMENT_HEADER_SIZE].iter().enumerate() {
+                size |= ((byte & 0x7f) as usize) << (7 * i);
+            }
+            let end = FRAGMENT_HEADER_SIZE + size;
+            fragments.push(payload[FRAGMENT_HEADER_SIZE..end].to_vec());
+            payload = &payload[end..];
+        }
+        bitmap.store(0, Ordering::Release);
+        Some(CompletedChunk {
+            chunk_id,
+            writer_id,
+            flags: packets >> 10,
+            fragments,
+        })
+    }
+}
+
+/// A chunk handed over to the service, as read by it.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct CompletedChunk {
+    pub chunk_id: u32,
+    pub writer_id: u16,
+    pub flags: u16,
+    pub fragments: Vec<Vec<u8>>,
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created in `map`, and chunks borrow `self`.
+        unsafe { libc::munmap(self.base.as_ptr().cast(), self.len) };
+    }
+}
+
+/// A chunk claimed by a writer, filled with packet fragments until it is completed.
+pub struct Chunk<'a> {
+    smb: &'a SharedMemory,
+    page: usize,
+    /// Bytes of payload written so far.
+    len: usize,
+    fragments: u16,
+    flags: u16,
+    completed: bool,
+}
+
+impl Chunk<'_> {
+    pub fn page(&self) -> u32 {
+        self.page as u32
+    }
+
+    /// Appends as much of `packet` as fits in a single fragment, returning the size appended.
+    /// `continued` tells that `packet` is the tail of a packet started in a previous chunk.
+    pub fn append(&mut self, packet: &[u8], continued: bool) -> usize {
+        let free = self.smb.chunk_payload_size() - self.len;
+        if self.fragments == MAX_FRAGMENTS_PER_CHUNK || free <= FRAGMENT_HEADER_SIZE {
+            return 0;
+        }
+        let size = packet.len().min(free - FRAGMENT_HEADER_SIZE);
+        let mut header = [0u8; FRAGMENT_HEADER_SIZE];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = (size >> (7 * i)) as u8 & 0x7f;
+            if i + 1 < FRAGMENT_HEADER_SIZE {
+                *byte |= 0x80;
+            }
+        }
+
+        // SAFETY: the chunk is being written by us alone, and `free` bounds the copies.
+        unsafe {
+            let dst = self
+                .smb
+                .chunk_ptr(self.page)
+                .add(CHUNK_HEADER_SIZE + self.len);
+            std::ptr::copy_nonoverlapping(header.as_ptr(), dst, FRAGMENT_HEADER_SIZE);
+            std::ptr::copy_nonoverlapping(packet.as_ptr(), dst.add(FRAGMENT_HEADER_SIZE), size);
+        }
+        if continued && self.fragments == 0 {
+            self.flags |= FIRST_PACKET_CONTINUES;
+        }
+        if size < packet.len() {
+            self.flags |= LAST_PACKET_CONTINUES;
+        }
+        self.len += FRAGMENT_HEADER_SIZE + size;
+        self.fragments += 1;
+        size
+    }
+
+    /// Writes the chunk header and hands the chunk over to the service.
+    pub fn complete(mut self, writer_id: u16, chunk_id: u32) {
+        let chunk = self.smb.chunk_ptr(self.page);
+        // SAFETY: the header fields are aligned in the chunk, which is still ours.
+        unsafe {
+            AtomicU32::from_ptr(chunk.cast()).store(chunk_id, Ordering::Relaxed);
+            AtomicU16::from_ptr(chunk.add(4).cast()).store(writer_id, Ordering::Relaxed);
+            AtomicU16::from_ptr(chunk.add(6).cast())
+                .store(self.fragments | (self.flags << 10), Ordering::Release);
+        }
+        self.smb.page_bitmap(self.page).store(
+            (LAYOUT_DIV1 << LAYOUT_SHIFT) | CHUNK_COMPLETE,
+            Ordering::Release,
+        );
+        self.completed = true;
+    }
+}
+
+impl Drop for Chunk<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.smb.page_bitmap(self.page).store(0, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Per-callsite span duration statistics, see [`PerfettoLayer::with_span_stats`].
+//!
+//! [`PerfettoLayer::with_span_stats`]: crate::PerfettoLayer::with_span_stats
+
+use crate::idl;
+use crate::idl_helpers::DebugAnnotations;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::callsite;
+use tracing::Metadata;
+
+/// Number of bits of precision kept per power of two, bounding the relative error of the
+/// reported percentiles to 1/32.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKET_COUNT: u64 = 1 << SUB_BUCKET_BITS;
+
+/// A log-linear histogram of nanosecond durations, in the spirit of HDR histograms: values are
+/// bucketed by their power of two, and each power of two is split in `SUB_BUCKET_COUNT` linear
+/// buckets.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    fn bucket(value: u64) -> usize {
+        if value < SUB_BUCKET_COUNT {
+            return value as usize;
+        }
+        let msb = 63 - value.leading_zeros();
+        let shift = msb - SUB_BUCKET_BITS;
+        let sub_bucket = (value >> shift) & (SUB_BUCKET_COUNT - 1);
+        ((shift as u64 + 1) * SUB_BUCKET_COUNT + sub_bucket) as usize
+    }
+
+    /// The highest value that falls into `bucket`.
+    fn highest_value(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKET_COUNT {
+            return bucket;
+        }
+        let shift = bucket / SUB_BUCKET_COUNT - 1;
+        let sub_bucket = bucket % SUB_BUCKET_COUNT;
+        let lowest = (SUB_BUCKET_COUNT + sub_bucket) << shift;
+        lowest + ((1 << shift) - 1)
+    }
+
+    fn record(&mut self, value: u64) {
+        let bucket = Self::bucket(value);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+    }
+
+    fn value_at_quantile(&self, quantile: f64, total: u64) -> u64 {
+        let target = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Self::highest_value(bucket);
+            }
+        }
+        0
+    }
+}
+
+/// Aggregated durations of every closed span of one callsite.
+#[derive(Clone, Debug)]
+pub struct SpanStats {
+    metadata: &'static Metadata<'static>,
+    count: u64,
+    total: u64,
+    self_time: u64,
+    min: u64,
+    max: u64,
+    histogram: Histogram,
+}
+
+impl SpanStats {
+    fn new(metadata: &'static Metadata<'static>) -> Self {
+        Self {
+            metadata,
+            count: 0,
+            total: 0,
+            self_time: 0,
+            min: u64::MAX,
+            max: 0,
+            histogram: Histogram::default(),
+        }
+    }
+
+    fn record(&mut self, duration: u64, self_time: u64) {
+        self.count += 1;
+        self.total += duration;
+        self.self_time += self_time;
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+        self.histogram.record(duration);
+    }
+
+    /// The name of the span.
+    pub fn name(&self) -> &'static str {
+        self.metadata.name()
+    }
+
+    /// The metadata of the span's callsite.
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+
+    /// Number of closed spans.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the durations of the closed spans.
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.total)
+    }
+
+    /// Sum of the durations of the closed spans, minus the time spent in their child spans.
+    pub fn self_time(&self) -> Duration {
+        Duration::from_nanos(self.self_time)
+    }
+
+    /// Duration of the shortest closed span.
+    pub fn min(&self) -> Duration {
+        Duration::from_nanos(if self.count == 0 { 0 } else { self.min })
+    }
+
+    /// Duration of the longest closed span.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// The duration below which `quantile` (between `0.0` and `1.0`) of the spans closed,
+    /// within a relative error of about 3%.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let value = self.histogram.value_at_quantile(quantile, self.count);
+        Duration::from_nanos(value.min(self.max))
+    }
+
+    pub(crate) fn debug_annotations(&self) -> DebugAnnotations {
+        let fields = [
+            ("count", self.count),
+            ("total_ns", self.total),
+            ("self_ns", self.self_time),
+            ("min_ns", self.min().as_nanos() as u64),
+            ("max_ns", self.max),
+            ("p50_ns", self.percentile(0.5).as_nanos() as u64),
+            ("p90_ns", self.percentile(0.9).as_nanos() as u64),
+            ("p99_ns", self.percentile(0.99).as_nanos() as u64),
+        ];
+        let annotations = fields
+            .into_iter()
+            .map(|(name, value)| idl::DebugAnnotation {
+                name_field: Some(idl::debug_annotation::NameField::Name(name.to_string())),
+                value: Some(idl::debug_annotation::Value::UintValue(value)),
+                ..Default::default()
+            })
+            .collect();
+        DebugAnnotations { annotations }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct StatsRegistry {
+    callsites: Mutex<HashMap<callsite::Identifier, SpanStats>>,
+}
+
+impl StatsRegistry {
+    pub fn record(&self, metadata: &'static Metadata<'static>, duration: u64, self_time: u64) {
+        let mut callsites = self.callsites.lock().unwrap_or_else(|e| e.into_inner());
+        callsites
+            .entry(metadata.callsite())
+            .or_insert_with(|| SpanStats::new(metadata))
+            .record(duration, self_time);
+    }
+
+    pub fn snapshot(&self) -> Vec<SpanStats> {
+        let callsites = self.callsites.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stats: Vec<_> = callsites.values().cloned().collect();
+        stats.sort_by_key(|stats| std::cmp::Reverse(stats.total));
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = Histogram::default();
+        for value in 1..=10_000u64 {
+            histogram.record(value * 1_000);
+        }
+        for (quantile, expected) in [(0.5, 5_000_000.0), (0.9, 9_000_000.0), (0.99, 9_900_000.0)] {
+            let value = histogram.value_at_quantile(quantile, 10_000) as f64;
+            assert!((value - expected).abs() / expected < 1.0 / 32.0, "{value}");
+        }
+        let min = histogram.value_at_quantile(0.0, 10_000);
+        assert!((1_000..1_000 + 1_000 / 32).contains(&min), "{min}");
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_contiguous() {
+        // u64::MAX falls in the last bucket.
+        let last = Histogram::bucket(u64::MAX);
+        assert_eq!(Histogram::highest_value(last), u64::MAX);
+        for bucket in 1..=last {
+            let lowest = Histogram::highest_value(bucket - 1) + 1;
+            assert_eq!(Histogram::bucket(lowest), bucket);
+            assert_eq!(Histogram::bucket(Histogram::highest_value(bucket)), bucket);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Live streaming of the trace to local viewers, over a TCP or Unix socket.
+//!
+//! [`StreamWriter`] listens on a socket and sends every record of the layer, as soon as it is
+//! written, to each connected client. The stream has the encoding of a `.pftrace` file, a
+//! sequence of `Trace.packet` fields, so a client can tail it with the usual tools or save it
+//! as is:
+//!
+//! ```rust,no_run
+//! use tracing_perfetto::stream::StreamWriter;
+//! use tracing_perfetto::PerfettoLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let layer = PerfettoLayer::new(StreamWriter::listen_tcp("127.0.0.1:9001").unwrap());
+//! tracing_subscriber::registry().with(layer).init();
+//! ```
+//!
+//! ```text
+//! nc localhost 9001 > live.pftrace
+//! ```
+//!
+//! Clients may come and go: a new client first receives the process and thread descriptors
+//! written so far, then the records written from then on. A client too slow to keep up is
+//! disconnected rather than slowing the traced threads down, and may connect again.
+
+use crate::idl;
+use crate::PerfettoWriter;
+use bytes::{Bytes, BytesMut};
+use prost::Message;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Records queued for a client, on top of which the client is disconnected.
+const CLIENT_BACKLOG: usize = 1024;
+
+/// A [`PerfettoWriter`] streaming the records to the clients of a listening socket.
+pub struct StreamWriter {
+    shared: Arc<Shared>,
+    listener: Listener,
+}
+
+enum Listener {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+#[derive(Default)]
+struct Shared {
+    closed: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    clients: Vec<SyncSender<Bytes>>,
+    /// Descriptors of the process and thread tracks by uuid, written once by the layer and sent
+    /// again to every new client.
+    tracks: HashMap<u64, idl::TracePacket>,
+}
+
+impl StreamWriter {
+    /// Listens for clients on the TCP address `addr`, which should be a local one.
+    pub fn listen_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+        spawn_acceptor(shared.clone(), move || {
+            let (client, _) = listener.accept()?;
+            client.set_nodelay(true)?;
+            Ok(client)
+        })?;
+        Ok(Self {
+            shared,
+            listener: Listener::Tcp(addr),
+        })
+    }
+
+    /// Listens for clients on the Unix socket `path`, replacing a stale socket left there.
+    #[cfg(unix)]
+    pub fn listen_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let path = path.as_ref().to_path_buf();
+        if std::fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        let shared = Arc::new(Shared::default());
+        spawn_acceptor(shared.clone(), move || Ok(listener.accept()?.0))?;
+        Ok(Self {
+            shared,
+            listener: Listener::Unix(path),
+        })
+    }
+
+    /// The address of the TCP listener, e.g. to find the port picked when binding port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Number of clients currently connected.
+    pub fn clients(&self) -> usize {
+        self.shared.state().clients.len()
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        self.shared.state().clients.clear();
+        // wakes the acceptor up, which sees that the writer is closed
+        match &self.listener {
+            Listener::Tcp(addr) => _ = TcpStream::connect(addr),
+            #[cfg(unix)]
+            Listener::Unix(path) => {
+                _ = UnixStream::connect(path);
+                _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for StreamWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("StreamWriter");
+        match &self.listener {
+            Listener::Tcp(addr) => debug.field("addr", addr),
+            #[cfg(unix)]
+            Listener::Unix(path) => debug.field("path", path),
+        };
+        debug.field("clients", &self.clients()).finish()
+    }
+}
+
+impl PerfettoWriter for StreamWriter {
+    fn write_log(&self, buf: BytesMut) -> io::Result<()> {
+        let buf = buf.freeze();
+        let mut state = self.shared.state();
+        if let Ok(trace) = idl::Trace::decode(buf.clone()) {
+            for packet in trace.packet {
+                let Some(idl::trace_packet::Data::TrackDescriptor(track)) = &packet.data else {
+                    continue;
+                };
+                if track.process.is_some() || track.thread.is_some() {
+                    state.tracks.insert(track.uuid(), packet);
+                }
+            }
+        }
+        // a client whose backlog is full is dropped, its thread then closes the connection
+        state
+            .clients
+            .retain(|client| client.try_send(buf.clone()).is_ok());
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues the known descriptors for a new client, then registers it for the next records.
+    fn attach(&self, mut client: impl Write + Send + 'static) -> io::Result<()> {
+        let (sender, records) = mpsc::sync_channel::<Bytes>(CLIENT_BACKLOG);
+        let mut state = self.state();
+        let descriptors = idl::Trace {
+            packet: state.tracks.values().cloned().collect(),
+        };
+        if !descriptors.packet.is_empty() {
+            _ = sender.try_send(descriptors.encode_to_vec().into());
+        }
+        std::thread::Builder::new()
+            .name("perfetto-stream-client".to_string())
+            .spawn(move || {
+                for record in records {
+                    if client.write_all(&record).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        state.clients.push(sender);
+        Ok(())
+    }
+}
+
+/// Spawns the thread attaching the clients returned by `accept`, until the writer is dropped.
+fn spawn_acceptor<C: Write + Send + 'static>(
+    shared: Arc<Shared>,
+    accept: impl Fn() -> io::Result<C> + Send + 'static,
+) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("perfetto-stream".to_string())
+        .spawn(move || loop {
+            let client = accept();
+            if shared.closed.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Ok(client) = client {
+                _ = shared.attach(client);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Duration;
+
+    fn packet(data: idl::trace_packet::Data) -> idl::TracePacket {
+        idl::TracePacket {
+            data: Some(data),
+            ..Default::default()
+        }
+    }
+
+    fn write(writer: &StreamWriter, packet: Vec<idl::TracePacket>) -> Vec<u8> {
+        let buf = idl::Trace { packet }.encode_to_vec();
+        writer.write_log(BytesMut::from(buf.as_slice())).unwrap();
+        buf
+    }
+
+    fn wait_for_clients(writer: &StreamWriter, clients: usize) {
+        for _ in 0..500 {
+            if writer.clients() == clients {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} clients instead of {clients}", writer.clients());
+    }
+
+    fn read_trace(client: &mut impl Read, len: usize) -> idl::Trace {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        idl::Trace::decode(buf.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_clients_get_descriptors() {
+        let writer = StreamWriter::listen_tcp("127.0.0.1:0").unwrap();
+        let process = packet(idl::trace_packet::Data::TrackDescriptor(
+            idl::TrackDescriptor {
+                uuid: Some(1),
+                process: Some(Default::default()),
+                ..Default::default()
+            },
+        ));
+        let named = packet(idl::trace_packet::Data::TrackDescriptor(
+            idl::TrackDescriptor::named_child_for("named", 1),
+        ));
+        // nobody listens yet
+        let descriptors = write(&writer, vec![process.clone(), named]);
+
+        let mut first = TcpStream::connect(writer.local_addr().unwrap()).unwrap();
+        first
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        wait_for_clients(&writer, 1);
+        let event = packet(idl::trace_packet::Data::TrackEvent(Default::default()));
+        let record = write(&writer, vec![event.clone()]);
+
+        let replayed = process.encoded_len() + 2;
+        assert!(
+            replayed < descriptors.len(),
+            "only the process track is replayed"
+        );
+        let trace = read_trace(&mut first, replayed + record.len());
+        assert_eq!(trace.packet, [process.clone(), event.clone()]);
+
+        drop(first);
+        // the writer notices the disconnection on the next write
+        while writer.clients() > 0 {
+            write(&writer, vec![event.clone()]);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut second = TcpStream::connect(writer.local_addr().unwrap()).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        wait_for_clients(&writer, 1);
+        write(&writer, vec![event.clone()]);
+        let trace = read_trace(&mut second, replayed + record.len());
+        assert_eq!(trace.packet, [process, event]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let path =
+            std::env::temp_dir().join(format!("tracing-perfetto-{}.sock", std::process::id()));
+        let writer = StreamWriter::listen_unix(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        wait_for_clients(&writer, 1);
+
+        let event = packet(idl::trace_packet::Data::TrackEvent(Default::default()));
+        let record = write(&writer, vec![event.clone(), event.clone()]);
+        assert_eq!(
+            read_trace(&mut client, record.len()).packet,
+            [event.clone(), event]
+        );
+
+        drop(writer);
+        assert!(!path.exists());
+        let mut rest = Vec::new();
+        assert_eq!(
+            client.read_to_end(&mut rest).unwrap(),
+            0,
+            "closed with the writer"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! Names and orders the worker threads of thread pools.
+//!
+//! Pool workers otherwise show up as anonymous threads, named after `std::thread` at best. A
+//! [`ThreadPoolTracks`] registers each worker with a stable name, under a common parent track
+//! where workers are ordered by index:
+//!
+//! ```rust
+//! use tracing_perfetto::thread_pool::ThreadPoolTracks;
+//!
+//! let tracks = ThreadPoolTracks::new("io pool");
+//! let workers: Vec<_> = (0..4)
+//!     .map(|index| {
+//!         let tracks = tracks.clone();
+//!         std::thread::spawn(move || {
+//!             tracks.register_worker(index);
+//!             tracing::info!("worker started");
+//!         })
+//!     })
+//!     .collect();
+//! for worker in workers {
+//!     worker.join().unwrap();
+//! }
+//! ```
+//!
+//! With the `rayon` feature, [`instrument_rayon`] does it for a rayon pool.
+
+use crate::idl_helpers::{register_current_thread, ThreadGroup};
+
+/// The parent track of the workers of a pool.
+#[derive(Clone, Debug)]
+pub struct ThreadPoolTracks {
+    group: ThreadGroup,
+}
+
+impl ThreadPoolTracks {
+    /// Creates the parent track of a pool, displayed as `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            group: ThreadGroup::new(name),
+        }
+    }
+
+    /// Registers the current thread as the worker `index` of the pool, named `{pool}-{index}`.
+    pub fn register_worker(&self, index: usize) {
+        self.register_named_worker(index, format!("{}-{index}", self.group.name));
+    }
+
+    /// Registers the current thread as the worker `index` of the pool, named `name`.
+    pub fn register_named_worker(&self, index: usize, name: impl Into<String>) {
+        register_current_thread(name.into(), Some((self.group.clone(), index as i32)));
+    }
+}
+
+/// Registers the workers of the pool built by `builder` as `{name}-{index}`, under a `name`
+/// parent track.
+///
+/// This sets the start handler of the builder.
+#[cfg(feature = "rayon")]
+pub fn instrument_rayon<S>(
+    builder: rayon::ThreadPoolBuilder<S>,
+    name: &str,
+) -> rayon::ThreadPoolBuilder<S> {
+    let tracks = ThreadPoolTracks::new(name);
+    builder.start_handler(move |index| tracks.register_worker(index))
+}
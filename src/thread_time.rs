@@ -0,0 +1,26 @@
+//! Thread CPU time of spans, see [`PerfettoLayer::with_thread_cpu_time`].
+//!
+//! [`PerfettoLayer::with_thread_cpu_time`]: crate::PerfettoLayer::with_thread_cpu_time
+
+use crate::idl;
+
+/// CPU time consumed by the current thread, in nanoseconds.
+pub(crate) fn now() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec.
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// CPU time spent inside a span, accumulated between its enters and exits.
+pub(crate) struct SpanCpuTime {
+    /// Descriptors of the thread creating the span and of its CPU time counter, referenced by
+    /// the `TYPE_SLICE_BEGIN`.
+    pub begin_descriptors: [idl::TrackDescriptor; 2],
+    /// Thread CPU time at the last enter, if the span is entered.
+    pub entered: Option<u64>,
+    pub total: u64,
+}
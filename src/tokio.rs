@@ -0,0 +1,200 @@
+//! Tokio integration, behind the `tokio` feature.
+//!
+//! - [`instrument_runtime`] names the workers of a runtime and orders them under a
+//!   `tokio runtime` track, and with `--cfg tokio_unstable` records every task on a track of its
+//!   own,
+//! - [`PerfettoLayer::with_tokio_metrics`] samples the runtime metrics as counter tracks,
+//! - [`spawn_named`] spawns a task on a track of its own, for builds without `tokio_unstable`.
+//!
+//! Tokio's task hooks are unstable: tasks only get their track automatically in builds with
+//! `RUSTFLAGS="--cfg tokio_unstable"`. Their spans are recorded by the subscriber current when
+//! they are spawned, and their events by the subscriber of the worker polling them, so the
+//! subscriber has to be the global default, or be carried by the tasks.
+//!
+//! ```rust
+//! use tracing_perfetto::PerfettoLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let mut builder = tokio::runtime::Builder::new_multi_thread();
+//! tracing_perfetto::tokio::instrument_runtime(builder.worker_threads(4));
+//! let runtime = builder.enable_all().build().unwrap();
+//!
+//! let layer = PerfettoLayer::new(std::io::sink)
+//!     .with_tokio_metrics(runtime.handle().clone(), std::time::Duration::from_millis(100));
+//! let _guard = tracing_subscriber::registry().with(layer).set_default();
+//!
+//! runtime.block_on(async {
+//!     tracing_perfetto::tokio::spawn_named("ticker", async {}).await.unwrap();
+//! });
+//! ```
+//!
+//! [`PerfettoLayer::with_tokio_metrics`]: crate::PerfettoLayer::with_tokio_metrics
+
+use crate::background::{counter_trace, Periodic};
+use crate::idl;
+use crate::thread_pool::ThreadPoolTracks;
+use ::tokio::runtime::{Builder, Handle};
+use ::tokio::task::JoinHandle;
+use idl::counter_descriptor::Unit;
+use std::cell::Cell;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::instrument::WithSubscriber;
+use tracing::Instrument;
+
+thread_local! {
+    /// Whether the current thread was registered as a worker.
+    static WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Names the workers of the multi-thread runtime built by `builder` `tokio-worker-{index}`, and
+/// groups them under a `tokio runtime` track, in index order. With `--cfg tokio_unstable`, it
+/// also records every task as a `task` span on a track named after the place it was spawned
+/// from, see the [module](self) documentation.
+///
+/// Workers are told apart from the blocking threads by parking, and registered when they first
+/// park, which they do as soon as they start with nothing to do; blocking threads keep tokio's
+/// thread name. With `tokio_unstable`, the index of a worker is the one of the runtime metrics;
+/// otherwise workers are indexed in the order they first park, which only matches the metrics
+/// by chance.
+///
+/// This sets the thread park handler of the builder, and with `tokio_unstable` its task spawn,
+/// poll and terminate handlers.
+pub fn instrument_runtime(builder: &mut Builder) -> &mut Builder {
+    let tracks = ThreadPoolTracks::new("tokio runtime");
+    let parked = AtomicUsize::new(0);
+    builder.on_thread_park(move || {
+        if WORKER.replace(true) {
+            return;
+        }
+        #[cfg(tokio_unstable)]
+        let index = ::tokio::runtime::worker_index()
+            .unwrap_or_else(|| parked.fetch_add(1, Ordering::Relaxed));
+        #[cfg(not(tokio_unstable))]
+        let index = parked.fetch_add(1, Ordering::Relaxed);
+        tracks.register_named_worker(index, format!("tokio-worker-{index}"));
+    });
+    #[cfg(tokio_unstable)]
+    task_tracks::install(builder);
+    builder
+}
+
+/// Spawns `future` on the current runtime, recording it on a track named `name`.
+///
+/// The task is instrumented with a `task` span carrying `perfetto.track_name = name`, so the
+/// spans and events of the task land on that track. It also keeps the current subscriber,
+/// even if it is only the default of the spawning thread.
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = tracing::info_span!("task", perfetto.track_name = name);
+    ::tokio::spawn(future.instrument(span).with_current_subscriber())
+}
+
+/// The `task` spans of the tasks alive in a runtime, entered around each of their polls.
+#[cfg(tokio_unstable)]
+mod task_tracks {
+    use ::tokio::runtime::{Builder, TaskMeta};
+    use ::tokio::task::Id;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    #[derive(Default)]
+    struct TaskSpans(Mutex<HashMap<Id, tracing::Span>>);
+
+    impl TaskSpans {
+        fn spans(&self) -> MutexGuard<'_, HashMap<Id, tracing::Span>> {
+            self.0.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        fn with_span(
+            &self,
+            meta: &TaskMeta<'_>,
+            f: impl FnOnce(&tracing::span::Id, &tracing::Dispatch),
+        ) {
+            let span = self.spans().get(&meta.id()).cloned();
+            if let Some(span) = span {
+                span.with_subscriber(|(id, dispatch)| f(id, dispatch));
+            }
+        }
+    }
+
+    pub(super) fn install(builder: &mut Builder) {
+        let tasks = Arc::new(TaskSpans::default());
+        let (poll, polled, terminated) = (tasks.clone(), tasks.clone(), tasks.clone());
+        builder
+            .on_task_spawn(move |meta| {
+                let location = meta.spawned_at();
+                let track = format!("task {}:{}", location.file(), location.line());
+                // a root, so that a long-lived task doesn't keep the span it was spawned in open
+                let span = tracing::info_span!(
+                    parent: None,
+                    "task",
+                    perfetto.track_name = track.as_str(),
+                    task.id = %meta.id()
+                );
+                tasks.spans().insert(meta.id(), span);
+            })
+            .on_before_task_poll(move |meta| {
+                poll.with_span(meta, |id, dispatch| dispatch.enter(id));
+            })
+            .on_after_task_poll(move |meta| {
+                polled.with_span(meta, |id, dispatch| dispatch.exit(id));
+            })
+            .on_task_terminate(move |meta| {
+                terminated.spans().remove(&meta.id());
+            });
+    }
+}
+
+/// Starts sampling the metrics of the runtime of `handle` every `interval`, handing the
+/// counters to `sink`.
+pub(crate) fn start_metrics(
+    handle: Handle,
+    interval: Duration,
+    process_track_uuid: u64,
+    sequence_id: u32,
+    sink: impl Fn(idl::Trace) + Send + 'static,
+) -> std::io::Result<Periodic> {
+    let workers = handle.metrics().num_workers();
+    let counter =
+        |name: &str, unit| idl::TrackDescriptor::counter_child_for(name, process_track_uuid, unit);
+    let mut tracks = vec![
+        counter("tokio.alive_tasks", Unit::Count),
+        counter("tokio.global_queue_depth", Unit::Count),
+    ];
+    tracks.extend(
+        (0..workers).map(|i| counter(&format!("tokio.worker.{i}.busy_percent"), Unit::Unspecified)),
+    );
+
+    let mut busy = vec![Duration::ZERO; workers];
+    let mut last_sample = std::time::Instant::now();
+    Periodic::start("perfetto-tokio-metrics", interval, move || {
+        let metrics = handle.metrics();
+        let elapsed = std::mem::replace(&mut last_sample, std::time::Instant::now()).elapsed();
+        let mut values = vec![
+            Some(metrics.num_alive_tasks() as i64),
+            Some(metrics.global_queue_depth() as i64),
+        ];
+        for (worker, previous) in busy.iter_mut().enumerate() {
+            let total = worker_busy_duration(&metrics, worker);
+            let delta = total.saturating_sub(std::mem::replace(previous, total));
+            let percent = (delta.as_nanos() * 100).checked_div(elapsed.as_nanos());
+            values.push(percent.map(|percent| percent.min(100) as i64));
+        }
+        sink(counter_trace(&tracks, values, sequence_id));
+    })
+}
+
+#[cfg(target_has_atomic = "64")]
+fn worker_busy_duration(metrics: &::tokio::runtime::RuntimeMetrics, worker: usize) -> Duration {
+    metrics.worker_total_busy_duration(worker)
+}
+
+#[cfg(not(target_has_atomic = "64"))]
+fn worker_busy_duration(_: &::tokio::runtime::RuntimeMetrics, _: usize) -> Duration {
+    Duration::ZERO
+}
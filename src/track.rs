@@ -0,0 +1,247 @@
+//! Named tracks, nested under the process or under each other.
+//!
+//! A span carrying a `perfetto.track` field with the [`Track::id`] of a track is recorded on
+//! that track, along with its child spans and events:
+//!
+//! ```rust
+//! use tracing_perfetto::track::{ChildOrdering, Track};
+//!
+//! let pool = Track::new("db pool").with_child_ordering(ChildOrdering::Explicit);
+//! for index in 0..4 {
+//!     let connection = pool.child(&format!("conn {index}")).with_rank(index);
+//!     tracing::info_span!("query", perfetto.track = connection.id()).in_scope(|| {});
+//! }
+//! ```
+//!
+//! Tracks are keyed by parent and name: creating a track again gives back the same one, so
+//! spans on the same logical track share it, wherever they come from. `perfetto.track_name`
+//! fields name such tracks under the process, [`Track::new`] ones. Tracks are kept for the
+//! lifetime of the process.
+//!
+//! Slices on a track have to nest. A span opened on a track, its own or its parent's, while a span
+//! other than its parent is the innermost one open there, e.g. a sibling in another task, goes to
+//! a lane of the track instead: a sibling track of the same name, next to it.
+
+use crate::idl;
+use crate::idl_helpers::unique_uuid;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use tracing::span;
+
+static TRACKS: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+/// How the children of a track are ordered in the UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildOrdering {
+    /// By name.
+    Lexicographic,
+    /// By the timestamp of their first event.
+    Chronological,
+    /// By the rank set with [`Track::with_rank`].
+    Explicit,
+}
+
+/// A named track, under the process track or under another [`Track`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Track {
+    uuid: u64,
+}
+
+impl Track {
+    /// The track named `name` under the process track.
+    pub fn new(name: &str) -> Self {
+        Self::child_of(None, name)
+    }
+
+    /// The track named `name` under this one.
+    pub fn child(&self, name: &str) -> Self {
+        Self::child_of(Some(self.uuid), name)
+    }
+
+    /// The track named `name` under `parent`, or under the process track.
+    fn child_of(parent: Option<u64>, name: &str) -> Self {
+        let mut registry = registry();
+        let key = (parent, name.to_string(), 0);
+        if let Some(&uuid) = registry.keys.get(&key) {
+            return Self { uuid };
+        }
+        let uuid = unique_uuid();
+        registry.keys.insert(key, uuid);
+        registry.tracks.insert(
+            uuid,
+            TrackInfo {
+                name: name.to_string(),
+                parent,
+                child_ordering: None,
+                rank: None,
+                base: uuid,
+            },
+        );
+        Self { uuid }
+    }
+
+    /// The lane `lane` of this track, the track itself for lane 0, if it is a known track.
+    fn lane(&self, lane: usize) -> Option<Self> {
+        let mut registry = registry();
+        let info = registry.tracks.get(&self.uuid)?;
+        if lane == 0 {
+            return Some(*self);
+        }
+        let key = (info.parent, info.name.clone(), lane);
+        if let Some(&uuid) = registry.keys.get(&key) {
+            return Some(Self { uuid });
+        }
+        let info = TrackInfo {
+            name: info.name.clone(),
+            parent: info.parent,
+            child_ordering: None,
+            rank: info.rank,
+            base: self.uuid,
+        };
+        let uuid = unique_uuid();
+        registry.keys.insert(key, uuid);
+        registry.tracks.insert(uuid, info);
+        Some(Self { uuid })
+    }
+
+    /// Orders the children of this track.
+    pub fn with_child_ordering(self, ordering: ChildOrdering) -> Self {
+        self.update(|info| info.child_ordering = Some(ordering));
+        self
+    }
+
+    /// Ranks this track among its siblings, when its parent has an [`ChildOrdering::Explicit`]
+    /// ordering. Lower ranks come first.
+    pub fn with_rank(self, rank: i32) -> Self {
+        self.update(|info| info.rank = Some(rank));
+        self
+    }
+
+    /// The id of the track, for the `perfetto.track` field of spans.
+    pub fn id(&self) -> u64 {
+        self.uuid
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TrackInfo)) {
+        if let Some(info) = registry().tracks.get_mut(&self.uuid) {
+            f(info);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Tracks by parent, name and lane.
+    keys: HashMap<(Option<u64>, String, usize), u64>,
+    tracks: HashMap<u64, TrackInfo>,
+}
+
+struct TrackInfo {
+    name: String,
+    /// The parent track, the process track if `None`.
+    parent: Option<u64>,
+    child_ordering: Option<ChildOrdering>,
+    rank: Option<i32>,
+    /// The track this one is a lane of, itself for lane 0.
+    base: u64,
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    TRACKS
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// The descriptors of the track of id `uuid` and of its ancestors, outermost first, if there
+/// is such a track. Root tracks are children of `process_track_uuid`.
+pub(crate) fn descriptors(uuid: u64, process_track_uuid: u64) -> Option<Vec<idl::TrackDescriptor>> {
+    let registry = registry();
+    let mut descriptors = Vec::new();
+    let mut current = Some(uuid);
+    while let Some(uuid) = current {
+        let info = registry.tracks.get(&uuid)?;
+        let parent = info.parent.unwrap_or(process_track_uuid);
+        let mut descriptor = idl::TrackDescriptor::named_child_for(&info.name, parent);
+        descriptor.uuid = Some(uuid);
+        descriptor.sibling_order_rank = info.rank;
+        if let Some(ordering) = info.child_ordering {
+            descriptor.set_child_ordering(match ordering {
+                ChildOrdering::Lexicographic => {
+                    idl::track_descriptor::ChildTracksOrdering::Lexicographic
+                }
+                ChildOrdering::Chronological => {
+                    idl::track_descriptor::ChildTracksOrdering::Chronological
+                }
+                ChildOrdering::Explicit => idl::track_descriptor::ChildTracksOrdering::Explicit,
+            });
+        }
+        descriptors.push(descriptor);
+        current = info.parent;
+    }
+    descriptors.reverse();
+    Some(descriptors)
+}
+
+/// The lane of a track claimed by a span, released when it closes.
+#[derive(Clone)]
+pub(crate) struct TrackLane {
+    base: u64,
+    lane: usize,
+    span: span::Id,
+}
+
+/// The lanes of the tracks with open spans, whose slices have to nest.
+#[derive(Default)]
+pub(crate) struct Lanes {
+    /// The spans open on each lane, innermost last, by track.
+    open: Mutex<HashMap<u64, Vec<Vec<span::Id>>>>,
+}
+
+impl Lanes {
+    /// Resolves the track of the span `span` opened on the track of id `uuid`, with its parent
+    /// on the lane `parent` if any: the lane of the parent if the parent is the innermost span
+    /// open there, so the slice nests, the first lane without open spans otherwise. Returns
+    /// `None` for an unknown track.
+    pub fn claim(
+        &self,
+        uuid: u64,
+        parent: Option<&TrackLane>,
+        span: &span::Id,
+    ) -> Option<(Track, TrackLane)> {
+        let base = registry().tracks.get(&uuid)?.base;
+        let lane = {
+            let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+            let lanes = open.entry(base).or_default();
+            let nested = parent.filter(|parent| {
+                parent.base == base
+                    && lanes.get(parent.lane).and_then(|l| l.last()) == Some(&parent.span)
+            });
+            let lane = match nested {
+                Some(parent) => parent.lane,
+                None => lanes.iter().position(Vec::is_empty).unwrap_or(lanes.len()),
+            };
+            if lane == lanes.len() {
+                lanes.push(Vec::new());
+            }
+            lanes[lane].push(span.clone());
+            lane
+        };
+        let track = Track { uuid: base }.lane(lane)?;
+        let span = span.clone();
+        Some((track, TrackLane { base, lane, span }))
+    }
+
+    pub fn release(&self, lane: TrackLane) {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(lanes) = open.get_mut(&lane.base) {
+            let spans = &mut lanes[lane.lane];
+            if let Some(index) = spans.iter().rposition(|span| *span == lane.span) {
+                spans.remove(index);
+            }
+            if lanes.iter().all(Vec::is_empty) {
+                open.remove(&lane.base);
+            }
+        }
+    }
+}
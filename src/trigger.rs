@@ -0,0 +1,388 @@
+//! Trace triggers, named points of interest deciding which part of the trace gets written.
+//!
+//! [`trigger`], or any event with a `perfetto.trigger` string field, records a `Trigger` packet
+//! in the trace:
+//!
+//! ```rust
+//! tracing_perfetto::trigger("slow_request");
+//! tracing::warn!(perfetto.trigger = "oom_suspect", rss_mb = 3900);
+//! ```
+//!
+//! With [`PerfettoLayer::with_triggers`](crate::PerfettoLayer::with_triggers), triggers also
+//! drive the recording, as the trigger-based configs of Perfetto do. Records not written right
+//! away are kept in memory for the pre-trigger window, so that a trigger can write what led to
+//! it. Descriptors of the tracks used within the pre-trigger window are written again ahead of
+//! such a snapshot, which thus stands on its own.
+
+use crate::idl;
+use prost::Message;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+
+/// Bytes of records buffered waiting for a trigger, whatever the pre-trigger window.
+const MAX_BUFFERED_BYTES: usize = 64 << 20;
+/// How often the descriptors of the tracks left unused are forgotten.
+const FORGET_TRACKS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records a trigger named `name`, as an `INFO` event with a `perfetto.trigger` field.
+///
+/// Like any event, the trigger only reaches a [`PerfettoLayer`](crate::PerfettoLayer) its
+/// filters let through.
+pub fn trigger(name: &str) {
+    tracing::info!(perfetto.trigger = name);
+}
+
+/// What a trigger does to the trace, see [`PerfettoLayer::with_triggers`](crate::PerfettoLayer::with_triggers).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Nothing is written until the first trigger. It writes the records of the `pre` window
+    /// before it, then the records until `post` after it, and the trace ends.
+    StartTracing { pre: Duration, post: Duration },
+    /// Records are written until `post` after the first trigger, and the trace ends.
+    StopTracing { post: Duration },
+    /// A flight recorder: nothing is written but the snapshots taken by triggers, each made of
+    /// the records of the `pre` window before the trigger and until `post` after it.
+    CloneSnapshot { pre: Duration, post: Duration },
+}
+
+enum State {
+    /// Buffering the records until a trigger.
+    Armed,
+    /// Writing the records until the deadline included, in nanoseconds.
+    Writing { until: u64 },
+    /// The trace ended.
+    Stopped,
+}
+
+/// Sorts the records of the layer into written, buffered and dropped ones, following the
+/// triggers.
+pub(crate) struct Recorder {
+    mode: TriggerMode,
+    state: State,
+    /// Records waiting for a trigger, with the time they were written at.
+    buffer: VecDeque<(u64, idl::Trace)>,
+    buffered_bytes: usize,
+    /// Track descriptors by uuid, with the last time a record used them, written ahead of the
+    /// snapshots. Those unused for the pre-trigger window are forgotten.
+    tracks: HashMap<u64, (u64, idl::TracePacket)>,
+    /// When unused tracks are next forgotten.
+    next_forget: u64,
+}
+
+impl Recorder {
+    pub fn new(mode: TriggerMode) -> Self {
+        let state = match mode {
+            TriggerMode::StopTracing { .. } => State::Writing { until: u64::MAX },
+            TriggerMode::StartTracing { .. } | TriggerMode::CloneSnapshot { .. } => State::Armed,
+        };
+        Self {
+            mode,
+            state,
+            buffer: VecDeque::new(),
+            buffered_bytes: 0,
+            tracks: HashMap::new(),
+            next_forget: 0,
+        }
+    }
+
+    /// Takes `trace`, written at `now`, and returns it back if it should be written right away.
+    pub fn record(&mut self, trace: idl::Trace, now: u64) -> Option<idl::Trace> {
+        self.expire(now);
+        if let Some(pre) = self.pre() {
+            self.remember_tracks(&trace, now, pre);
+        }
+        match self.state {
+            State::Writing { .. } => Some(trace),
+            State::Stopped => None,
+            State::Armed => {
+                self.buffered_bytes += trace.encoded_len();
+                self.buffer.push_back((now, trace));
+                self.evict(now);
+                None
+            }
+        }
+    }
+
+    /// Handles a trigger fired at `now`, returning the buffered records to write before it.
+    pub fn fire(&mut self, now: u64) -> Vec<idl::Trace> {
+        self.expire(now);
+        match (&self.state, self.mode) {
+            (
+                State::Armed,
+                TriggerMode::StartTracing { post, .. } | TriggerMode::CloneSnapshot { post, .. },
+            ) => {
+                self.evict(now);
+                self.buffered_bytes = 0;
+                self.state = State::Writing {
+                    until: now.saturating_add(post.as_nanos() as u64),
+                };
+                let descriptors = idl::Trace {
+                    packet: self
+                        .tracks
+                        .values()
+                        .map(|(_, packet)| packet.clone())
+                        .collect(),
+                };
+                std::iter::once(descriptors)
+                    .filter(|descriptors| !descriptors.packet.is_empty())
+                    .chain(self.buffer.drain(..).map(|(_, trace)| trace))
+                    .collect()
+            }
+            (State::Writing { until: u64::MAX }, TriggerMode::StopTracing { post }) => {
+                self.state = State::Writing {
+                    until: now.saturating_add(post.as_nanos() as u64),
+                };
+                Vec::new()
+            }
+            // writing the window of a previous trigger, or done
+            _ => Vec::new(),
+        }
+    }
+
+    /// Ends the post-trigger window once past its deadline.
+    fn expire(&mut self, now: u64) {
+        if let State::Writing { until } = self.state {
+            if now > until {
+                self.state = match self.mode {
+                    TriggerMode::CloneSnapshot { .. } => State::Armed,
+                    _ => State::Stopped,
+                };
+            }
+        }
+    }
+
+    /// The pre-trigger window, if triggers write snapshots.
+    fn pre(&self) -> Option<Duration> {
+        match self.mode {
+            TriggerMode::StartTracing { pre, .. } | TriggerMode::CloneSnapshot { pre, .. } => {
+                Some(pre)
+            }
+            TriggerMode::StopTracing { .. } => None,
+        }
+    }
+
+    /// Keeps the track descriptors of `trace`, written at `now`, and marks the tracks its
+    /// records use, with their ancestors, as used at `now`. Every `FORGET_TRACKS_INTERVAL`, the
+    /// tracks unused for the `pre` window are forgotten, but for the process tracks.
+    fn remember_tracks(&mut self, trace: &idl::Trace, now: u64, pre: Duration) {
+        for packet in &trace.packet {
+            match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(track)) => {
+                    self.tracks.insert(track.uuid(), (now, packet.clone()));
+                    self.use_track(track.parent_uuid, now);
+                }
+                Some(idl::trace_packet::Data::TrackEvent(event)) => {
+                    for &uuid in event
+                        .track_uuid
+                        .iter()
+                        .chain(&event.extra_counter_track_uuids)
+                    {
+                        self.use_track(Some(uuid), now);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if now >= self.next_forget {
+            let oldest = now.saturating_sub(pre.as_nanos() as u64);
+            self.tracks.retain(|_, (used, packet)| {
+                *used >= oldest
+                    || matches!(
+                        &packet.data,
+                        Some(idl::trace_packet::Data::TrackDescriptor(track))
+                            if track.process.is_some()
+                    )
+            });
+            self.next_forget = now.saturating_add(FORGET_TRACKS_INTERVAL.as_nanos() as u64);
+        }
+    }
+
+    /// Marks the track of id `uuid` and its ancestors as used at `now`.
+    fn use_track(&mut self, mut uuid: Option<u64>, now: u64) {
+        while let Some((used, packet)) = uuid.and_then(|uuid| self.tracks.get_mut(&uuid)) {
+            if *used == now {
+                // and so are its ancestors
+                break;
+            }
+            *used = now;
+            uuid = match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(track)) => track.parent_uuid,
+                _ => None,
+            };
+        }
+    }
+
+    /// Drops the buffered records older than the pre-trigger window, or over the size budget.
+    fn evict(&mut self, now: u64) {
+        let pre = self.pre().unwrap_or_default();
+        let oldest = now.saturating_sub(pre.as_nanos() as u64);
+        while let Some((written, trace)) = self.buffer.front() {
+            if *written >= oldest && self.buffered_bytes <= MAX_BUFFERED_BYTES {
+                break;
+            }
+            self.buffered_bytes -= trace.encoded_len();
+            self.buffer.pop_front();
+        }
+    }
+}
+
+/// Builds the `Trigger` packet of a trigger named `name`.
+pub(crate) fn trigger_packet(name: &str, timestamp: Option<u64>) -> idl::TracePacket {
+    idl::TracePacket {
+        data: Some(idl::trace_packet::Data::Trigger(idl::Trigger {
+            trigger_name: Some(name.to_string()),
+            ..Default::default()
+        })),
+        timestamp,
+        trusted_pid: Some(std::process::id() as _),
+        ..Default::default()
+    }
+}
+
+/// Finds the name of the trigger fired by an event, in its `perfetto.trigger` field.
+#[derive(Default)]
+pub(crate) struct TriggerVisitor {
+    pub name: Option<String>,
+}
+
+impl Visit for TriggerVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "perfetto.trigger" {
+            self.name = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    fn record(name: &str) -> idl::Trace {
+        idl::Trace {
+            packet: vec![trigger_packet(name, None)],
+        }
+    }
+
+    fn names(traces: &[idl::Trace]) -> Vec<String> {
+        traces
+            .iter()
+            .flat_map(|trace| &trace.packet)
+            .filter_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::Trigger(trigger)) => {
+                    Some(trigger.trigger_name().to_string())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_clone_snapshot() {
+        let mut recorder = Recorder::new(TriggerMode::CloneSnapshot {
+            pre: Duration::from_millis(10),
+            post: Duration::from_millis(5),
+        });
+        let track = idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackDescriptor(
+                idl::TrackDescriptor::named_child_for("track", 1),
+            )),
+            ..Default::default()
+        };
+        let described = idl::Trace {
+            packet: vec![track.clone()],
+        };
+        assert!(recorder.record(described, 0).is_none());
+        assert!(recorder.record(record("old"), 5 * MS).is_none());
+        assert!(recorder.record(record("pre"), 20 * MS).is_none());
+
+        let snapshot = recorder.fire(25 * MS);
+        assert_eq!(snapshot[0].packet, [track], "descriptors come first");
+        assert_eq!(names(&snapshot), ["pre"]);
+        assert!(recorder.record(record("post"), 28 * MS).is_some());
+
+        // back to buffering once the window is over
+        assert!(recorder.record(record("later"), 31 * MS).is_none());
+        assert_eq!(names(&recorder.fire(35 * MS)), ["later"]);
+    }
+
+    #[test]
+    fn test_forget_unused_tracks() {
+        let mut recorder = Recorder::new(TriggerMode::CloneSnapshot {
+            pre: Duration::from_millis(10),
+            post: Duration::ZERO,
+        });
+        let descriptor = |track: idl::TrackDescriptor| idl::TracePacket {
+            data: Some(idl::trace_packet::Data::TrackDescriptor(track)),
+            ..Default::default()
+        };
+        let process = idl::TrackDescriptor {
+            uuid: Some(1),
+            process: Some(idl::ProcessDescriptor::default()),
+            ..Default::default()
+        };
+        let pool = idl::TrackDescriptor::named_child_for("pool", 1);
+        let worker = idl::TrackDescriptor::named_child_for("worker", pool.uuid());
+        let unused = idl::TrackDescriptor::named_child_for("unused", 1);
+        let described = idl::Trace {
+            packet: [&process, &pool, &worker, &unused]
+                .into_iter()
+                .map(|track| descriptor(track.clone()))
+                .collect(),
+        };
+        assert!(recorder.record(described, 0).is_none());
+
+        let mut event = idl::TrackEvent {
+            track_uuid: Some(worker.uuid()),
+            ..Default::default()
+        };
+        event.set_type(idl::track_event::Type::Instant);
+        let on_worker = idl::Trace {
+            packet: vec![idl::TracePacket {
+                data: Some(idl::trace_packet::Data::TrackEvent(event)),
+                ..Default::default()
+            }],
+        };
+        assert!(recorder.record(on_worker, 1500 * MS).is_none());
+
+        let snapshot = recorder.fire(1505 * MS);
+        let mut described: Vec<_> = snapshot[0]
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(idl::trace_packet::Data::TrackDescriptor(track)) => Some(track.uuid()),
+                _ => None,
+            })
+            .collect();
+        described.sort();
+        let mut expected = vec![process.uuid(), pool.uuid(), worker.uuid()];
+        expected.sort();
+        assert_eq!(described, expected);
+    }
+
+    #[test]
+    fn test_start_and_stop_tracing() {
+        let mut start = Recorder::new(TriggerMode::StartTracing {
+            pre: Duration::ZERO,
+            post: Duration::from_millis(5),
+        });
+        assert!(start.record(record("before"), 0).is_none());
+        assert!(names(&start.fire(MS)).is_empty());
+        assert!(start.record(record("after"), 2 * MS).is_some());
+        assert!(start.record(record("end"), 7 * MS).is_none());
+        assert!(start.fire(8 * MS).is_empty(), "the trace ended");
+
+        let mut stop = Recorder::new(TriggerMode::StopTracing {
+            post: Duration::from_millis(5),
+        });
+        assert!(stop.record(record("before"), 0).is_some());
+        assert!(stop.fire(MS).is_empty());
+        assert!(stop.record(record("after"), 2 * MS).is_some());
+        assert!(stop.record(record("end"), 7 * MS).is_none());
+    }
+}